@@ -1 +0,0 @@
-DROP SCHEMA infra CASCADE;
@@ -1,8 +1,3 @@
-\set ON_ERROR_STOP on
-
-CREATE SCHEMA infra;
-SET search_path TO infra;
-
 CREATE DOMAIN hostname       AS varchar(32)  CHECK (VALUE ~ '\A[-_a-z0-9]+\Z');
 CREATE DOMAIN netname        AS varchar(32)  CHECK (VALUE ~ '\A(NONE|[-_a-z0-9]+)\Z');
 CREATE DOMAIN port           AS integer      CHECK (VALUE > 0 AND VALUE <= 65536);
@@ -0,0 +1,44 @@
+-- Names of pools of WireGuard addresses
+CREATE DOMAIN poolname       AS varchar(32)  CHECK (VALUE ~ '\A[-_a-z0-9]+\Z');
+
+-- How `i add` picks the next WireGuard address in a pool
+--
+-- lowest_free:    the lowest address not in use
+-- after_highest:  the address after the highest address in use, wrapping around
+--                 to the lowest free address when the end of the pool is reached
+CREATE TYPE allocation_policy AS ENUM ('lowest_free', 'after_highest');
+
+-- How the IPv6 address of a machine is picked relative to its IPv4 address
+--
+-- independent:  IPv6 addresses are allocated separately from IPv4 addresses
+-- embed_ipv4:   each host octet of the IPv4 address becomes one of the trailing
+--               16-bit groups of the IPv6 address, written to read the same in hex
+--               as in decimal: with 10.10.0.0/16 and fd00::/64, 10.10.3.17 gets
+--               fd00::3:17.  `i ipam check` reports machines that do not match.
+CREATE TYPE ipv6_mode AS ENUM ('independent', 'embed_ipv4');
+
+-- Named ranges of WireGuard addresses that `i add --pool` allocates from
+--
+-- The network and broadcast addresses of ipv4_cidr and the subnet-router anycast
+-- address of ipv6_cidr are never allocated.  `reserved` lists additional ranges
+-- inside the pool that must not be allocated, e.g. for routers or VIPs.
+CREATE TABLE ip_pools (
+    name       poolname           PRIMARY KEY,
+    ipv4_cidr  cidr               NOT NULL CHECK (family(ipv4_cidr) = 4),
+    ipv6_cidr  cidr               NOT NULL CHECK (family(ipv6_cidr) = 6),
+    reserved   cidr[]             NOT NULL DEFAULT '{}',
+    policy     allocation_policy  NOT NULL DEFAULT 'lowest_free',
+    ipv6_mode  ipv6_mode          NOT NULL DEFAULT 'independent'
+);
+SELECT periods.add_system_time_period('ip_pools', 'row_start', 'row_end');
+SELECT periods.add_system_versioning('ip_pools');
+
+-- WireGuard addresses that must never be handed out, in addition to the
+-- `reserved` ranges of each pool
+CREATE TABLE ip_reservations (
+    cidr        cidr         PRIMARY KEY,
+    reason      text,
+    added_time  timestamptz  NOT NULL DEFAULT now()
+);
+SELECT periods.add_system_time_period('ip_reservations', 'row_start', 'row_end');
+SELECT periods.add_system_versioning('ip_reservations');
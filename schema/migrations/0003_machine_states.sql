@@ -0,0 +1,49 @@
+-- Where a machine is in its lifecycle.  Retired machines stay in the inventory
+-- but are left out of WireGuard peers and ssh_config.
+CREATE DOMAIN machine_state  AS varchar(16)  CHECK (VALUE IN ('planned', 'active', 'decommissioning', 'retired'));
+
+-- Separate table so that existing machines and their history are left alone;
+-- machines without a row here are active
+CREATE TABLE machine_states (
+    hostname  hostname       PRIMARY KEY REFERENCES machines,
+    state     machine_state  NOT NULL
+);
+SELECT periods.add_system_time_period('machine_states', 'row_start', 'row_end');
+SELECT periods.add_system_versioning('machine_states');
+
+CREATE OR REPLACE VIEW machines_view AS
+    SELECT
+        machines.hostname,
+        added_time,
+        owner,
+        provider_id,
+        providers.name AS provider_name,
+        providers.email AS provider_email,
+        provider_reference,
+        coalesce(networks.networks, ARRAY['NONE']) AS networks,
+        wireguard_ipv4_address,
+        wireguard_ipv6_address,
+        wireguard_port,
+        wireguard_privkey,
+        wireguard_pubkey,
+        ssh_port,
+        ssh_user,
+        coalesce(machine_states.state, 'active')::varchar AS state
+    FROM machines
+    LEFT JOIN wireguard_interfaces ON machines.hostname    = wireguard_interfaces.hostname
+    LEFT JOIN ssh_servers          ON machines.hostname    = ssh_servers.hostname
+    LEFT JOIN providers            ON machines.provider_id = providers.id
+    LEFT JOIN machine_states       ON machines.hostname    = machine_states.hostname
+    LEFT JOIN (SELECT hostname, array_agg(network::varchar) AS networks FROM machine_addresses GROUP BY hostname) networks ON machines.hostname = networks.hostname;
+
+-- Remove a machine from all non-history tables
+CREATE OR REPLACE PROCEDURE remove_machine(kill_hostname varchar)
+LANGUAGE SQL
+AS $$
+    DELETE FROM wireguard_interfaces WHERE hostname = kill_hostname;
+    DELETE FROM ssh_servers          WHERE hostname = kill_hostname;
+    DELETE FROM machine_addresses    WHERE hostname = kill_hostname;
+    DELETE FROM wireguard_keepalives WHERE source_machine = kill_hostname OR target_machine = kill_hostname;
+    DELETE FROM machine_states       WHERE hostname = kill_hostname;
+    DELETE FROM machines             WHERE hostname = kill_hostname;
+$$;
@@ -0,0 +1,96 @@
+-- The schema for SQLite inventories, kept equivalent to schema/migrations/.
+--
+-- Domains become CHECK constraints, inet and cidr become text, and times are
+-- RFC 3339 text in UTC.  Tables that the periods extension versions in Postgres
+-- have a row_start column here; their _history tables and the triggers that
+-- fill them are created by store/sqlite.rs.
+--
+-- This file is version 1.  Later changes are in schema/sqlite_migrations/ and
+-- are applied in order after it, by `i db init` and `i db migrate`.
+
+CREATE TABLE networks (
+    name  text  PRIMARY KEY CHECK (name = 'NONE' OR (length(name) BETWEEN 1 AND 32 AND name NOT GLOB '*[^-_a-z0-9]*'))
+);
+
+CREATE TABLE network_links (
+    name           text     NOT NULL REFERENCES networks(name),
+    other_network  text     NOT NULL REFERENCES networks(name),
+    priority       integer  NOT NULL,
+    row_start      text     NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%fZ', 'now')),
+    PRIMARY KEY (name, other_network)
+);
+
+CREATE TABLE providers (
+    id         integer  PRIMARY KEY CHECK (id >= 1),
+    name       text     NOT NULL CHECK (length(name) <= 32),
+    email      text     NOT NULL CHECK (length(email) <= 254 AND email LIKE '_%@_%'),
+    row_start  text     NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%fZ', 'now'))
+);
+
+CREATE TABLE owners (
+    owner  text  NOT NULL PRIMARY KEY CHECK (length(owner) <= 32)
+);
+
+CREATE TABLE machines (
+    hostname            text     PRIMARY KEY CHECK (length(hostname) BETWEEN 1 AND 32 AND hostname NOT GLOB '*[^-_a-z0-9]*'),
+    added_time          text     NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%fZ', 'now')),
+    owner               text     NOT NULL REFERENCES owners(owner),
+    provider_id         integer  REFERENCES providers(id),
+    provider_reference  text,
+    row_start           text     NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%fZ', 'now'))
+);
+
+CREATE TABLE wireguard_interfaces (
+    hostname                text     PRIMARY KEY REFERENCES machines,
+    wireguard_ipv4_address  text     NOT NULL,
+    wireguard_ipv6_address  text     NOT NULL,
+    wireguard_port          integer  NOT NULL CHECK (wireguard_port BETWEEN 1 AND 65535),
+    wireguard_privkey       text     NOT NULL UNIQUE CHECK (length(wireguard_privkey) = 44 AND wireguard_privkey GLOB '*=' AND substr(wireguard_privkey, 1, 43) NOT GLOB '*[^+/A-Za-z0-9]*'),
+    wireguard_pubkey        text     NOT NULL UNIQUE CHECK (length(wireguard_pubkey) = 44 AND wireguard_pubkey GLOB '*=' AND substr(wireguard_pubkey, 1, 43) NOT GLOB '*[^+/A-Za-z0-9]*'),
+    row_start               text     NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%fZ', 'now'))
+);
+
+CREATE TABLE ssh_servers (
+    hostname   text     PRIMARY KEY REFERENCES machines,
+    ssh_port   integer  NOT NULL CHECK (ssh_port BETWEEN 1 AND 65535),
+    ssh_user   text     NOT NULL DEFAULT 'root' CHECK (length(ssh_user) BETWEEN 2 AND 32 AND ssh_user GLOB '[a-z]*' AND ssh_user NOT GLOB '*[^-_a-z0-9]*'),
+    row_start  text     NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%fZ', 'now'))
+);
+
+CREATE TABLE wireguard_keepalives (
+    source_machine  text     NOT NULL REFERENCES machines(hostname),
+    target_machine  text     NOT NULL REFERENCES machines(hostname),
+    interval_sec    integer  NOT NULL CHECK (interval_sec BETWEEN 1 AND 65535),
+    row_start       text     NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%fZ', 'now')),
+    PRIMARY KEY (source_machine, target_machine)
+);
+
+CREATE TABLE ip_pools (
+    name       text  PRIMARY KEY CHECK (length(name) BETWEEN 1 AND 32 AND name NOT GLOB '*[^-_a-z0-9]*'),
+    ipv4_cidr  text  NOT NULL,
+    ipv6_cidr  text  NOT NULL,
+    -- Space-separated CIDRs
+    reserved   text  NOT NULL DEFAULT '',
+    policy     text  NOT NULL DEFAULT 'lowest_free' CHECK (policy IN ('lowest_free', 'after_highest')),
+    ipv6_mode  text  NOT NULL DEFAULT 'independent' CHECK (ipv6_mode IN ('independent', 'embed_ipv4')),
+    row_start  text  NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%fZ', 'now'))
+);
+
+CREATE TABLE ip_reservations (
+    cidr        text  PRIMARY KEY,
+    reason      text,
+    added_time  text  NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%fZ', 'now')),
+    row_start   text  NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%fZ', 'now'))
+);
+
+CREATE TABLE machine_addresses (
+    hostname        text     NOT NULL REFERENCES machines,
+    network         text     NOT NULL REFERENCES networks(name),
+    address         text     NOT NULL,
+    ssh_port        integer  CHECK (ssh_port BETWEEN 1 AND 65535),
+    wireguard_port  integer  CHECK (wireguard_port BETWEEN 1 AND 65535),
+    row_start       text     NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%fZ', 'now')),
+    PRIMARY KEY (hostname, network, address),
+    UNIQUE (address, ssh_port),
+    UNIQUE (address, wireguard_port)
+);
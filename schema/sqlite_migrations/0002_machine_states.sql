@@ -0,0 +1,7 @@
+-- Like schema/migrations/0003_machine_states.sql; machines without a row here are active
+
+CREATE TABLE machine_states (
+    hostname   text  PRIMARY KEY REFERENCES machines,
+    state      text  NOT NULL CHECK (state IN ('planned', 'active', 'decommissioning', 'retired')),
+    row_start  text  NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%fZ', 'now'))
+);
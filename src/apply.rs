@@ -0,0 +1,613 @@
+//! Declarative inventory for `i apply`.
+//!
+//! The desired state is read from a file with the same layout as `i export`,
+//! so the output of `i export` can be applied as-is.  Each top-level list is
+//! optional: a list that is left out is not managed and nothing in it is
+//! changed, while a list that is present (even if empty) is the complete
+//! desired contents of that table, and everything not in it is removed.
+//! `ip_pools` and `ip_reservations` are never managed by `i apply`.
+//!
+//! Fields of a machine that are left out keep their current value, or for a new
+//! machine, get the same defaults as `i add`: WireGuard addresses are allocated
+//! from the pool and a new keypair is generated.
+//!
+//! Applying happens in two steps: `Desired::resolve` fills in everything that
+//! was left out to get the complete target inventory, and `plan` compares that
+//! to the current inventory to get the list of changes to make.
+
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use anyhow::{anyhow, bail, ensure, Result};
+use chrono::Utc;
+use serde::Deserialize;
+use infrabase::model::MachineState;
+
+use crate::inventory::{self, Inventory, MachineAddress, NetworkLink, Provider, SshServer, WireguardInterface, WireguardKeepalive};
+
+/// The desired inventory, as read from the file given to `i apply`
+#[derive(Debug, Default, Deserialize)]
+pub(crate) struct Desired {
+    pub version: Option<u32>,
+    pub owners: Option<Vec<String>>,
+    pub networks: Option<Vec<String>>,
+    pub providers: Option<Vec<Provider>>,
+    pub network_links: Option<Vec<NetworkLink>>,
+    pub machines: Option<Vec<DesiredMachine>>,
+    pub machine_addresses: Option<Vec<MachineAddress>>,
+    pub wireguard_keepalives: Option<Vec<WireguardKeepalive>>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub(crate) struct DesiredMachine {
+    pub hostname: String,
+    pub owner: Option<String>,
+    pub state: Option<MachineState>,
+    pub provider_id: Option<i32>,
+    pub provider_reference: Option<String>,
+    pub ssh: Option<DesiredSshServer>,
+    pub wireguard: Option<DesiredWireguardInterface>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub(crate) struct DesiredSshServer {
+    pub port: Option<i32>,
+    pub user: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub(crate) struct DesiredWireguardInterface {
+    pub ipv4_address: Option<Ipv4Addr>,
+    pub ipv6_address: Option<Ipv6Addr>,
+    pub port: Option<i32>,
+    pub pubkey: Option<String>,
+    pub privkey: Option<String>,
+    /// Pool to allocate addresses from if they are not given
+    pub pool: Option<String>,
+}
+
+/// Defaults for new machines, see the help for `i add`
+#[derive(Debug, Default)]
+pub(crate) struct Defaults {
+    pub owner: Option<String>,
+    pub provider_id: Option<i32>,
+    pub ssh_port: Option<i32>,
+    pub ssh_user: Option<String>,
+    pub wireguard_port: Option<i32>,
+}
+
+/// Supplies WireGuard addresses and keys for machines that do not specify them
+pub(crate) trait Allocator {
+    /// Allocate whichever of `ipv4` and `ipv6` is None from `pool`, or the
+    /// default pool if None
+    fn allocate_addresses(&mut self, pool: Option<&str>, ipv4: Option<Ipv4Addr>, ipv6: Option<Ipv6Addr>) -> Result<(Ipv4Addr, Ipv6Addr)>;
+
+    /// Generate a new (privkey, pubkey)
+    fn generate_keypair(&mut self) -> Result<(String, String)>;
+
+    fn get_pubkey(&mut self, privkey: &str) -> Result<String>;
+}
+
+/// Fail if `key` gives the same key for two items
+fn ensure_unique<T, K: Ord + fmt::Debug>(what: &str, items: &[T], key: impl Fn(&T) -> K) -> Result<()> {
+    let mut seen = BTreeSet::new();
+    for item in items {
+        ensure!(seen.insert(key(item)), "{} {:?} is listed more than once", what, key(item));
+    }
+    Ok(())
+}
+
+impl Desired {
+    /// WireGuard addresses given explicitly for any machine, which must not be
+    /// allocated to another machine
+    pub fn requested_wireguard_addresses(&self) -> HashSet<IpAddr> {
+        self.machines.iter().flatten()
+            .filter_map(|machine| machine.wireguard.as_ref())
+            .flat_map(|wireguard| {
+                wireguard.ipv4_address.map(IpAddr::V4).into_iter().chain(wireguard.ipv6_address.map(IpAddr::V6))
+            })
+            .collect()
+    }
+
+    /// Get the complete target inventory by filling in everything left out
+    /// from `current`, `defaults`, and `allocator`
+    pub fn resolve(self, current: &Inventory, defaults: &Defaults, allocator: &mut impl Allocator) -> Result<Inventory> {
+        if let Some(version) = self.version {
+            ensure!(version <= inventory::FORMAT_VERSION,
+                "File has inventory format version {}, but this version of infrabase only understands up to {}",
+                version, inventory::FORMAT_VERSION);
+        }
+
+        let machines = match self.machines {
+            Some(desired_machines) => {
+                ensure_unique("Machine", &desired_machines, |m| m.hostname.clone())?;
+                let current_machines = current.machines.iter()
+                    .map(|machine| (machine.hostname.as_str(), machine))
+                    .collect::<BTreeMap<_, _>>();
+                desired_machines.into_iter()
+                    .map(|desired| {
+                        let current = current_machines.get(desired.hostname.as_str()).copied();
+                        desired.resolve(current, defaults, allocator)
+                    })
+                    .collect::<Result<Vec<_>>>()?
+            }
+            None => current.machines.clone(),
+        };
+
+        // Addresses and keepalives of machines that will be removed go away with
+        // the machine, even if those lists are not managed
+        let hostnames = machines.iter().map(|m| m.hostname.as_str()).collect::<HashSet<_>>();
+        let machine_addresses = match self.machine_addresses {
+            Some(addresses) => addresses,
+            None => current.machine_addresses.iter()
+                .filter(|a| hostnames.contains(a.hostname.as_str()))
+                .cloned()
+                .collect(),
+        };
+        let wireguard_keepalives = match self.wireguard_keepalives {
+            Some(keepalives) => keepalives,
+            None => current.wireguard_keepalives.iter()
+                .filter(|k| hostnames.contains(k.source_machine.as_str()) && hostnames.contains(k.target_machine.as_str()))
+                .cloned()
+                .collect(),
+        };
+
+        let target = Inventory {
+            version: inventory::FORMAT_VERSION,
+            owners: self.owners.unwrap_or_else(|| current.owners.clone()),
+            networks: self.networks.unwrap_or_else(|| current.networks.clone()),
+            providers: self.providers.unwrap_or_else(|| current.providers.clone()),
+            network_links: self.network_links.unwrap_or_else(|| current.network_links.clone()),
+            ip_pools: current.ip_pools.clone(),
+            ip_reservations: current.ip_reservations.clone(),
+            machines,
+            machine_addresses,
+            wireguard_keepalives,
+        };
+        ensure_unique("Owner", &target.owners, Clone::clone)?;
+        ensure_unique("Network", &target.networks, Clone::clone)?;
+        ensure_unique("Provider", &target.providers, |p| p.id)?;
+        ensure_unique("Network link", &target.network_links, |l| (l.network.clone(), l.other_network.clone()))?;
+        ensure_unique("Address", &target.machine_addresses, |a| (a.hostname.clone(), a.network.clone(), a.address))?;
+        ensure_unique("Keepalive", &target.wireguard_keepalives, |k| (k.source_machine.clone(), k.target_machine.clone()))?;
+        Ok(target)
+    }
+}
+
+impl DesiredMachine {
+    fn resolve(self, current: Option<&inventory::Machine>, defaults: &Defaults, allocator: &mut impl Allocator) -> Result<inventory::Machine> {
+        let hostname = self.hostname;
+        let missing = |what: &str, var: &str| {
+            anyhow!("Machine {:?} has no {}, and {} is not set in the environment", hostname, what, var)
+        };
+
+        let owner = match (self.owner, current) {
+            (Some(owner), _) => owner,
+            (None, Some(current)) => current.owner.clone(),
+            (None, None) => defaults.owner.clone().ok_or_else(|| missing("owner", "DEFAULT_OWNER"))?,
+        };
+        let state = self.state.or_else(|| current.map(|c| c.state)).unwrap_or_default();
+        let (provider_id, provider_reference) = match current {
+            Some(current) => (
+                self.provider_id.or(current.provider_id),
+                self.provider_reference.or_else(|| current.provider_reference.clone()),
+            ),
+            None => (self.provider_id.or(defaults.provider_id), self.provider_reference),
+        };
+
+        let current_ssh = current.and_then(|c| c.ssh.as_ref());
+        let ssh = match (self.ssh, current) {
+            // Existing machines keep their SSH server, or lack of one
+            (None, Some(current)) => current.ssh.clone(),
+            (desired, _) => {
+                let desired = desired.unwrap_or_default();
+                Some(SshServer {
+                    port: desired.port
+                        .or_else(|| current_ssh.map(|ssh| ssh.port))
+                        .or(defaults.ssh_port)
+                        .ok_or_else(|| missing("SSH port", "DEFAULT_SSH_PORT"))?,
+                    user: desired.user
+                        .or_else(|| current_ssh.map(|ssh| ssh.user.clone()))
+                        .or_else(|| defaults.ssh_user.clone())
+                        .ok_or_else(|| missing("SSH user", "DEFAULT_SSH_USER"))?,
+                })
+            }
+        };
+
+        let current_wireguard = current.and_then(|c| c.wireguard.as_ref());
+        let wireguard = match (self.wireguard, current) {
+            (None, Some(current)) => current.wireguard.clone(),
+            (desired, _) => {
+                let desired = desired.unwrap_or_default();
+                let port = desired.port
+                    .or_else(|| current_wireguard.map(|wg| wg.port))
+                    .or(defaults.wireguard_port)
+                    .ok_or_else(|| missing("WireGuard port", "DEFAULT_WIREGUARD_PORT"))?;
+                let ipv4 = desired.ipv4_address.or_else(|| current_wireguard.map(|wg| wg.ipv4_address));
+                let ipv6 = desired.ipv6_address.or_else(|| current_wireguard.map(|wg| wg.ipv6_address));
+                let (ipv4_address, ipv6_address) = match (ipv4, ipv6) {
+                    (Some(ipv4), Some(ipv6)) => (ipv4, ipv6),
+                    (ipv4, ipv6) => allocator.allocate_addresses(desired.pool.as_deref(), ipv4, ipv6)?,
+                };
+                let (privkey, pubkey) = match (desired.privkey, desired.pubkey, current_wireguard) {
+                    (Some(privkey), pubkey, _) => {
+                        let derived = allocator.get_pubkey(&privkey)?;
+                        if let Some(pubkey) = pubkey {
+                            ensure!(pubkey == derived, "Machine {:?} has a WireGuard pubkey that does not match its privkey", hostname);
+                        }
+                        (privkey, derived)
+                    }
+                    (None, Some(pubkey), Some(current)) if pubkey == current.pubkey => {
+                        (current.privkey.clone().expect("current inventory must include privkeys"), pubkey)
+                    }
+                    (None, Some(_), _) => {
+                        bail!("Machine {:?} has a new WireGuard pubkey but no privkey; give the privkey instead", hostname)
+                    }
+                    (None, None, Some(current)) => {
+                        (current.privkey.clone().expect("current inventory must include privkeys"), current.pubkey.clone())
+                    }
+                    (None, None, None) => allocator.generate_keypair()?,
+                };
+                Some(WireguardInterface { ipv4_address, ipv6_address, port, pubkey, privkey: Some(privkey) })
+            }
+        };
+
+        Ok(inventory::Machine {
+            added_time: current.map_or_else(Utc::now, |c| c.added_time),
+            hostname,
+            owner,
+            state,
+            provider_id,
+            provider_reference,
+            ssh,
+            wireguard,
+        })
+    }
+}
+
+/// A row in one of the tables managed by `i apply`
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Record {
+    Owner(String),
+    Network(String),
+    Provider(Provider),
+    NetworkLink(NetworkLink),
+    Machine(inventory::Machine),
+    MachineAddress(MachineAddress),
+    WireguardKeepalive(WireguardKeepalive),
+}
+
+fn field<T: ToString>(name: &'static str, value: Option<T>) -> (&'static str, Option<String>) {
+    (name, value.map(|v| v.to_string()))
+}
+
+impl Record {
+    /// What the record is, e.g. "machine web1"
+    fn name(&self) -> String {
+        match self {
+            Record::Owner(owner) => format!("owner {owner}"),
+            Record::Network(network) => format!("network {network}"),
+            Record::Provider(provider) => format!("provider {}", provider.id),
+            Record::NetworkLink(link) => format!("network link {} -> {}", link.network, link.other_network),
+            Record::Machine(machine) => format!("machine {}", machine.hostname),
+            Record::MachineAddress(address) => format!("address {} {} {}", address.hostname, address.network, address.address),
+            Record::WireguardKeepalive(keepalive) => format!("keepalive {} -> {}", keepalive.source_machine, keepalive.target_machine),
+        }
+    }
+
+    /// The values of the record other than the ones in its name
+    fn fields(&self) -> Vec<(&'static str, Option<String>)> {
+        match self {
+            Record::Owner(_) | Record::Network(_) => vec![],
+            Record::Provider(provider) => vec![
+                field("name", Some(&provider.name)),
+                field("email", Some(&provider.email)),
+            ],
+            Record::NetworkLink(link) => vec![field("priority", Some(link.priority))],
+            Record::Machine(machine) => {
+                let ssh = machine.ssh.as_ref();
+                let wireguard = machine.wireguard.as_ref();
+                vec![
+                    field("owner", Some(&machine.owner)),
+                    field("state", Some(machine.state)),
+                    field("provider_id", machine.provider_id),
+                    field("provider_reference", machine.provider_reference.as_ref()),
+                    field("ssh_port", ssh.map(|ssh| ssh.port)),
+                    field("ssh_user", ssh.map(|ssh| &ssh.user)),
+                    field("wireguard_ipv4_address", wireguard.map(|wg| wg.ipv4_address)),
+                    field("wireguard_ipv6_address", wireguard.map(|wg| wg.ipv6_address)),
+                    field("wireguard_port", wireguard.map(|wg| wg.port)),
+                    field("wireguard_pubkey", wireguard.map(|wg| &wg.pubkey)),
+                ]
+            }
+            Record::MachineAddress(address) => vec![
+                field("ssh_port", address.ssh_port),
+                field("wireguard_port", address.wireguard_port),
+            ],
+            Record::WireguardKeepalive(keepalive) => vec![field("interval_sec", Some(keepalive.interval_sec))],
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Change {
+    Add(Record),
+    /// (current, target)
+    Update(Record, Record),
+    Remove(Record),
+}
+
+/// Fields whose change breaks connectivity until every peer is reconfigured
+const DISRUPTIVE_FIELDS: &[&str] = &["wireguard_ipv4_address", "wireguard_ipv6_address", "wireguard_pubkey"];
+
+impl Change {
+    /// Whether the change removes data or breaks existing WireGuard peers
+    pub fn is_destructive(&self) -> bool {
+        match self {
+            Change::Add(_) => false,
+            Change::Update(current, target) => {
+                changed_fields(current, target).iter().any(|(name, _, _)| DISRUPTIVE_FIELDS.contains(name))
+            }
+            Change::Remove(_) => true,
+        }
+    }
+}
+
+/// (name, current value, target value) of each field that differs
+fn changed_fields(current: &Record, target: &Record) -> Vec<(&'static str, Option<String>, Option<String>)> {
+    current.fields().into_iter()
+        .zip(target.fields())
+        .filter(|((_, current), (_, target))| current != target)
+        .map(|((name, current), (_, target))| (name, current, target))
+        .collect()
+}
+
+impl fmt::Display for Change {
+    /// One line like `+ machine web1: owner=ivan ssh_port=22` or
+    /// `~ machine web1: owner ivan -> alice`
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Change::Add(record) => {
+                write!(f, "+ {}", record.name())?;
+                let fields = record.fields().into_iter()
+                    .filter_map(|(name, value)| value.map(|value| format!("{name}={value}")))
+                    .collect::<Vec<_>>();
+                if !fields.is_empty() {
+                    write!(f, ": {}", fields.join(" "))?;
+                }
+                Ok(())
+            }
+            Change::Update(current, target) => {
+                let none = || "(none)".to_string();
+                let fields = changed_fields(current, target).into_iter()
+                    .map(|(name, current, target)| format!("{name} {} -> {}", current.unwrap_or_else(none), target.unwrap_or_else(none)))
+                    .collect::<Vec<_>>();
+                write!(f, "~ {}: {}", target.name(), fields.join(", "))
+            }
+            Change::Remove(record) => write!(f, "- {}", record.name()),
+        }
+    }
+}
+
+/// Compare two lists of records by key.  Returns the removals and the
+/// additions and updates separately, so that all removals can be done first.
+fn diff<T: Clone + PartialEq, K: Ord>(
+    current: &[T],
+    target: &[T],
+    key: impl Fn(&T) -> K,
+    record: impl Fn(T) -> Record,
+) -> (Vec<Change>, Vec<Change>) {
+    let current_map = current.iter().map(|item| (key(item), item)).collect::<BTreeMap<_, _>>();
+    let target_keys = target.iter().map(&key).collect::<BTreeSet<_>>();
+    let removals = current.iter()
+        .filter(|item| !target_keys.contains(&key(item)))
+        .map(|item| Change::Remove(record(item.clone())))
+        .collect();
+    let mut additions = vec![];
+    for item in target {
+        match current_map.get(&key(item)) {
+            None => additions.push(Change::Add(record(item.clone()))),
+            Some(&current) if current != item => additions.push(Change::Update(record(current.clone()), record(item.clone()))),
+            Some(_) => {}
+        }
+    }
+    (removals, additions)
+}
+
+/// The changes that turn `current` into `target`, in an order that can be
+/// executed without violating foreign keys: removals of dependent rows first,
+/// then additions and updates of the rows they depend on first.
+pub(crate) fn plan(current: &Inventory, target: &Inventory) -> Vec<Change> {
+    let owners = diff(&current.owners, &target.owners, Clone::clone, Record::Owner);
+    let networks = diff(&current.networks, &target.networks, Clone::clone, Record::Network);
+    let providers = diff(&current.providers, &target.providers, |p| p.id, Record::Provider);
+    let network_links = diff(
+        &current.network_links, &target.network_links,
+        |l| (l.network.clone(), l.other_network.clone()), Record::NetworkLink);
+    let machines = diff(&current.machines, &target.machines, |m| m.hostname.clone(), Record::Machine);
+    let machine_addresses = diff(
+        &current.machine_addresses, &target.machine_addresses,
+        |a| (a.hostname.clone(), a.network.clone(), a.address), Record::MachineAddress);
+    let wireguard_keepalives = diff(
+        &current.wireguard_keepalives, &target.wireguard_keepalives,
+        |k| (k.source_machine.clone(), k.target_machine.clone()), Record::WireguardKeepalive);
+
+    let in_dependency_order = vec![owners, networks, providers, network_links, machines, machine_addresses, wireguard_keepalives];
+    let mut changes = vec![];
+    for (removals, _) in in_dependency_order.iter().rev() {
+        changes.extend(removals.iter().cloned());
+    }
+    for (_, additions) in in_dependency_order {
+        changes.extend(additions);
+    }
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::{machine, PRIVKEY, PUBKEY};
+
+    struct FakeAllocator {
+        next_host: u8,
+    }
+
+    impl Allocator for FakeAllocator {
+        fn allocate_addresses(&mut self, _pool: Option<&str>, ipv4: Option<Ipv4Addr>, ipv6: Option<Ipv6Addr>) -> Result<(Ipv4Addr, Ipv6Addr)> {
+            self.next_host += 1;
+            Ok((
+                ipv4.unwrap_or_else(|| Ipv4Addr::new(10, 10, 1, self.next_host)),
+                ipv6.unwrap_or_else(|| format!("fd00::1:{}", self.next_host).parse().unwrap()),
+            ))
+        }
+
+        fn generate_keypair(&mut self) -> Result<(String, String)> {
+            Ok(("p".repeat(43) + "=", "P".repeat(43) + "="))
+        }
+
+        fn get_pubkey(&mut self, privkey: &str) -> Result<String> {
+            Ok(privkey.to_uppercase())
+        }
+    }
+
+    fn defaults() -> Defaults {
+        Defaults {
+            owner: Some("ivan".to_string()),
+            provider_id: None,
+            ssh_port: Some(22),
+            ssh_user: Some("root".to_string()),
+            wireguard_port: Some(904),
+        }
+    }
+
+    fn current() -> Inventory {
+        Inventory {
+            version: inventory::FORMAT_VERSION,
+            owners: vec!["ivan".to_string()],
+            networks: vec!["internet".to_string()],
+            providers: vec![],
+            network_links: vec![],
+            ip_pools: vec![],
+            ip_reservations: vec![],
+            machines: vec![machine("web1", "10.10.0.1", "fd00::1"), machine("old", "10.10.0.2", "fd00::2")],
+            machine_addresses: vec![MachineAddress {
+                hostname: "old".to_string(),
+                network: "internet".to_string(),
+                address: "203.0.113.5".parse().unwrap(),
+                ssh_port: Some(22),
+                wireguard_port: Some(904),
+            }],
+            wireguard_keepalives: vec![],
+        }
+    }
+
+    fn resolve(yaml: &str) -> Result<Inventory> {
+        let desired: Desired = serde_yaml::from_str(yaml).unwrap();
+        desired.resolve(&current(), &defaults(), &mut FakeAllocator { next_host: 0 })
+    }
+
+    fn lines(changes: &[Change]) -> Vec<String> {
+        changes.iter().map(|change| change.to_string()).collect()
+    }
+
+    /// New machines get the defaults, and addresses and keys are allocated
+    #[test]
+    fn test_resolve_new_machine() {
+        let target = resolve("machines:\n  - hostname: web1\n  - hostname: web2\n").unwrap();
+        let web2 = &target.machines[1];
+        assert_eq!(web2.owner, "ivan");
+        assert_eq!(web2.ssh, Some(SshServer { port: 22, user: "root".to_string() }));
+        let wireguard = web2.wireguard.as_ref().unwrap();
+        assert_eq!(wireguard.ipv4_address, Ipv4Addr::new(10, 10, 1, 1));
+        assert_eq!(wireguard.port, 904);
+        assert_eq!(wireguard.pubkey, "P".repeat(43) + "=");
+    }
+
+    /// Fields that are left out keep their current value
+    #[test]
+    fn test_resolve_keeps_current_values() {
+        let target = resolve("machines:\n  - hostname: web1\n    owner: alice\n    wireguard:\n      ipv4_address: 10.10.0.9\n").unwrap();
+        let web1 = &target.machines[0];
+        assert_eq!(web1.owner, "alice");
+        assert_eq!(web1.added_time, machine("web1", "10.10.0.1", "fd00::1").added_time);
+        let wireguard = web1.wireguard.as_ref().unwrap();
+        assert_eq!(wireguard.ipv4_address, Ipv4Addr::new(10, 10, 0, 9));
+        assert_eq!(wireguard.ipv6_address, "fd00::1".parse::<Ipv6Addr>().unwrap());
+        assert_eq!(wireguard.privkey, Some(PRIVKEY.to_string()));
+    }
+
+    /// A given privkey determines the pubkey, and a pubkey alone cannot be changed
+    #[test]
+    fn test_resolve_keys() {
+        let target = resolve("machines:\n  - hostname: web2\n    wireguard:\n      privkey: abc\n").unwrap();
+        assert_eq!(target.machines[0].wireguard.as_ref().unwrap().pubkey, "ABC");
+        assert!(resolve("machines:\n  - hostname: web2\n    wireguard:\n      privkey: abc\n      pubkey: xyz\n").is_err());
+        assert!(resolve("machines:\n  - hostname: web1\n    wireguard:\n      pubkey: xyz\n").is_err());
+        assert!(resolve(&format!("machines:\n  - hostname: web1\n    wireguard:\n      pubkey: {}\n", PUBKEY)).is_ok());
+    }
+
+    #[test]
+    fn test_resolve_errors() {
+        assert!(resolve("machines:\n  - hostname: web1\n  - hostname: web1\n").is_err());
+        assert!(resolve("owners: [ivan, ivan]\n").is_err());
+        assert!(resolve("version: 99\n").is_err());
+        let desired: Desired = serde_yaml::from_str("machines:\n  - hostname: web2\n").unwrap();
+        let error = desired.resolve(&current(), &Defaults::default(), &mut FakeAllocator { next_host: 0 }).unwrap_err();
+        assert_eq!(error.to_string(), "Machine \"web2\" has no owner, and DEFAULT_OWNER is not set in the environment");
+    }
+
+    /// Lists that are left out are not changed
+    #[test]
+    fn test_plan_no_changes() {
+        let current = current();
+        assert_eq!(plan(&current, &resolve("{}").unwrap()), vec![]);
+        assert_eq!(plan(&current, &resolve("owners: [ivan]\nmachines:\n  - hostname: web1\n  - hostname: old\n").unwrap()), vec![]);
+    }
+
+    /// Removals come first with dependent rows before the rows they depend on,
+    /// then additions with the rows depended on first
+    #[test]
+    fn test_plan() {
+        let target = resolve(concat!(
+            "owners: [ivan, alice]\n",
+            "networks: []\n",
+            "machines:\n",
+            "  - hostname: web1\n",
+            "    owner: alice\n",
+            "  - hostname: web2\n",
+        )).unwrap();
+        let changes = plan(&current(), &target);
+        assert_eq!(lines(&changes), vec![
+            "- address old internet 203.0.113.5".to_string(),
+            "- machine old".to_string(),
+            "- network internet".to_string(),
+            "+ owner alice".to_string(),
+            "~ machine web1: owner ivan -> alice".to_string(),
+            format!(
+                "+ machine web2: owner=ivan state=active ssh_port=22 ssh_user=root wireguard_ipv4_address=10.10.1.1 \
+                 wireguard_ipv6_address=fd00::1:1 wireguard_port=904 wireguard_pubkey={}", "P".repeat(43) + "="),
+        ]);
+        let destructive = changes.iter().map(Change::is_destructive).collect::<Vec<_>>();
+        assert_eq!(destructive, vec![true, true, true, false, false, false]);
+    }
+
+    /// Changing WireGuard addresses or keys is destructive, other updates are not
+    #[test]
+    fn test_destructive_updates() {
+        let changes = plan(&current(), &resolve("machines:\n  - hostname: web1\n    wireguard:\n      port: 905\n").unwrap());
+        assert_eq!(changes.last().unwrap().to_string(), "~ machine web1: wireguard_port 904 -> 905");
+        assert!(!changes.last().unwrap().is_destructive());
+
+        let changes = plan(&current(), &resolve("machines:\n  - hostname: web1\n    wireguard:\n      ipv6_address: fd00::9\n").unwrap());
+        assert_eq!(changes.last().unwrap().to_string(), "~ machine web1: wireguard_ipv6_address fd00::1 -> fd00::9");
+        assert!(changes.last().unwrap().is_destructive());
+    }
+
+    /// Machines keep their state unless it is given
+    #[test]
+    fn test_plan_state() {
+        let target = resolve("machines:\n  - hostname: web1\n    state: retired\n  - hostname: old\n").unwrap();
+        assert_eq!(lines(&plan(&current(), &target)), vec!["~ machine web1: state active -> retired".to_string()]);
+        assert!(serde_yaml::from_str::<Desired>("machines:\n  - hostname: web1\n    state: gone\n").is_err());
+    }
+}
@@ -0,0 +1,189 @@
+//! Full backups for `i dump` and `i restore`.
+//!
+//! A dump is a JSON document with every row of every table, including the
+//! `_history` tables kept by the periods extension, so that restoring it into a
+//! freshly created schema gives back the same inventory and the same history.
+//! Each row is the output of Postgres's `row_to_json`, and is restored with
+//! `json_populate_recordset`, so values round-trip through their Postgres text
+//! representation.
+
+use std::collections::BTreeSet;
+use anyhow::{bail, ensure, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// Identifies a file as an infrabase dump
+const FORMAT: &str = "infrabase-dump";
+
+/// Version of the dump format
+pub(crate) const FORMAT_VERSION: u32 = 1;
+
+/// All tables, in an order where every table comes after the tables it references.
+/// `schema_migrations` is not included; the dump records the schema version instead.
+pub(crate) const TABLES: &[&str] = &[
+    "networks",
+    "network_links",
+    "providers",
+    "owners",
+    "machines",
+    "machine_states",
+    "wireguard_interfaces",
+    "ssh_servers",
+    "wireguard_keepalives",
+    "ip_pools",
+    "ip_reservations",
+    "machine_addresses",
+];
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct Dump {
+    pub format: String,
+    pub version: u32,
+    pub dumped_time: DateTime<Utc>,
+    /// Version of the database schema the rows are from, see `migrations`
+    pub schema_version: i32,
+    pub tables: Vec<TableDump>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct TableDump {
+    pub name: String,
+    /// One JSON object per row, keyed by column name
+    pub rows: Vec<Value>,
+}
+
+impl Dump {
+    pub fn new(schema_version: i32, tables: Vec<TableDump>) -> Dump {
+        Dump { format: FORMAT.to_string(), version: FORMAT_VERSION, dumped_time: Utc::now(), schema_version, tables }
+    }
+
+    pub fn parse(s: &str) -> Result<Dump> {
+        let dump: Dump = serde_json::from_str(s)?;
+        ensure!(dump.format == FORMAT, "Not an infrabase dump: format is {:?}", dump.format);
+        ensure!(dump.version <= FORMAT_VERSION,
+            "Dump has format version {}, but this version of infrabase only understands up to {}",
+            dump.version, FORMAT_VERSION);
+        Ok(dump)
+    }
+
+    pub fn table(&self, name: &str) -> Option<&TableDump> {
+        self.tables.iter().find(|table| table.name == name)
+    }
+
+    /// Check that the dump has exactly the tables in `expected`
+    pub fn check_tables(&self, expected: &[String]) -> Result<()> {
+        let names = self.tables.iter().map(|table| table.name.as_str()).collect::<BTreeSet<_>>();
+        let expected = expected.iter().map(String::as_str).collect::<BTreeSet<_>>();
+        let missing = expected.difference(&names).collect::<Vec<_>>();
+        let unknown = names.difference(&expected).collect::<Vec<_>>();
+        ensure!(missing.is_empty(), "Dump is missing tables {:?}", missing);
+        ensure!(unknown.is_empty(), "Dump has tables that are not in the database: {:?}", unknown);
+        ensure!(names.len() == self.tables.len(), "Dump lists a table more than once");
+        Ok(())
+    }
+
+    /// Get (table, hostname, privkey, pubkey) of every WireGuard interface row,
+    /// including history
+    pub fn wireguard_keys(&self) -> Result<Vec<(&str, &str, &str, &str)>> {
+        let mut keys = vec![];
+        for table in &self.tables {
+            if table.name != "wireguard_interfaces" && table.name != "wireguard_interfaces_history" {
+                continue;
+            }
+            for row in &table.rows {
+                let get = |column: &str| match row.get(column) {
+                    Some(Value::String(s)) => Ok(s.as_str()),
+                    _ => bail!("Row in {} has no string {:?}: {}", table.name, column, row),
+                };
+                keys.push((table.name.as_str(), get("hostname")?, get("wireguard_privkey")?, get("wireguard_pubkey")?));
+            }
+        }
+        Ok(keys)
+    }
+}
+
+impl TableDump {
+    /// Check that every row only has columns in `columns`, so that nothing is
+    /// silently dropped on restore
+    pub fn check_columns(&self, columns: &[String]) -> Result<()> {
+        for row in &self.rows {
+            let object = match row {
+                Value::Object(object) => object,
+                _ => bail!("Row in {} is not an object: {}", self.name, row),
+            };
+            if let Some(column) = object.keys().find(|key| !columns.contains(key)) {
+                bail!("Table {} in the dump has column {:?}, which is not in the database", self.name, column);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn dump() -> Dump {
+        Dump::new(1, vec![
+            TableDump { name: "machines".to_string(), rows: vec![json!({"hostname": "web1", "owner": "ivan"})] },
+            TableDump {
+                name: "wireguard_interfaces".to_string(),
+                rows: vec![json!({"hostname": "web1", "wireguard_privkey": "priv1", "wireguard_pubkey": "pub1"})],
+            },
+            TableDump {
+                name: "wireguard_interfaces_history".to_string(),
+                rows: vec![json!({"hostname": "old", "wireguard_privkey": "priv0", "wireguard_pubkey": "pub0"})],
+            },
+        ])
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let json = serde_json::to_string(&dump()).unwrap();
+        let parsed = Dump::parse(&json).unwrap();
+        assert_eq!(parsed.tables.len(), 3);
+        assert_eq!(parsed.table("machines").unwrap().rows[0]["hostname"], "web1");
+        assert!(parsed.table("owners").is_none());
+    }
+
+    /// Files that are not dumps or are from a newer version are refused
+    #[test]
+    fn test_parse_errors() {
+        let mut dump = dump();
+        dump.format = "something-else".to_string();
+        assert!(Dump::parse(&serde_json::to_string(&dump).unwrap()).is_err());
+        let mut dump = self::dump();
+        dump.version = FORMAT_VERSION + 1;
+        assert!(Dump::parse(&serde_json::to_string(&dump).unwrap()).is_err());
+        assert!(Dump::parse("{}").is_err());
+    }
+
+    #[test]
+    fn test_check_tables() {
+        let names = |names: &[&str]| names.iter().map(|name| name.to_string()).collect::<Vec<_>>();
+        let dump = dump();
+        assert!(dump.check_tables(&names(&["machines", "wireguard_interfaces", "wireguard_interfaces_history"])).is_ok());
+        assert!(dump.check_tables(&names(&["machines", "wireguard_interfaces"])).is_err());
+        assert!(dump.check_tables(&names(&["machines", "wireguard_interfaces", "wireguard_interfaces_history", "owners"])).is_err());
+    }
+
+    #[test]
+    fn test_check_columns() {
+        let dump = dump();
+        let table = dump.table("machines").unwrap();
+        let columns = |names: &[&str]| names.iter().map(|name| name.to_string()).collect::<Vec<_>>();
+        assert!(table.check_columns(&columns(&["hostname", "owner", "added_time"])).is_ok());
+        assert!(table.check_columns(&columns(&["hostname"])).is_err());
+    }
+
+    /// Keys are found in both the current and the history table
+    #[test]
+    fn test_wireguard_keys() {
+        assert_eq!(dump().wireguard_keys().unwrap(), vec![
+            ("wireguard_interfaces", "web1", "priv1", "pub1"),
+            ("wireguard_interfaces_history", "old", "priv0", "pub0"),
+        ]);
+    }
+}
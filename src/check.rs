@@ -0,0 +1,335 @@
+//! `i check`: find problems in the inventory that the database does not
+//! prevent, and that otherwise only show up as peers that cannot connect.
+//!
+//! Errors are things that are broken, like two machines with the same
+//! WireGuard address; warnings are things that are probably a mistake, like
+//! a network without a link to itself.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::net::IpAddr;
+use anyhow::Result;
+use infrabase::model::{get_sorted_machines, Machine, MachineAddress, MachineState, MachinesMap, NetworkLinksPriorityMap, WireguardKeepaliveIntervalMap};
+use infrabase::routing::get_wireguard_peers;
+use itertools::Itertools;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Severity {
+    Error,
+    Warning,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        })
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct Problem {
+    pub severity: Severity,
+    pub message: String,
+    /// The rows that cause the problem, e.g. "machine_addresses: web1 internet 203.0.113.5"
+    pub rows: Vec<String>,
+}
+
+fn error(message: String, rows: Vec<String>) -> Problem {
+    Problem { severity: Severity::Error, message, rows }
+}
+
+fn warning(message: String, rows: Vec<String>) -> Problem {
+    Problem { severity: Severity::Warning, message, rows }
+}
+
+fn machine_row(machine: &Machine) -> String {
+    format!("machines: {} networks={}", machine.hostname, machine.networks.join(","))
+}
+
+fn wireguard_row(machine: &Machine) -> String {
+    let optional = |value: Option<String>| value.unwrap_or_else(|| "-".to_string());
+    format!("wireguard_interfaces: {} {} {} port={}",
+        machine.hostname,
+        optional(machine.wireguard_ipv4_address.map(|address| address.to_string())),
+        optional(machine.wireguard_ipv6_address.map(|address| address.to_string())),
+        optional(machine.wireguard_port.map(|port| port.to_string())))
+}
+
+fn address_row(address: &MachineAddress) -> String {
+    let optional = |value: Option<i32>| value.map(|port| port.to_string()).unwrap_or_else(|| "-".to_string());
+    format!("machine_addresses: {} {} {} ssh_port={} wireguard_port={}",
+        address.hostname, address.network, address.address, optional(address.ssh_port), optional(address.wireguard_port))
+}
+
+fn keepalive_row(source: &str, target: &str, interval_sec: i32) -> String {
+    format!("wireguard_keepalives: {source} -> {target} interval_sec={interval_sec}")
+}
+
+/// Whether `address` is routed on the internet, as opposed to an address that
+/// can be reused on any number of private networks
+fn is_public(address: IpAddr) -> bool {
+    match address {
+        IpAddr::V4(address) => {
+            let octets = address.octets();
+            let shared = octets[0] == 100 && (64..128).contains(&octets[1]);
+            !(address.is_private() || address.is_loopback() || address.is_link_local() ||
+              address.is_unspecified() || address.is_broadcast() || shared)
+        }
+        IpAddr::V6(address) => {
+            let first = address.segments()[0];
+            let unique_local = first & 0xfe00 == 0xfc00;
+            let link_local = first & 0xffc0 == 0xfe80;
+            !(address.is_loopback() || address.is_unspecified() || unique_local || link_local)
+        }
+    }
+}
+
+/// Check the inventory.  `get_pubkey` gets the WireGuard public key for a
+/// private key; if it fails, keys are not checked.
+pub(crate) fn check(
+    machines_map: &MachinesMap,
+    network_links_priority_map: &NetworkLinksPriorityMap,
+    keepalives_map: &WireguardKeepaliveIntervalMap,
+    networks: &[String],
+    get_pubkey: &dyn Fn(&str) -> Result<String>,
+) -> Result<Vec<Problem>> {
+    let mut problems = vec![];
+    let machines = get_sorted_machines(machines_map);
+    let addresses = machines.iter().flat_map(|machine| &machine.addresses).collect::<Vec<_>>();
+    let link = |network: &str, other_network: &str| network_links_priority_map.contains_key(&(network.to_string(), other_network.to_string()));
+
+    // Networks.  Machines without addresses are on NONE even if it is not in networks.
+    let on_none = networks.iter().any(|network| network == "NONE") ||
+        machines.iter().any(|machine| machine.addresses.is_empty() && machine.state != MachineState::Retired);
+    if on_none && networks.iter().any(|network| network == "internet") && !link("NONE", "internet") {
+        problems.push(error(
+            "network NONE has no link to internet, so machines without addresses cannot reach anything".to_string(),
+            vec!["network_links: (NONE, internet) is missing".to_string()],
+        ));
+    }
+    for network in networks.iter().filter(|network| *network != "NONE") {
+        if addresses.iter().any(|address| &address.network == network) && !link(network, network) {
+            problems.push(warning(
+                format!("network {network} has no link to itself, so its machines reach each other only through other networks"),
+                vec![format!("network_links: ({network}, {network}) is missing")],
+            ));
+        }
+    }
+
+    // WireGuard interfaces
+    for machine in &machines {
+        if machine.wireguard_pubkey.is_none() && machine.state != MachineState::Retired {
+            problems.push(warning(format!("machine {} has no WireGuard interface, so it is nobody's peer", machine.hostname), vec![machine_row(machine)]));
+        }
+    }
+    let wireguard_addresses = machines.iter()
+        .flat_map(|machine| {
+            let ipv4 = machine.wireguard_ipv4_address.map(IpAddr::V4);
+            let ipv6 = machine.wireguard_ipv6_address.map(IpAddr::V6);
+            ipv4.into_iter().chain(ipv6).map(move |address| (address, *machine))
+        })
+        .into_group_map();
+    for (address, users) in wireguard_addresses.iter().sorted_by_key(|(address, _)| **address) {
+        if users.len() > 1 {
+            problems.push(error(
+                format!("WireGuard address {address} is used by {} machines", users.len()),
+                users.iter().map(|machine| wireguard_row(machine)).collect(),
+            ));
+        }
+    }
+    for machine in &machines {
+        if let (Some(privkey), Some(pubkey)) = (&machine.wireguard_privkey, &machine.wireguard_pubkey) {
+            match get_pubkey(privkey) {
+                Ok(expected) if expected == *pubkey => {}
+                Ok(_) => problems.push(error(
+                    format!("WireGuard public key of {} does not match its private key", machine.hostname),
+                    vec![wireguard_row(machine)],
+                )),
+                Err(err) => {
+                    problems.push(warning(format!("could not check WireGuard keys: {err:#}"), vec![]));
+                    break;
+                }
+            }
+        }
+    }
+
+    // Public addresses
+    let mut by_address = BTreeMap::new();
+    for address in &addresses {
+        by_address.entry(address.address).or_insert_with(Vec::new).push(*address);
+    }
+    for (address, rows) in &by_address {
+        let address_networks = rows.iter().map(|row| &row.network).unique().collect::<Vec<_>>();
+        if is_public(*address) && address_networks.len() > 1 {
+            problems.push(error(
+                format!("public address {address} is on more than one network: {}", address_networks.iter().join(", ")),
+                rows.iter().map(|row| address_row(row)).collect(),
+            ));
+        }
+        // Machines behind the same NAT, see the comment on machine_addresses in the schema
+        let by_port = rows.iter()
+            .map(|row| &machines_map[&row.hostname])
+            .filter_map(|machine| Some((machine.wireguard_port?, machine)))
+            .into_group_map();
+        for (port, users) in by_port.iter().sorted_by_key(|(port, _)| **port) {
+            let hostnames = users.iter().map(|machine| &machine.hostname).unique().collect::<Vec<_>>();
+            if hostnames.len() > 1 {
+                problems.push(error(
+                    format!("machines behind {address} all listen on WireGuard port {port}; each needs its own port: {}", hostnames.iter().join(", ")),
+                    users.iter().map(|machine| wireguard_row(machine)).collect(),
+                ));
+            }
+        }
+    }
+    for address in &addresses {
+        let listen_port = machines_map[&address.hostname].wireguard_port;
+        if let (Some(port), Some(listen_port)) = (address.wireguard_port, listen_port) {
+            if port != listen_port {
+                problems.push(warning(
+                    format!("{} forwards WireGuard port {port} on {} to port {listen_port}; peers learn the port the machine sends from, {listen_port}",
+                        address.hostname, address.address),
+                    vec![address_row(address), wireguard_row(&machines_map[&address.hostname])],
+                ));
+            }
+        }
+    }
+
+    // Peers
+    let peers = machines.iter()
+        .filter(|machine| machine.state != MachineState::Retired && machine.wireguard_pubkey.is_some())
+        .map(|machine| {
+            let peers = get_wireguard_peers(machines_map, network_links_priority_map, keepalives_map, &machine.hostname)?;
+            Ok((machine.hostname.as_str(), peers.into_iter().map(|peer| (peer.hostname.clone(), peer)).collect::<HashMap<_, _>>()))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let has_endpoint = |from: &str, to: &str| peers.iter()
+        .find(|(hostname, _)| *hostname == from)
+        .and_then(|(_, peers)| peers.get(to))
+        .map(|peer| peer.endpoint.is_some());
+    for ((first, _), (second, _)) in peers.iter().tuple_combinations() {
+        if has_endpoint(first, second) == Some(false) && has_endpoint(second, first) == Some(false) {
+            problems.push(error(
+                format!("peers {first} and {second} have no endpoint for each other, so neither can start a connection"),
+                vec![machine_row(&machines_map[*first]), machine_row(&machines_map[*second])],
+            ));
+        }
+    }
+    for ((source, target), interval_sec) in keepalives_map.iter().sorted() {
+        match has_endpoint(source, target) {
+            Some(true) => {}
+            Some(false) => problems.push(warning(
+                format!("keepalive from {source} to {target}, but {source} has no endpoint for {target}"),
+                vec![keepalive_row(source, target, *interval_sec)],
+            )),
+            None => problems.push(warning(
+                format!("keepalive from {source} to {target}, which are not WireGuard peers"),
+                vec![keepalive_row(source, target, *interval_sec)],
+            )),
+        }
+    }
+    Ok(problems)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::bail;
+    use crate::test_fixtures::model_machine;
+
+    fn address(hostname: &str, network: &str, address: &str, wireguard_port: i32) -> MachineAddress {
+        MachineAddress {
+            hostname: hostname.to_string(),
+            network: network.to_string(),
+            address: address.parse().unwrap(),
+            ssh_port: None,
+            wireguard_port: Some(wireguard_port),
+        }
+    }
+
+    fn get_pubkey(privkey: &str) -> Result<String> {
+        Ok(privkey.replace("privkey", "pubkey"))
+    }
+
+    fn messages(problems: &[Problem]) -> Vec<String> {
+        problems.iter().map(|problem| format!("{}: {}", problem.severity, problem.message)).collect()
+    }
+
+    fn networks() -> Vec<String> {
+        vec!["NONE".to_string(), "home".to_string(), "internet".to_string()]
+    }
+
+    /// web1 is on the internet and laptop is not on any network
+    #[test]
+    fn test_no_problems() {
+        let machines = vec![
+            model_machine("web1", "10.10.0.1", "fd00::1", vec![address("web1", "internet", "203.0.113.5", 904)]),
+            model_machine("laptop", "10.10.0.2", "fd00::2", vec![]),
+        ].into_iter().map(|m| (m.hostname.clone(), m)).collect();
+        let mut links = HashMap::new();
+        links.insert(("NONE".to_string(), "internet".to_string()), 0);
+        links.insert(("internet".to_string(), "internet".to_string()), 0);
+        let mut keepalives = HashMap::new();
+        keepalives.insert(("laptop".to_string(), "web1".to_string()), 25);
+        assert!(messages(&check(&machines, &links, &keepalives, &networks(), &get_pubkey).unwrap()).is_empty());
+    }
+
+    #[test]
+    fn test_problems() {
+        let mut laptop = model_machine("laptop", "10.10.0.1", "fd00::1", vec![]);
+        laptop.wireguard_pubkey = Some("other-pubkey".to_string());
+        let machines = vec![
+            model_machine("web1", "10.10.0.1", "fd00::1", vec![address("web1", "internet", "203.0.113.5", 904)]),
+            model_machine("nas", "10.10.0.3", "fd00::3", vec![address("nas", "home", "203.0.113.5", 905)]),
+            laptop,
+        ].into_iter().map(|m| (m.hostname.clone(), m)).collect();
+        let mut keepalives = HashMap::new();
+        keepalives.insert(("laptop".to_string(), "web1".to_string()), 25);
+        let problems = check(&machines, &HashMap::new(), &keepalives, &networks(), &get_pubkey).unwrap();
+        assert_eq!(messages(&problems), vec![
+            "error: network NONE has no link to internet, so machines without addresses cannot reach anything",
+            "warning: network home has no link to itself, so its machines reach each other only through other networks",
+            "warning: network internet has no link to itself, so its machines reach each other only through other networks",
+            "error: WireGuard address 10.10.0.1 is used by 2 machines",
+            "error: WireGuard address fd00::1 is used by 2 machines",
+            "error: WireGuard public key of laptop does not match its private key",
+            "error: public address 203.0.113.5 is on more than one network: home, internet",
+            "error: machines behind 203.0.113.5 all listen on WireGuard port 904; each needs its own port: nas, web1",
+            "warning: nas forwards WireGuard port 905 on 203.0.113.5 to port 904; peers learn the port the machine sends from, 904",
+            "error: peers laptop and nas have no endpoint for each other, so neither can start a connection",
+            "error: peers laptop and web1 have no endpoint for each other, so neither can start a connection",
+            "error: peers nas and web1 have no endpoint for each other, so neither can start a connection",
+            "warning: keepalive from laptop to web1, but laptop has no endpoint for web1",
+        ]);
+        assert_eq!(problems[3].rows, vec![
+            "wireguard_interfaces: laptop 10.10.0.1 fd00::1 port=904",
+            "wireguard_interfaces: web1 10.10.0.1 fd00::1 port=904",
+        ]);
+    }
+
+    /// Keys are not checked without `wg`
+    #[test]
+    fn test_no_wg() {
+        let machines = vec![
+            model_machine("web1", "10.10.0.1", "fd00::1", vec![]),
+            model_machine("web2", "10.10.0.2", "fd00::2", vec![]),
+        ].into_iter().map(|m| (m.hostname.clone(), m)).collect();
+        let mut links = HashMap::new();
+        links.insert(("NONE".to_string(), "internet".to_string()), 0);
+        let problems = check(&machines, &links, &HashMap::new(), &networks(), &|_| bail!("wg not found")).unwrap();
+        assert_eq!(messages(&problems), vec![
+            "warning: could not check WireGuard keys: wg not found",
+            "error: peers web1 and web2 have no endpoint for each other, so neither can start a connection",
+        ]);
+    }
+
+    #[test]
+    fn test_is_public() {
+        assert!(is_public("203.0.113.5".parse().unwrap()));
+        assert!(is_public("2001:db8::1".parse().unwrap()));
+        assert!(!is_public("192.168.1.10".parse().unwrap()));
+        assert!(!is_public("100.64.0.1".parse().unwrap()));
+        assert!(!is_public("fd00::1".parse().unwrap()));
+    }
+}
@@ -0,0 +1,171 @@
+//! `--dry-run`: run a command that changes the inventory in its transaction
+//! as usual, then print what it changed instead of committing.
+//!
+//! `DryRun` wraps the `Store` of the command.  It reads the inventory and the
+//! generated files when it is created, and again when the command commits,
+//! then prints the difference and drops the transaction, which rolls it back.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::net::IpAddr;
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use infrabase::model::{MachineState, MachinesMap, NetworkLinksPriorityMap, WireguardKeepaliveIntervalMap};
+
+use crate::apply::{self, Change};
+use crate::inventory;
+use crate::ipam::{Cidr, IpPool};
+use crate::store::{RemovedMachine, Store};
+
+/// Everything a dry run compares
+pub(crate) struct State {
+    pub inventory: inventory::Inventory,
+    /// Description of each generated file, e.g. its path -> contents
+    pub files: BTreeMap<String, String>,
+}
+
+/// Read the `State` of a store
+pub(crate) type ReadState<'a> = Box<dyn Fn(&mut dyn Store) -> Result<State> + 'a>;
+
+pub(crate) struct DryRun<'a> {
+    store: Box<dyn Store + 'a>,
+    read_state: ReadState<'a>,
+    before: State,
+}
+
+impl<'a> DryRun<'a> {
+    pub fn new(mut store: Box<dyn Store + 'a>, read_state: ReadState<'a>) -> Result<DryRun<'a>> {
+        let before = read_state(&mut *store)?;
+        Ok(DryRun { store, read_state, before })
+    }
+}
+
+/// Print the changes between `before` and `after`
+fn print_changes(before: &State, after: &State) {
+    let mut changes = apply::plan(&before.inventory, &after.inventory)
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>();
+    let pools = |state: &State| state.inventory.ip_pools.iter().map(|pool| pool.name.clone()).collect::<BTreeSet<_>>();
+    let (pools_before, pools_after) = (pools(before), pools(after));
+    changes.extend(pools_before.difference(&pools_after).map(|name| format!("- pool {name}")));
+    changes.extend(pools_after.difference(&pools_before).map(|name| format!("+ pool {name}")));
+    let reservations = |state: &State| state.inventory.ip_reservations.iter().map(|reservation| reservation.cidr.to_string()).collect::<BTreeSet<_>>();
+    let (reservations_before, reservations_after) = (reservations(before), reservations(after));
+    changes.extend(reservations_before.difference(&reservations_after).map(|cidr| format!("- reservation {cidr}")));
+    changes.extend(reservations_after.difference(&reservations_before).map(|cidr| format!("+ reservation {cidr}")));
+
+    if changes.is_empty() {
+        println!("Dry run: no changes");
+        return;
+    }
+    println!("Dry run, not committing:");
+    for change in changes {
+        println!("{change}");
+    }
+
+    let files = before.files.keys().chain(after.files.keys())
+        .filter(|name| before.files.get(*name) != after.files.get(*name))
+        .collect::<BTreeSet<_>>();
+    if files.is_empty() {
+        println!("\nNo generated files would change");
+    } else {
+        println!("\nGenerated files that would change:");
+        for name in files {
+            let what = match (before.files.contains_key(name), after.files.contains_key(name)) {
+                (false, _) => "new",
+                (_, false) => "removed",
+                _ => "changed",
+            };
+            println!("  {name} ({what})");
+        }
+    }
+}
+
+impl Store for DryRun<'_> {
+    fn get_owners(&mut self) -> Result<Vec<String>> {
+        self.store.get_owners()
+    }
+
+    fn get_networks(&mut self) -> Result<Vec<String>> {
+        self.store.get_networks()
+    }
+
+    fn get_providers(&mut self) -> Result<Vec<inventory::Provider>> {
+        self.store.get_providers()
+    }
+
+    fn get_network_links_priority_map(&mut self) -> Result<NetworkLinksPriorityMap> {
+        self.store.get_network_links_priority_map()
+    }
+
+    fn get_wireguard_keepalive_map(&mut self) -> Result<WireguardKeepaliveIntervalMap> {
+        self.store.get_wireguard_keepalive_map()
+    }
+
+    fn get_machines_with_addresses(&mut self) -> Result<MachinesMap> {
+        self.store.get_machines_with_addresses()
+    }
+
+    fn get_ip_pools(&mut self) -> Result<Vec<IpPool>> {
+        self.store.get_ip_pools()
+    }
+
+    fn get_ip_reservations(&mut self) -> Result<Vec<inventory::IpReservation>> {
+        self.store.get_ip_reservations()
+    }
+
+    fn get_quarantined_wireguard_addresses(&mut self, quarantine_days: i32) -> Result<HashMap<IpAddr, DateTime<Utc>>> {
+        self.store.get_quarantined_wireguard_addresses(quarantine_days)
+    }
+
+    fn execute_change(&mut self, change: &Change) -> Result<()> {
+        self.store.execute_change(change)
+    }
+
+    fn remove_machine(&mut self, hostname: &str) -> Result<()> {
+        self.store.remove_machine(hostname)
+    }
+
+    fn set_machine_state(&mut self, hostname: &str, state: MachineState) -> Result<()> {
+        self.store.set_machine_state(hostname, state)
+    }
+
+    fn get_removed_machine(&mut self, hostname: &str, as_of: Option<DateTime<Utc>>) -> Result<Option<RemovedMachine>> {
+        self.store.get_removed_machine(hostname, as_of)
+    }
+
+    fn remove_address(&mut self, hostname: &str, network: &str, address: &IpAddr) -> Result<u64> {
+        self.store.remove_address(hostname, network, address)
+    }
+
+    fn remove_wireguard_keepalive(&mut self, source: &str, target: &str) -> Result<u64> {
+        self.store.remove_wireguard_keepalive(source, target)
+    }
+
+    fn add_ip_pool(&mut self, pool: &IpPool) -> Result<()> {
+        self.store.add_ip_pool(pool)
+    }
+
+    fn remove_ip_pool(&mut self, name: &str) -> Result<u64> {
+        self.store.remove_ip_pool(name)
+    }
+
+    fn reserve_ip_range(&mut self, cidr: &Cidr, reason: Option<&str>) -> Result<()> {
+        self.store.reserve_ip_range(cidr, reason)
+    }
+
+    fn release_ip_range(&mut self, cidr: &Cidr) -> Result<u64> {
+        self.store.release_ip_range(cidr)
+    }
+
+    fn lock_wireguard_addresses(&mut self) -> Result<()> {
+        self.store.lock_wireguard_addresses()
+    }
+
+    /// Print what the transaction changed, then roll it back
+    fn commit(mut self: Box<Self>) -> Result<()> {
+        let after = (self.read_state)(&mut *self.store)?;
+        print_changes(&self.before, &after);
+        Ok(())
+    }
+}
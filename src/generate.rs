@@ -0,0 +1,379 @@
+//! ssh_config, wg-quick, and Nix output for machines in memory.
+
+use std::fmt::Write;
+use std::net::{IpAddr, SocketAddr};
+use anyhow::{anyhow, Result};
+
+use crate::model::{get_sorted_machines, Machine, MachineAddress, MachineState, MachinesMap, NetworkLinksPriorityMap, WireguardKeepaliveIntervalMap};
+use crate::nix::{Nix, ToNix};
+use crate::routing::{get_network_to_network, get_wireguard_peers, sort_wireguard_peers, WireguardPeer};
+
+/// An ~/.ssh/config that lists all machines, reached from `for_machine`
+pub fn ssh_config(machines_map: &MachinesMap, network_links_priority_map: &NetworkLinksPriorityMap, for_machine: &str) -> Result<String> {
+    let source_machine =
+        &machines_map.get(for_machine)
+        .ok_or_else(|| anyhow!("machines_map missing {}", for_machine))?;
+    let machines = get_sorted_machines(machines_map);
+
+    let mut out = String::new();
+    writeln!(out, "# infrabase-generated SSH config for {for_machine}\n")?;
+
+    for machine in machines.into_iter().filter(|machine| machine.state != MachineState::Retired) {
+        let network_to_network = get_network_to_network(network_links_priority_map, &source_machine.networks, &machine.addresses);
+        let (address, ssh_port) = match network_to_network.first() {
+            None => {
+                // We prefer to SSH over the non-WireGuard IP because WireGuard may be down,
+                // but in cases where there is no reachable address, use the WireGuard IP instead.
+                (machine.wireguard_ipv4_address.map(IpAddr::V4), machine.ssh_port)
+            },
+            Some((_, dest_network)) => {
+                let desired_address = machine.addresses.iter().find(|a| a.network == *dest_network).unwrap();
+                (Some(desired_address.address), desired_address.ssh_port)
+            }
+        };
+
+        if let (Some(address), Some(port)) = (address, ssh_port) {
+            let owner = &machine.owner;
+            let hostname = &machine.hostname;
+            let t = "  ";
+            writeln!(out, "\
+                # owner: {owner}\n\
+                Host {hostname}\n\
+                {t}HostName {address}\n\
+                {t}Port {port}\n\
+            ")?;
+        }
+    }
+    Ok(out)
+}
+
+/// A wg-quick config for `for_machine` with all of its peers
+pub fn wg_quick(
+    machines_map: &MachinesMap,
+    network_links_priority_map: &NetworkLinksPriorityMap,
+    keepalives_map: &WireguardKeepaliveIntervalMap,
+    for_machine: &str,
+) -> Result<String> {
+    let my_machine = machines_map.get(for_machine)
+        .ok_or_else(|| anyhow!("Could not find machine {:?} in database", for_machine))?;
+    let my_ipv4_address = my_machine.wireguard_ipv4_address
+        .ok_or_else(|| anyhow!("Machine {:?} does not have WireGuard IPv4 address", for_machine))?;
+    let my_ipv6_address = my_machine.wireguard_ipv6_address
+        .ok_or_else(|| anyhow!("Machine {:?} does not have WireGuard IPv6 address", for_machine))?;
+    let privkey = my_machine.wireguard_privkey.as_ref()
+        .ok_or_else(|| anyhow!("Machine {:?} does not have a WireGuard private key", for_machine))?;
+    let listen_port = my_machine.wireguard_port
+        .ok_or_else(|| anyhow!("Machine {:?} does not have a WireGuard port", for_machine))?;
+
+    let mut out = String::new();
+    writeln!(out, "\
+        # infrabase-generated wg-quick config for {for_machine}\n\
+        \n\
+        [Interface]\n\
+        Address = {my_ipv4_address}/32, {my_ipv6_address}/128\n\
+        PrivateKey = {privkey}\n\
+        ListenPort = {listen_port}\n\
+    ")?;
+
+    let mut peers = get_wireguard_peers(machines_map, network_links_priority_map, keepalives_map, for_machine)?;
+    sort_wireguard_peers(&mut peers);
+    for peer in peers {
+        let maybe_endpoint = match peer.endpoint {
+            Some((address, port)) => format!("Endpoint = {address}:{port}\n"),
+            None => "".to_string(),
+        };
+        let maybe_keepalive = match peer.keepalive {
+            Some(interval) => format!("PersistentKeepalive = {interval}\n"),
+            None => "".to_string()
+        };
+        let peer_hostname = &peer.hostname;
+        let peer_pubkey = &peer.wireguard_pubkey;
+        let peer_ipv4_address = &peer.wireguard_ipv4_address;
+        let peer_ipv6_address = &peer.wireguard_ipv6_address;
+        writeln!(out, "\
+            # {peer_hostname}\n\
+            [Peer]\n\
+            PublicKey = {peer_pubkey}\n\
+            AllowedIPs = {peer_ipv4_address}/32, {peer_ipv6_address}/128\n\
+            {maybe_endpoint}\
+            {maybe_keepalive}\
+        ")?;
+    }
+    Ok(out)
+}
+
+fn address_to_nix(address: &MachineAddress) -> Nix {
+    Nix::attrs(vec![
+        ("ip", address.address.to_nix()),
+        ("ssh_port", address.ssh_port.to_nix()),
+        ("wireguard_port", address.wireguard_port.to_nix()),
+    ])
+}
+
+fn machine_to_nix(machine: &Machine, keepalives_map: &WireguardKeepaliveIntervalMap) -> Nix {
+    let mut keepalives = keepalives_map.iter()
+        .filter(|((source, _), _)| *source == machine.hostname)
+        .map(|((_, target), interval)| (target.as_str(), interval.to_nix()))
+        .collect::<Vec<_>>();
+    keepalives.sort_unstable_by_key(|(target, _)| *target);
+    let mut addresses = machine.addresses.iter().collect::<Vec<_>>();
+    addresses.sort_unstable_by(|a1, a2| (&a1.network, a1.address).cmp(&(&a2.network, a2.address)));
+    Nix::attrs(vec![
+        ("owner", machine.owner.to_nix()),
+        ("added_time", machine.added_time.to_nix()),
+        ("wireguard_ipv4_address", machine.wireguard_ipv4_address.to_nix()),
+        ("wireguard_ipv6_address", machine.wireguard_ipv6_address.to_nix()),
+        ("wireguard_port", machine.wireguard_port.to_nix()),
+        ("wireguard_pubkey", machine.wireguard_pubkey.to_nix()),
+        ("ssh_port", machine.ssh_port.to_nix()),
+        ("ssh_user", machine.ssh_user.to_nix()),
+        ("provider_id", machine.provider_id.to_nix()),
+        ("provider_name", machine.provider_name.to_nix()),
+        ("provider_email", machine.provider_email.to_nix()),
+        ("provider_reference", machine.provider_reference.to_nix()),
+        ("addresses", Nix::attrs(addresses.into_iter().map(|a| (a.network.as_str(), address_to_nix(a))))),
+        ("keepalives", Nix::attrs(keepalives)),
+    ])
+}
+
+/// Machine and address data for use in Nix configuration, keyed by hostname
+pub fn nix_data(machines_map: &MachinesMap, keepalives_map: &WireguardKeepaliveIntervalMap) -> Nix {
+    let machines = get_sorted_machines(machines_map);
+    Nix::attrs(machines.into_iter().map(|machine| {
+        (machine.hostname.as_str(), machine_to_nix(machine, keepalives_map))
+    }))
+}
+
+/// A peer in networking.wireguard.interfaces.<name>.peers.  `with_names` adds
+/// `name`, which upstream nixpkgs does not support.
+pub fn peer_to_nix(peer: &WireguardPeer, with_names: bool) -> Nix {
+    let mut attrs = vec![];
+    if with_names {
+        attrs.push(("name", peer.hostname.to_nix()));
+    }
+    attrs.push(("allowedIPs", vec![
+        format!("{}/32", peer.wireguard_ipv4_address),
+        format!("{}/128", peer.wireguard_ipv6_address),
+    ].to_nix()));
+    attrs.push(("publicKey", peer.wireguard_pubkey.to_nix()));
+    if let Some((address, port)) = peer.endpoint {
+        attrs.push(("endpoint", SocketAddr::new(address, port).to_string().to_nix()));
+    }
+    if let Some(interval) = peer.keepalive {
+        attrs.push(("persistentKeepalive", interval.to_nix()));
+    }
+    Nix::attrs(attrs)
+}
+
+/// Options for writing a complete NixOS module instead of a bare list of peers
+#[derive(Debug, Clone)]
+pub struct WireguardModuleOptions {
+    /// Name of the interface in networking.wireguard.interfaces
+    pub interface: String,
+    /// Path to the machine's private key on the machine itself
+    pub private_key_file: String,
+}
+
+/// A NixOS module that configures the machine's WireGuard interface with
+/// `peers` and opens its WireGuard port in the firewall
+pub fn wireguard_module(machine: &Machine, peers: Nix, options: &WireguardModuleOptions) -> Result<Nix> {
+    let ipv4_address = machine.wireguard_ipv4_address
+        .ok_or_else(|| anyhow!("Machine {:?} does not have WireGuard IPv4 address", machine.hostname))?;
+    let ipv6_address = machine.wireguard_ipv6_address
+        .ok_or_else(|| anyhow!("Machine {:?} does not have WireGuard IPv6 address", machine.hostname))?;
+    let interface = Nix::attrs(vec![
+        ("ips", vec![
+            format!("{}/32", ipv4_address),
+            format!("{}/128", ipv6_address),
+        ].to_nix()),
+        ("listenPort", machine.wireguard_port.to_nix()),
+        ("privateKeyFile", options.private_key_file.to_nix()),
+        ("peers", peers),
+    ]);
+    Ok(Nix::attrs(vec![
+        ("networking", Nix::attrs(vec![
+            ("wireguard", Nix::attrs(vec![
+                ("interfaces", Nix::attrs(vec![(options.interface.as_str(), interface)])),
+            ])),
+            ("firewall", Nix::attrs(vec![
+                ("allowedUDPPorts", vec![machine.wireguard_port].to_nix()),
+            ])),
+        ])),
+    ]))
+}
+
+/// The WireGuard peers of `for_machine` as a Nix list, one peer per line, or
+/// with `module`, a NixOS module that sets up its WireGuard interface
+pub fn wireguard_peers_nix(
+    machines_map: &MachinesMap,
+    network_links_priority_map: &NetworkLinksPriorityMap,
+    keepalives_map: &WireguardKeepaliveIntervalMap,
+    for_machine: &str,
+    with_names: bool,
+    module: Option<&WireguardModuleOptions>,
+) -> Result<String> {
+    let mut peers = get_wireguard_peers(machines_map, network_links_priority_map, keepalives_map, for_machine)?;
+    sort_wireguard_peers(&mut peers);
+    let peers = peers.iter().map(|peer| peer_to_nix(peer, with_names)).collect::<Vec<_>>();
+    let mut out = String::new();
+    match module {
+        Some(options) => {
+            let machine = &machines_map[for_machine];
+            let module = wireguard_module(machine, Nix::List(peers), options)?;
+            writeln!(out, "{}", module.to_pretty())?;
+        },
+        None => {
+            out.push_str("[\n");
+            for peer in peers {
+                writeln!(out, "  {}", peer)?;
+            }
+            out.push_str("]\n");
+        },
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn machine(hostname: &str, ipv4: &str, ipv6: &str, pubkey: &str, addresses: Vec<MachineAddress>) -> Machine {
+        let networks = if addresses.is_empty() {
+            vec!["NONE".to_string()]
+        } else {
+            addresses.iter().map(|a| a.network.clone()).collect()
+        };
+        Machine {
+            hostname: hostname.to_string(),
+            state: MachineState::Active,
+            wireguard_ipv4_address: Some(ipv4.parse().unwrap()),
+            wireguard_ipv6_address: Some(ipv6.parse().unwrap()),
+            wireguard_port: Some(904),
+            wireguard_privkey: Some(format!("{hostname}-privkey")),
+            wireguard_pubkey: Some(pubkey.to_string()),
+            ssh_port: Some(22),
+            ssh_user: Some("root".to_string()),
+            added_time: "2020-06-01T00:00:00Z".parse().unwrap(),
+            owner: "ivan".to_string(),
+            provider_id: None,
+            provider_name: None,
+            provider_email: None,
+            provider_reference: None,
+            networks,
+            addresses,
+        }
+    }
+
+    fn address(hostname: &str, network: &str, address: &str) -> MachineAddress {
+        MachineAddress {
+            hostname: hostname.to_string(),
+            network: network.to_string(),
+            address: address.parse().unwrap(),
+            ssh_port: Some(2222),
+            wireguard_port: Some(51820),
+        }
+    }
+
+    /// web1 is on the internet, laptop is not on any network
+    fn inventory() -> (MachinesMap, NetworkLinksPriorityMap, WireguardKeepaliveIntervalMap) {
+        let machines = vec![
+            machine("web1", "10.10.0.1", "fd00::1", "web1-pubkey", vec![address("web1", "internet", "203.0.113.5")]),
+            machine("laptop", "10.10.0.2", "fd00::2", "laptop-pubkey", vec![]),
+        ];
+        let machines_map = machines.into_iter().map(|m| (m.hostname.clone(), m)).collect();
+        let mut links = HashMap::new();
+        links.insert(("NONE".to_string(), "internet".to_string()), 0);
+        links.insert(("internet".to_string(), "internet".to_string()), 0);
+        let mut keepalives = HashMap::new();
+        keepalives.insert(("laptop".to_string(), "web1".to_string()), 25);
+        (machines_map, links, keepalives)
+    }
+
+    #[test]
+    fn test_ssh_config() {
+        let (machines, links, _) = inventory();
+        assert_eq!(ssh_config(&machines, &links, "laptop").unwrap(), concat!(
+            "# infrabase-generated SSH config for laptop\n\n",
+            "# owner: ivan\nHost laptop\n  HostName 10.10.0.2\n  Port 22\n\n",
+            "# owner: ivan\nHost web1\n  HostName 203.0.113.5\n  Port 2222\n\n",
+        ));
+        assert!(ssh_config(&machines, &links, "web2").is_err());
+    }
+
+    /// Retired machines are left out of other machines' configs
+    #[test]
+    fn test_retired_machines() {
+        let (mut machines, links, keepalives) = inventory();
+        machines.get_mut("web1").unwrap().state = MachineState::Retired;
+        assert_eq!(ssh_config(&machines, &links, "laptop").unwrap(), concat!(
+            "# infrabase-generated SSH config for laptop\n\n",
+            "# owner: ivan\nHost laptop\n  HostName 10.10.0.2\n  Port 22\n\n",
+        ));
+        assert_eq!(wireguard_peers_nix(&machines, &links, &keepalives, "laptop", true, None).unwrap(), "[\n]\n");
+    }
+
+    #[test]
+    fn test_wg_quick() {
+        let (machines, links, keepalives) = inventory();
+        assert_eq!(wg_quick(&machines, &links, &keepalives, "laptop").unwrap(), concat!(
+            "# infrabase-generated wg-quick config for laptop\n\n",
+            "[Interface]\n",
+            "Address = 10.10.0.2/32, fd00::2/128\n",
+            "PrivateKey = laptop-privkey\n",
+            "ListenPort = 904\n\n",
+            "# web1\n",
+            "[Peer]\n",
+            "PublicKey = web1-pubkey\n",
+            "AllowedIPs = 10.10.0.1/32, fd00::1/128\n",
+            "Endpoint = 203.0.113.5:51820\n",
+            "PersistentKeepalive = 25\n\n",
+        ));
+        // web1 cannot reach the laptop, so there is no endpoint
+        assert!(wg_quick(&machines, &links, &keepalives, "web1").unwrap().ends_with(concat!(
+            "# laptop\n",
+            "[Peer]\n",
+            "PublicKey = laptop-pubkey\n",
+            "AllowedIPs = 10.10.0.2/32, fd00::2/128\n\n",
+        )));
+    }
+
+    #[test]
+    fn test_wireguard_peers_nix() {
+        let (machines, links, keepalives) = inventory();
+        assert_eq!(wireguard_peers_nix(&machines, &links, &keepalives, "laptop", true, None).unwrap(), concat!(
+            "[\n",
+            "  { name = \"web1\"; allowedIPs = [ \"10.10.0.1/32\" \"fd00::1/128\" ]; publicKey = \"web1-pubkey\"; ",
+            "endpoint = \"203.0.113.5:51820\"; persistentKeepalive = 25; }\n",
+            "]\n",
+        ));
+    }
+
+    #[test]
+    fn test_wireguard_module() {
+        let mut machine = machine("web1", "10.10.0.1", "fd00::1", "web1-pubkey", vec![]);
+        let options = WireguardModuleOptions { interface: "wg-mesh".to_string(), private_key_file: "/run/keys/wg".to_string() };
+        let module = wireguard_module(&machine, Nix::List(vec![]), &options).unwrap();
+        assert_eq!(module.to_pretty(), concat!(
+            "{\n",
+            "  networking = {\n",
+            "    wireguard = {\n",
+            "      interfaces = {\n",
+            "        wg-mesh = {\n",
+            "          ips = [ \"10.10.0.1/32\" \"fd00::1/128\" ];\n",
+            "          listenPort = 904;\n",
+            "          privateKeyFile = \"/run/keys/wg\";\n",
+            "          peers = [ ];\n",
+            "        };\n",
+            "      };\n",
+            "    };\n",
+            "    firewall = {\n",
+            "      allowedUDPPorts = [ 904 ];\n",
+            "    };\n",
+            "  };\n",
+            "}",
+        ));
+        machine.wireguard_ipv4_address = None;
+        assert!(wireguard_module(&machine, Nix::List(vec![]), &options).is_err());
+    }
+}
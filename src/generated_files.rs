@@ -0,0 +1,143 @@
+//! Writing generated files, like the ones from `write-wg-peers`, so that a
+//! reader such as `nixos-rebuild` never sees a partly written file, and files
+//! that did not change keep their modification time, and keeping track of
+//! them in a manifest so that files no longer generated can be pruned.
+
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use anyhow::{anyhow, ensure, Context, Result};
+use serde::{Deserialize, Serialize};
+
+/// What `write` did
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Written {
+    Created,
+    Updated,
+    Unchanged,
+}
+
+/// Write `contents` to `path`, unless it already has exactly those contents.
+/// The contents are written to a temporary file next to `path`, which is then
+/// renamed over it.  An existing file keeps its permissions.
+pub(crate) fn write(path: &Path, contents: &str) -> Result<Written> {
+    let (written, permissions) = match fs::read(path) {
+        Ok(existing) if existing == contents.as_bytes() => return Ok(Written::Unchanged),
+        Ok(_) => (Written::Updated, Some(fs::metadata(path)?.permissions())),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => (Written::Created, None),
+        Err(err) => return Err(err).with_context(|| format!("Could not read {:?}", path)),
+    };
+    let file_name = path.file_name().ok_or_else(|| anyhow!("{:?} is not a path to a file", path))?;
+    let temp_path = path.with_file_name(format!(".{}.{}.tmp", file_name.to_string_lossy(), process::id()));
+    let result = (|| -> Result<()> {
+        let mut file = File::create(&temp_path)?;
+        file.write_all(contents.as_bytes())?;
+        if let Some(permissions) = permissions {
+            file.set_permissions(permissions)?;
+        }
+        file.sync_all()?;
+        fs::rename(&temp_path, path)?;
+        Ok(())
+    })();
+    if result.is_err() {
+        // Ignore errors here, so that the original error is reported
+        let _ = fs::remove_file(&temp_path);
+    }
+    result.with_context(|| format!("Could not write {:?}", path))?;
+    Ok(written)
+}
+
+/// Name of the manifest file kept in the directory of the generated files
+const MANIFEST_FILE_NAME: &str = ".infrabase-generated.json";
+
+const MANIFEST_VERSION: u32 = 1;
+
+/// The files written from a path template, and the machine each was written for
+#[derive(Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Manifest {
+    version: u32,
+    /// Hostname by path
+    pub files: BTreeMap<String, String>,
+}
+
+impl Manifest {
+    /// Get the path of the manifest for files written from `template`, which is
+    /// in the last directory of `template` before its first placeholder
+    pub fn path(template: &str) -> PathBuf {
+        let prefix = &template[..template.find('{').unwrap_or(template.len())];
+        match prefix.rfind('/') {
+            Some(index) => Path::new(&prefix[..=index]).join(MANIFEST_FILE_NAME),
+            None => PathBuf::from(MANIFEST_FILE_NAME),
+        }
+    }
+
+    /// Read the manifest at `path`, or get an empty one if it does not exist
+    pub fn load(path: &Path) -> Result<Manifest> {
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Manifest::new(BTreeMap::new())),
+            Err(err) => return Err(err).with_context(|| format!("Could not read {:?}", path)),
+        };
+        let manifest: Manifest = serde_json::from_str(&text).with_context(|| format!("Could not parse {:?}", path))?;
+        ensure!(manifest.version == MANIFEST_VERSION, "{:?} has version {}, but this version of infrabase only reads version {}", path, manifest.version, MANIFEST_VERSION);
+        Ok(manifest)
+    }
+
+    /// Make a manifest of `files`, with the hostname by path
+    pub fn new(files: BTreeMap<String, String>) -> Manifest {
+        Manifest { version: MANIFEST_VERSION, files }
+    }
+
+    /// Write the manifest to `path` with `write`
+    pub fn save(&self, path: &Path) -> Result<Written> {
+        let mut text = serde_json::to_string_pretty(self)?;
+        text.push('\n');
+        write(path, &text)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn test_write() {
+        let dir = env::temp_dir().join(format!("infrabase-test-generated-files-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("web1.nix");
+        assert_eq!(write(&path, "[ ]\n").unwrap(), Written::Created);
+        assert_eq!(write(&path, "[ ]\n").unwrap(), Written::Unchanged);
+        assert_eq!(write(&path, "[\n]\n").unwrap(), Written::Updated);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "[\n]\n");
+        // No temporary files are left behind
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        assert!(write(&dir.join("missing").join("web2.nix"), "[ ]\n").is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_manifest_path() {
+        assert_eq!(Manifest::path("/etc/nixos/wireguard/{hostname}.nix"), Path::new("/etc/nixos/wireguard/.infrabase-generated.json"));
+        assert_eq!(Manifest::path("/etc/nixos/wg-{hostname}.nix"), Path::new("/etc/nixos/.infrabase-generated.json"));
+        assert_eq!(Manifest::path("/srv/{hostname}/peers.nix"), Path::new("/srv/.infrabase-generated.json"));
+        assert_eq!(Manifest::path("{hostname}.nix"), Path::new(".infrabase-generated.json"));
+    }
+
+    #[test]
+    fn test_manifest() {
+        let dir = env::temp_dir().join(format!("infrabase-test-manifest-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(MANIFEST_FILE_NAME);
+        assert_eq!(Manifest::load(&path).unwrap(), Manifest::new(BTreeMap::new()));
+        let manifest = Manifest::new(vec![("/etc/nixos/web1.nix".to_string(), "web1".to_string())].into_iter().collect());
+        assert_eq!(manifest.save(&path).unwrap(), Written::Created);
+        assert_eq!(manifest.save(&path).unwrap(), Written::Unchanged);
+        assert_eq!(Manifest::load(&path).unwrap(), manifest);
+        fs::write(&path, "{\"version\": 2, \"files\": {}}").unwrap();
+        assert!(Manifest::load(&path).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,295 @@
+//! The complete inventory as plain data, for `i export` and `i apply`.
+//!
+//! The document has one list per table, in this order:
+//!
+//! ```text
+//! version                 integer, currently 1
+//! owners                  [ "owner", ... ]
+//! networks                [ "network", ... ]
+//! providers               [ { id, name, email } ]
+//! network_links           [ { network, other_network, priority } ]
+//! ip_pools                [ { name, ipv4_cidr, ipv6_cidr, reserved: [ cidr ], policy, ipv6_mode } ]
+//! ip_reservations         [ { cidr, reason?, added_time } ]
+//! machines                [ { hostname, added_time, owner, state, provider_id?, provider_reference?,
+//!                             ssh?: { port, user },
+//!                             wireguard?: { ipv4_address, ipv6_address, port, pubkey, privkey? } } ]
+//! machine_addresses       [ { hostname, network, address, ssh_port?, wireguard_port? } ]
+//! wireguard_keepalives    [ { source_machine, target_machine, interval_sec } ]
+//! ```
+//!
+//! Fields marked `?` are omitted when unset.  Addresses are strings like
+//! "10.10.0.1" or "fd00::1", CIDRs are strings like "10.10.0.0/16", and times
+//! are RFC 3339 strings.  `privkey` is only included with `--with-privkeys`.
+//! `state` is one of planned, active, decommissioning, or retired, and is read
+//! as active when left out.
+//!
+//! `version` is incremented whenever a field is removed or changes meaning;
+//! new fields may be added without a version change, so readers should ignore
+//! fields they do not know.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::Path;
+use std::str::FromStr;
+use anyhow::{anyhow, bail, Error, Result};
+use chrono::{DateTime, Utc};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use infrabase::model::MachineState;
+
+use crate::ipam::{AllocationPolicy, Cidr, Ipv6Mode};
+
+/// Version of the inventory document format
+pub(crate) const FORMAT_VERSION: u32 = 1;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct Inventory {
+    pub version: u32,
+    pub owners: Vec<String>,
+    pub networks: Vec<String>,
+    pub providers: Vec<Provider>,
+    pub network_links: Vec<NetworkLink>,
+    pub ip_pools: Vec<IpPool>,
+    pub ip_reservations: Vec<IpReservation>,
+    pub machines: Vec<Machine>,
+    pub machine_addresses: Vec<MachineAddress>,
+    pub wireguard_keepalives: Vec<WireguardKeepalive>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct Provider {
+    pub id: i32,
+    pub name: String,
+    pub email: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct NetworkLink {
+    pub network: String,
+    pub other_network: String,
+    pub priority: i32,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct IpPool {
+    pub name: String,
+    pub ipv4_cidr: Cidr,
+    pub ipv6_cidr: Cidr,
+    pub reserved: Vec<Cidr>,
+    pub policy: AllocationPolicy,
+    pub ipv6_mode: Ipv6Mode,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct IpReservation {
+    pub cidr: Cidr,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    pub added_time: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct Machine {
+    pub hostname: String,
+    pub added_time: DateTime<Utc>,
+    pub owner: String,
+    #[serde(default)]
+    pub state: MachineState,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub provider_id: Option<i32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub provider_reference: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ssh: Option<SshServer>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub wireguard: Option<WireguardInterface>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct SshServer {
+    pub port: i32,
+    pub user: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct WireguardInterface {
+    pub ipv4_address: Ipv4Addr,
+    pub ipv6_address: Ipv6Addr,
+    pub port: i32,
+    pub pubkey: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub privkey: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct MachineAddress {
+    pub hostname: String,
+    pub network: String,
+    pub address: IpAddr,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ssh_port: Option<i32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub wireguard_port: Option<i32>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct WireguardKeepalive {
+    pub source_machine: String,
+    pub target_machine: String,
+    pub interval_sec: i32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Format {
+    Json,
+    Yaml,
+    Toml,
+}
+
+impl FromStr for Format {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Format> {
+        Ok(match s {
+            "json" => Format::Json,
+            "yaml" => Format::Yaml,
+            "toml" => Format::Toml,
+            _ => bail!("Unknown format {:?}, expected json, yaml, or toml", s),
+        })
+    }
+}
+
+impl Format {
+    /// Guess the format of a file from its extension
+    pub fn from_path(path: &Path) -> Result<Format> {
+        let extension = path.extension().and_then(|ext| ext.to_str())
+            .ok_or_else(|| anyhow!("Could not guess the format of {:?}: it has no extension", path))?;
+        match extension {
+            "yml" => Ok(Format::Yaml),
+            ext => ext.parse(),
+        }
+    }
+
+    pub fn parse<T: DeserializeOwned>(self, s: &str) -> Result<T> {
+        Ok(match self {
+            Format::Json => serde_json::from_str(s)?,
+            Format::Yaml => serde_yaml::from_str(s)?,
+            Format::Toml => toml::from_str(s)?,
+        })
+    }
+}
+
+impl Inventory {
+    pub fn serialize(&self, format: Format) -> Result<String> {
+        Ok(match format {
+            Format::Json => serde_json::to_string_pretty(self)? + "\n",
+            Format::Yaml => serde_yaml::to_string(self)?,
+            // Going through toml::Value puts plain values (including empty lists)
+            // before tables, which TOML requires
+            Format::Toml => toml::to_string_pretty(&toml::Value::try_from(self)?)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn inventory() -> Inventory {
+        Inventory {
+            version: FORMAT_VERSION,
+            owners: vec!["ivan".to_string()],
+            networks: vec!["NONE".to_string(), "internet".to_string()],
+            providers: vec![Provider { id: 1, name: "Hetzner".to_string(), email: "ops@example.com".to_string() }],
+            network_links: vec![NetworkLink { network: "NONE".to_string(), other_network: "internet".to_string(), priority: 0 }],
+            ip_pools: vec![IpPool {
+                name: "mesh".to_string(),
+                ipv4_cidr: "10.10.0.0/16".parse().unwrap(),
+                ipv6_cidr: "fd00::/64".parse().unwrap(),
+                reserved: vec!["10.10.0.0/24".parse().unwrap()],
+                policy: AllocationPolicy::LowestFree,
+                ipv6_mode: Ipv6Mode::EmbedIpv4,
+            }],
+            ip_reservations: vec![],
+            machines: vec![
+                Machine {
+                    hostname: "web1".to_string(),
+                    added_time: "2020-06-01T00:00:00Z".parse().unwrap(),
+                    owner: "ivan".to_string(),
+                    state: MachineState::Active,
+                    provider_id: Some(1),
+                    provider_reference: None,
+                    ssh: Some(SshServer { port: 22, user: "root".to_string() }),
+                    wireguard: Some(WireguardInterface {
+                        ipv4_address: "10.10.1.1".parse().unwrap(),
+                        ipv6_address: "fd00::1:1".parse().unwrap(),
+                        port: 904,
+                        pubkey: "x".repeat(43) + "=",
+                        privkey: None,
+                    }),
+                },
+                Machine {
+                    hostname: "laptop".to_string(),
+                    added_time: "2020-06-02T00:00:00Z".parse().unwrap(),
+                    owner: "ivan".to_string(),
+                    state: MachineState::Retired,
+                    provider_id: None,
+                    provider_reference: None,
+                    ssh: None,
+                    wireguard: None,
+                },
+            ],
+            machine_addresses: vec![MachineAddress {
+                hostname: "web1".to_string(),
+                network: "internet".to_string(),
+                address: "203.0.113.5".parse().unwrap(),
+                ssh_port: Some(22),
+                wireguard_port: Some(904),
+            }],
+            wireguard_keepalives: vec![WireguardKeepalive {
+                source_machine: "laptop".to_string(),
+                target_machine: "web1".to_string(),
+                interval_sec: 25,
+            }],
+        }
+    }
+
+    /// Every format can represent the whole inventory and reads back the same
+    #[test]
+    fn test_roundtrip() {
+        let inventory = inventory();
+        let json = inventory.serialize(Format::Json).unwrap();
+        let from_json: Inventory = serde_json::from_str(&json).unwrap();
+        let from_yaml: Inventory = serde_yaml::from_str(&inventory.serialize(Format::Yaml).unwrap()).unwrap();
+        let from_toml: Inventory = toml::from_str(&inventory.serialize(Format::Toml).unwrap()).unwrap();
+        for other in &[from_json, from_yaml, from_toml] {
+            assert_eq!(other.serialize(Format::Json).unwrap(), json);
+        }
+    }
+
+    /// Unset optional fields are omitted rather than written as null
+    #[test]
+    fn test_omits_unset_fields() {
+        let json = inventory().serialize(Format::Json).unwrap();
+        assert!(!json.contains("null"));
+        assert!(!json.contains("privkey"));
+        assert!(json.contains(r#""ipv4_cidr": "10.10.0.0/16""#));
+        assert!(json.contains(r#""ipv6_mode": "embed_ipv4""#));
+    }
+
+    #[test]
+    fn test_parse_format() {
+        assert_eq!("json".parse::<Format>().unwrap(), Format::Json);
+        assert_eq!("yaml".parse::<Format>().unwrap(), Format::Yaml);
+        assert_eq!("toml".parse::<Format>().unwrap(), Format::Toml);
+        assert!("xml".parse::<Format>().is_err());
+    }
+
+    #[test]
+    fn test_format_from_path() {
+        assert_eq!(Format::from_path(Path::new("infra.json")).unwrap(), Format::Json);
+        assert_eq!(Format::from_path(Path::new("infra/machines.yaml")).unwrap(), Format::Yaml);
+        assert_eq!(Format::from_path(Path::new("machines.yml")).unwrap(), Format::Yaml);
+        assert_eq!(Format::from_path(Path::new("machines.toml")).unwrap(), Format::Toml);
+        assert!(Format::from_path(Path::new("machines")).is_err());
+        assert!(Format::from_path(Path::new("machines.nix")).is_err());
+    }
+}
@@ -0,0 +1,852 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+use anyhow::{anyhow, bail, ensure, Context, Error, Result};
+
+/// An IPv4 or IPv6 network in CIDR notation, like 10.10.0.0/16 or fd00::/64
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) struct Cidr {
+    address: IpAddr,
+    prefix_len: u8,
+}
+
+fn max_prefix_len(ip: IpAddr) -> u8 {
+    match ip {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    }
+}
+
+fn ip_to_u128(ip: IpAddr) -> u128 {
+    match ip {
+        IpAddr::V4(ip) => u128::from(u32::from(ip)),
+        IpAddr::V6(ip) => u128::from(ip),
+    }
+}
+
+fn u128_to_ip(value: u128, ipv4: bool) -> IpAddr {
+    if ipv4 {
+        IpAddr::V4(Ipv4Addr::from(value as u32))
+    } else {
+        IpAddr::V6(Ipv6Addr::from(value))
+    }
+}
+
+impl Cidr {
+    pub fn new(address: IpAddr, prefix_len: u8) -> Result<Cidr> {
+        ensure!(prefix_len <= max_prefix_len(address), "Prefix length {} is too long for {}", prefix_len, address);
+        let cidr = Cidr { address, prefix_len };
+        ensure!(ip_to_u128(address) & !cidr.mask() == 0, "{}/{} has bits set to the right of the mask", address, prefix_len);
+        Ok(cidr)
+    }
+
+    pub fn prefix_len(&self) -> u8 {
+        self.prefix_len
+    }
+
+    pub fn is_ipv4(&self) -> bool {
+        self.address.is_ipv4()
+    }
+
+    fn host_bits(&self) -> u8 {
+        max_prefix_len(self.address) - self.prefix_len
+    }
+
+    /// The network mask as an integer, within the bits used by the address family
+    fn mask(&self) -> u128 {
+        let family_bits = if self.is_ipv4() { u128::from(u32::MAX) } else { u128::MAX };
+        match self.host_bits() {
+            128 => 0,
+            bits => (u128::MAX << bits) & family_bits,
+        }
+    }
+
+    /// The lowest address in the network
+    pub fn first(&self) -> IpAddr {
+        self.address
+    }
+
+    /// The highest address in the network
+    pub fn last(&self) -> IpAddr {
+        let family_bits = if self.is_ipv4() { u128::from(u32::MAX) } else { u128::MAX };
+        u128_to_ip(ip_to_u128(self.address) | (!self.mask() & family_bits), self.is_ipv4())
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        ip.is_ipv4() == self.is_ipv4() && ip_to_u128(ip) & self.mask() == ip_to_u128(self.address)
+    }
+
+    /// The range of addresses in the network that may be handed out to machines.
+    ///
+    /// For IPv4, this excludes the network and broadcast addresses (except in /31
+    /// and /32 networks, which have neither).  For IPv6, this excludes the
+    /// subnet-router anycast address (except in /127 and /128 networks).
+    pub fn usable_range(&self) -> Option<(IpAddr, IpAddr)> {
+        let (first, last) = (self.first(), self.last());
+        let host_bits = self.host_bits();
+        match self.address {
+            IpAddr::V4(_) if host_bits >= 2 => Some((next_address(first)?, previous_address(last)?)),
+            IpAddr::V6(_) if host_bits >= 2 => Some((next_address(first)?, last)),
+            _ => Some((first, last)),
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = Error;
+
+    /// Parse a CIDR like 10.10.0.0/16; a bare address is parsed as a single-address network
+    fn from_str(s: &str) -> Result<Cidr> {
+        let (address, prefix_len) = match s.find('/') {
+            Some(idx) => (&s[..idx], Some(&s[idx + 1..])),
+            None => (s, None),
+        };
+        let address = address.parse::<IpAddr>()
+            .with_context(|| anyhow!("Could not parse {:?} as an IP address", address))?;
+        let prefix_len = match prefix_len {
+            Some(len) => len.parse::<u8>()
+                .with_context(|| anyhow!("Could not parse {:?} as a prefix length", len))?,
+            None => max_prefix_len(address),
+        };
+        Cidr::new(address, prefix_len)
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.address, self.prefix_len)
+    }
+}
+
+serde_via_string!(Cidr);
+
+/// How `i add` picks the next WireGuard address in a pool
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum AllocationPolicy {
+    /// The lowest address not in use
+    LowestFree,
+    /// The address after the highest address in use, wrapping around to the
+    /// lowest free address when the end of the pool is reached.  This avoids
+    /// quickly reusing addresses that were recently freed.
+    AfterHighest,
+}
+
+impl FromStr for AllocationPolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<AllocationPolicy> {
+        Ok(match s {
+            "lowest_free" => AllocationPolicy::LowestFree,
+            "after_highest" => AllocationPolicy::AfterHighest,
+            _ => bail!("Unknown allocation policy {:?}, expected lowest_free or after_highest", s),
+        })
+    }
+}
+
+impl fmt::Display for AllocationPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            AllocationPolicy::LowestFree => "lowest_free",
+            AllocationPolicy::AfterHighest => "after_highest",
+        })
+    }
+}
+
+serde_via_string!(AllocationPolicy);
+
+/// How the IPv6 address of a machine is picked relative to its IPv4 address
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Ipv6Mode {
+    /// IPv6 addresses are allocated separately from IPv4 addresses
+    Independent,
+    /// The IPv6 address is derived from the host part of the IPv4 address, see
+    /// `embed_ipv4_address`
+    EmbedIpv4,
+}
+
+impl FromStr for Ipv6Mode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Ipv6Mode> {
+        Ok(match s {
+            "independent" => Ipv6Mode::Independent,
+            "embed_ipv4" => Ipv6Mode::EmbedIpv4,
+            _ => bail!("Unknown IPv6 mode {:?}, expected independent or embed_ipv4", s),
+        })
+    }
+}
+
+impl fmt::Display for Ipv6Mode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Ipv6Mode::Independent => "independent",
+            Ipv6Mode::EmbedIpv4 => "embed_ipv4",
+        })
+    }
+}
+
+serde_via_string!(Ipv6Mode);
+
+/// Write the decimal digits of an octet as hex digits, so that 17 becomes 0x17
+fn decimal_as_hex(octet: u8) -> u16 {
+    let (hundreds, tens, ones) = (u16::from(octet / 100), u16::from(octet / 10 % 10), u16::from(octet % 10));
+    hundreds << 8 | tens << 4 | ones
+}
+
+/// The number of trailing IPv4 octets that are (at least partly) host bits
+fn host_octets(ipv4_cidr: &Cidr) -> usize {
+    4 - usize::from(ipv4_cidr.prefix_len() / 8)
+}
+
+/// Check that the IPv6 network has room for the host octets of the IPv4 network
+pub(crate) fn check_embeddable(ipv4_cidr: &Cidr, ipv6_cidr: &Cidr) -> Result<()> {
+    let octets = host_octets(ipv4_cidr);
+    ensure!(octets >= 1, "{} has no host part to embed in IPv6 addresses", ipv4_cidr);
+    let needed_bits = 16 * octets;
+    ensure!(128 - usize::from(ipv6_cidr.prefix_len()) >= needed_bits,
+            "{} is too small to embed the host part of {}, need at least {} host bits", ipv6_cidr, ipv4_cidr, needed_bits);
+    Ok(())
+}
+
+/// Derive an IPv6 address in `ipv6_cidr` from the host part of `ip` in `ipv4_cidr`.
+///
+/// Each host octet of the IPv4 address becomes one of the trailing 16-bit groups of
+/// the IPv6 address, written so that it reads the same in hex as the octet does in
+/// decimal: with 10.10.0.0/16 and fd00::/64, 10.10.3.17 becomes fd00::3:17.
+pub(crate) fn embed_ipv4_address(ipv4_cidr: &Cidr, ipv6_cidr: &Cidr, ip: Ipv4Addr) -> Result<Ipv6Addr> {
+    ensure!(ipv4_cidr.contains(IpAddr::V4(ip)), "{} is not inside {}", ip, ipv4_cidr);
+    check_embeddable(ipv4_cidr, ipv6_cidr)?;
+    let mut segments = get_ipv6addr(ipv6_cidr.first()).segments();
+    let octets = host_octets(ipv4_cidr);
+    for (i, octet) in ip.octets()[4 - octets..].iter().enumerate() {
+        segments[8 - octets + i] = decimal_as_hex(*octet);
+    }
+    Ok(Ipv6Addr::from(segments))
+}
+
+/// Get the IPv4 address in `ipv4_cidr` whose host part `ip` embeds, the inverse
+/// of `embed_ipv4_address`: with 10.10.0.0/16 and fd00::/64, fd00::3:17 gives
+/// 10.10.3.17.  Fails if `ip` is not what `embed_ipv4_address` would derive.
+pub(crate) fn extract_ipv4_address(ipv4_cidr: &Cidr, ipv6_cidr: &Cidr, ip: Ipv6Addr) -> Result<Ipv4Addr> {
+    check_embeddable(ipv4_cidr, ipv6_cidr)?;
+    let not_embedded = || anyhow!("{} does not embed an IPv4 address in {} the way pools with ipv6_mode embed_ipv4 do", ip, ipv4_cidr);
+    let mut octets = get_ipv4addr(ipv4_cidr.first()).octets();
+    let host_octets = host_octets(ipv4_cidr);
+    for (i, segment) in ip.segments()[8 - host_octets..].iter().enumerate() {
+        let digits = [segment >> 12, segment >> 8 & 0xf, segment >> 4 & 0xf, segment & 0xf];
+        ensure!(digits[0] == 0 && digits[1..].iter().all(|digit| *digit <= 9), not_embedded());
+        let octet = digits[1] * 100 + digits[2] * 10 + digits[3];
+        ensure!(octet <= 255, not_embedded());
+        octets[4 - host_octets + i] = octet as u8;
+    }
+    let ipv4 = Ipv4Addr::from(octets);
+    ensure!(ipv4_cidr.contains(IpAddr::V4(ipv4)) && embed_ipv4_address(ipv4_cidr, ipv6_cidr, ipv4)? == ip, not_embedded());
+    Ok(ipv4)
+}
+
+/// The smallest network that contains both `start` and `end`, which must be
+/// of the same family
+fn covering_cidr(start: IpAddr, end: IpAddr) -> Cidr {
+    let differing_bits = 128 - (ip_to_u128(start) ^ ip_to_u128(end)).leading_zeros() as u8;
+    let cidr = Cidr { address: start, prefix_len: max_prefix_len(start) - differing_bits };
+    Cidr { address: u128_to_ip(ip_to_u128(start) & cidr.mask(), start.is_ipv4()), ..cidr }
+}
+
+/// Split `start..=end` into the fewest networks that together contain exactly
+/// those addresses
+fn range_to_cidrs(start: IpAddr, end: IpAddr) -> Vec<Cidr> {
+    let ipv4 = start.is_ipv4();
+    let (mut value, end) = (ip_to_u128(start), ip_to_u128(end));
+    let mut cidrs = vec![];
+    while value <= end {
+        // The largest aligned network starting at `value` that does not go past `end`
+        let fits = match (end - value).checked_add(1) {
+            Some(count) => 127 - count.leading_zeros() as u8,
+            None => 128,
+        };
+        let host_bits = (value.trailing_zeros() as u8).min(fits).min(max_prefix_len(start));
+        cidrs.push(Cidr { address: u128_to_ip(value, ipv4), prefix_len: max_prefix_len(start) - host_bits });
+        match 1u128.checked_shl(u32::from(host_bits)).and_then(|size| value.checked_add(size)) {
+            Some(next) => value = next,
+            None => break,
+        }
+    }
+    cidrs
+}
+
+/// A pool named `name` that allocates from `ipv4_start..=ipv4_end` and
+/// `ipv6_start..=ipv6_end`, the way `i add` did before IP pools existed.  Each
+/// range becomes the smallest network that covers it, with the addresses
+/// outside the range reserved.  Like in any pool, the first address of the
+/// network (and for IPv4, the last) is never allocated.
+pub(crate) fn range_pool(name: &str, ipv4_start: Ipv4Addr, ipv4_end: Ipv4Addr, ipv6_start: Ipv6Addr, ipv6_end: Ipv6Addr) -> IpPool {
+    let mut reserved = vec![];
+    let mut cover = |start: IpAddr, end: IpAddr| {
+        let cidr = covering_cidr(start, end);
+        if start > cidr.first() {
+            reserved.extend(range_to_cidrs(cidr.first(), previous_address(start).unwrap()));
+        }
+        if end < cidr.last() {
+            reserved.extend(range_to_cidrs(next_address(end).unwrap(), cidr.last()));
+        }
+        cidr
+    };
+    let ipv4_cidr = cover(IpAddr::V4(ipv4_start), IpAddr::V4(ipv4_end));
+    let ipv6_cidr = cover(IpAddr::V6(ipv6_start), IpAddr::V6(ipv6_end));
+    IpPool {
+        name: name.to_string(),
+        ipv4_cidr,
+        ipv6_cidr,
+        reserved,
+        policy: AllocationPolicy::LowestFree,
+        ipv6_mode: Ipv6Mode::Independent,
+    }
+}
+
+/// A named range of WireGuard mesh addresses
+#[derive(Debug, Clone)]
+pub(crate) struct IpPool {
+    pub name: String,
+    pub ipv4_cidr: Cidr,
+    pub ipv6_cidr: Cidr,
+    /// Ranges inside the pool that must not be allocated
+    pub reserved: Vec<Cidr>,
+    pub policy: AllocationPolicy,
+    pub ipv6_mode: Ipv6Mode,
+}
+
+impl IpPool {
+    /// Find an unused IPv4 address in this pool
+    pub fn find_unused_ipv4_address(&self, used: &HashSet<IpAddr>) -> Option<Ipv4Addr> {
+        find_unused_address(&self.ipv4_cidr, &self.reserved, used, self.policy).map(get_ipv4addr)
+    }
+
+    /// Find an unused IPv6 address in this pool
+    pub fn find_unused_ipv6_address(&self, used: &HashSet<IpAddr>) -> Option<Ipv6Addr> {
+        find_unused_address(&self.ipv6_cidr, &self.reserved, used, self.policy).map(get_ipv6addr)
+    }
+
+    /// The IPv6 address a machine with IPv4 address `ip` should have, if this pool
+    /// derives IPv6 addresses from IPv4 addresses
+    pub fn expected_ipv6_address(&self, ip: Ipv4Addr) -> Result<Option<Ipv6Addr>> {
+        match self.ipv6_mode {
+            Ipv6Mode::Independent => Ok(None),
+            Ipv6Mode::EmbedIpv4 => Ok(Some(embed_ipv4_address(&self.ipv4_cidr, &self.ipv6_cidr, ip)?)),
+        }
+    }
+
+    /// Pick WireGuard addresses for a new machine, keeping any address that was
+    /// already chosen and allocating the rest.  `used` contains the addresses of
+    /// both families that are unavailable.
+    pub fn allocate(&self, ipv4: Option<Ipv4Addr>, ipv6: Option<Ipv6Addr>, used: &HashSet<IpAddr>) -> Result<(Ipv4Addr, Ipv6Addr)> {
+        let no_ipv4 = || anyhow!("Could not find an unused WireGuard IPv4 address in pool {:?}", self.name);
+        let no_ipv6 = || anyhow!("Could not find an unused WireGuard IPv6 address in pool {:?}", self.name);
+        match (ipv4, ipv6, self.ipv6_mode) {
+            (Some(ipv4), Some(ipv6), _) => Ok((ipv4, ipv6)),
+            (Some(ipv4), None, Ipv6Mode::EmbedIpv4) => Ok((ipv4, embed_ipv4_address(&self.ipv4_cidr, &self.ipv6_cidr, ipv4)?)),
+            (None, Some(ipv6), Ipv6Mode::EmbedIpv4) => {
+                let ipv4 = extract_ipv4_address(&self.ipv4_cidr, &self.ipv6_cidr, ipv6)?;
+                let ipv4_unavailable = used.contains(&IpAddr::V4(ipv4)) ||
+                    self.reserved.iter().any(|range| range.contains(IpAddr::V4(ipv4)));
+                ensure!(!ipv4_unavailable, "WireGuard IPv4 address {} embedded in {} is not free in pool {:?}", ipv4, ipv6, self.name);
+                Ok((ipv4, ipv6))
+            },
+            (None, None, Ipv6Mode::EmbedIpv4) => {
+                // Skip IPv4 addresses whose paired IPv6 address is taken or reserved
+                let mut unavailable = used.clone();
+                loop {
+                    let ipv4 = self.find_unused_ipv4_address(&unavailable).ok_or_else(no_ipv4)?;
+                    let ipv6 = embed_ipv4_address(&self.ipv4_cidr, &self.ipv6_cidr, ipv4)?;
+                    let ipv6_unavailable = used.contains(&IpAddr::V6(ipv6)) ||
+                        self.reserved.iter().any(|range| range.contains(IpAddr::V6(ipv6)));
+                    if !ipv6_unavailable {
+                        return Ok((ipv4, ipv6));
+                    }
+                    unavailable.insert(IpAddr::V4(ipv4));
+                }
+            },
+            (ipv4, ipv6, _) => {
+                let ipv4 = match ipv4 {
+                    Some(ip) => ip,
+                    None => self.find_unused_ipv4_address(used).ok_or_else(no_ipv4)?,
+                };
+                let ipv6 = match ipv6 {
+                    Some(ip) => ip,
+                    None => self.find_unused_ipv6_address(used).ok_or_else(no_ipv6)?,
+                };
+                Ok((ipv4, ipv6))
+            },
+        }
+    }
+}
+
+/// Get Ipv4Addr from IpAddr or panic
+pub(crate) fn get_ipv4addr(ipaddr: IpAddr) -> Ipv4Addr {
+    match ipaddr {
+        IpAddr::V4(ip) => ip,
+        IpAddr::V6(_) => panic!("Got Ipv6Addr: {:?}", ipaddr),
+    }
+}
+
+/// Get Ipv6Addr from IpAddr or panic
+pub(crate) fn get_ipv6addr(ipaddr: IpAddr) -> Ipv6Addr {
+    match ipaddr {
+        IpAddr::V6(ip) => ip,
+        IpAddr::V4(_) => panic!("Got Ipv4Addr: {:?}", ipaddr),
+    }
+}
+
+#[allow(clippy::trivially_copy_pass_by_ref)]
+fn increment_ipv4_address(ip: &Ipv4Addr) -> Option<Ipv4Addr> {
+    let mut octets = ip.octets();
+    if octets == [255, 255, 255, 255] {
+        return None;
+    }
+    for i in (0..4).rev() {
+        if octets[i] < 255 {
+            octets[i] += 1;
+            break;
+        } else {
+            octets[i] = 0;
+        }
+    }
+    Some(Ipv4Addr::new(octets[0], octets[1], octets[2], octets[3]))
+}
+
+fn increment_ipv6_address(ip: &Ipv6Addr) -> Option<Ipv6Addr> {
+    let mut segments = ip.segments();
+    if segments == [0xffff, 0xffff, 0xffff, 0xffff, 0xffff, 0xffff, 0xffff, 0xffff] {
+        return None;
+    }
+    for i in (0..8).rev() {
+        if segments[i] < 0xffff {
+            segments[i] += 1;
+            break;
+        } else {
+            segments[i] = 0;
+        }
+    }
+    Some(Ipv6Addr::new(segments[0], segments[1], segments[2], segments[3], segments[4], segments[5], segments[6], segments[7]))
+}
+
+/// The address after `ip`, or None if `ip` is the highest address in its family
+pub(crate) fn next_address(ip: IpAddr) -> Option<IpAddr> {
+    match ip {
+        IpAddr::V4(ip) => increment_ipv4_address(&ip).map(IpAddr::V4),
+        IpAddr::V6(ip) => increment_ipv6_address(&ip).map(IpAddr::V6),
+    }
+}
+
+/// The address before `ip`, or None if `ip` is the lowest address in its family
+pub(crate) fn previous_address(ip: IpAddr) -> Option<IpAddr> {
+    ip_to_u128(ip).checked_sub(1).map(|value| u128_to_ip(value, ip.is_ipv4()))
+}
+
+/// Return the lowest address in `from..=to` that is neither in `used` nor inside any of `excluded`
+fn first_unused_address(from: IpAddr, to: IpAddr, excluded: &[Cidr], used: &HashSet<IpAddr>) -> Option<IpAddr> {
+    let mut candidate = from;
+    while candidate <= to {
+        if let Some(range) = excluded.iter().find(|range| range.contains(candidate)) {
+            candidate = next_address(range.last())?;
+        } else if used.contains(&candidate) {
+            candidate = next_address(candidate)?;
+        } else {
+            return Some(candidate);
+        }
+    }
+    None
+}
+
+/// Pick an address in `cidr` that is neither in `used` nor inside any of `excluded`,
+/// according to `policy`.  `excluded` may contain networks of either address family.
+pub(crate) fn find_unused_address(cidr: &Cidr, excluded: &[Cidr], used: &HashSet<IpAddr>, policy: AllocationPolicy) -> Option<IpAddr> {
+    let (lowest, highest) = cidr.usable_range()?;
+    let start = match policy {
+        AllocationPolicy::LowestFree => lowest,
+        AllocationPolicy::AfterHighest => {
+            used.iter()
+                .filter(|ip| **ip >= lowest && **ip <= highest)
+                .max()
+                .and_then(|ip| next_address(*ip))
+                .filter(|ip| *ip <= highest)
+                .unwrap_or(lowest)
+        }
+    };
+    first_unused_address(start, highest, excluded, used)
+        .or_else(|| first_unused_address(lowest, highest, excluded, used))
+}
+
+/// How much of a network is available for allocation.  Each address in the
+/// usable range is counted in exactly one of `used`, `reserved`, `quarantined`,
+/// and `free`, in that order of precedence.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct Utilization {
+    /// Number of addresses in the usable range
+    pub size: u128,
+    pub used: u128,
+    pub reserved: u128,
+    pub quarantined: u128,
+    pub free: u128,
+    /// Number of separate runs of free addresses
+    pub free_ranges: usize,
+    /// Length of the longest run of free addresses
+    pub largest_free_range: u128,
+}
+
+/// Sort and merge overlapping or adjacent inclusive (start, end) ranges
+fn merge_ranges(mut ranges: Vec<(u128, u128)>) -> Vec<(u128, u128)> {
+    ranges.sort_unstable();
+    let mut merged: Vec<(u128, u128)> = vec![];
+    for (start, end) in ranges {
+        match merged.last_mut() {
+            Some(last) if start <= last.1.saturating_add(1) => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+    merged
+}
+
+/// Count the used, reserved, quarantined, and free addresses in the usable
+/// range of `cidr`.  `reserved` may contain networks of either address family.
+pub(crate) fn utilization(cidr: &Cidr, reserved: &[Cidr], used: &HashSet<IpAddr>, quarantined: &HashSet<IpAddr>) -> Option<Utilization> {
+    let (lowest, highest) = cidr.usable_range()?;
+    let (lowest, highest) = (ip_to_u128(lowest), ip_to_u128(highest));
+    let in_range = |ip: &IpAddr| ip.is_ipv4() == cidr.is_ipv4() && (lowest..=highest).contains(&ip_to_u128(*ip));
+
+    let reserved_ranges = merge_ranges(reserved.iter()
+        .filter(|range| range.is_ipv4() == cidr.is_ipv4())
+        .map(|range| (ip_to_u128(range.first()).max(lowest), ip_to_u128(range.last()).min(highest)))
+        .filter(|(start, end)| start <= end)
+        .collect());
+    let is_reserved = |ip: u128| reserved_ranges.iter().any(|(start, end)| (*start..=*end).contains(&ip));
+
+    let used = used.iter().filter(|ip| in_range(ip)).map(|ip| ip_to_u128(*ip)).collect::<HashSet<u128>>();
+    let quarantined = quarantined.iter()
+        .filter(|ip| in_range(ip))
+        .map(|ip| ip_to_u128(*ip))
+        .filter(|ip| !used.contains(ip) && !is_reserved(*ip))
+        .collect::<HashSet<u128>>();
+    let used_in_reserved = used.iter().filter(|ip| is_reserved(**ip)).count() as u128;
+    let reserved_count = reserved_ranges.iter().map(|(start, end)| end - start + 1).sum::<u128>() - used_in_reserved;
+
+    let unavailable = merge_ranges(reserved_ranges.iter().copied()
+        .chain(used.iter().chain(quarantined.iter()).map(|ip| (*ip, *ip)))
+        .collect());
+    let mut free_runs = vec![];
+    let mut next_free = Some(lowest);
+    for (start, end) in &unavailable {
+        if let Some(free_start) = next_free {
+            if free_start < *start {
+                free_runs.push(start - free_start);
+            }
+        }
+        next_free = end.checked_add(1);
+    }
+    if let Some(free_start) = next_free {
+        if free_start <= highest {
+            free_runs.push(highest - free_start + 1);
+        }
+    }
+
+    Some(Utilization {
+        size: highest - lowest + 1,
+        used: used.len() as u128,
+        reserved: reserved_count,
+        quarantined: quarantined.len() as u128,
+        free: free_runs.iter().sum(),
+        free_ranges: free_runs.len(),
+        largest_free_range: free_runs.iter().copied().max().unwrap_or(0),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cidr(s: &str) -> Cidr {
+        s.parse().unwrap()
+    }
+
+    fn ips(list: &[&str]) -> HashSet<IpAddr> {
+        list.iter().map(|s| s.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn test_increment_ipv4_address() {
+        assert_eq!(increment_ipv4_address(&Ipv4Addr::new(0,   0,   0,   0)),   Some(Ipv4Addr::new(0, 0, 0,   1)));
+        assert_eq!(increment_ipv4_address(&Ipv4Addr::new(0,   0,   0,   1)),   Some(Ipv4Addr::new(0, 0, 0,   2)));
+        assert_eq!(increment_ipv4_address(&Ipv4Addr::new(0,   0,   1,   255)), Some(Ipv4Addr::new(0, 0, 2,   0)));
+        assert_eq!(increment_ipv4_address(&Ipv4Addr::new(0,   0,   255, 0)),   Some(Ipv4Addr::new(0, 0, 255, 1)));
+        assert_eq!(increment_ipv4_address(&Ipv4Addr::new(0,   2,   255, 255)), Some(Ipv4Addr::new(0, 3, 0,   0)));
+        assert_eq!(increment_ipv4_address(&Ipv4Addr::new(3,   255, 255, 255)), Some(Ipv4Addr::new(4, 0, 0,   0)));
+        assert_eq!(increment_ipv4_address(&Ipv4Addr::new(255, 255, 255, 255)), None);
+    }
+
+    #[test]
+    fn test_increment_ipv6_address() {
+        assert_eq!(increment_ipv6_address(&"0:0:0:0:0:0:0:0"                        .parse::<Ipv6Addr>().unwrap()), Some("0:0:0:0:0:0:0:1"   .parse().unwrap()));
+        assert_eq!(increment_ipv6_address(&"0:0:0:0:0:0:0:1"                        .parse::<Ipv6Addr>().unwrap()), Some("0:0:0:0:0:0:0:2"   .parse().unwrap()));
+        assert_eq!(increment_ipv6_address(&"0:0:0:0:0:0:1:ffff"                     .parse::<Ipv6Addr>().unwrap()), Some("0:0:0:0:0:0:2:0"   .parse().unwrap()));
+        assert_eq!(increment_ipv6_address(&"0:0:0:0:0:0:ffff:0"                     .parse::<Ipv6Addr>().unwrap()), Some("0:0:0:0:0:0:ffff:1".parse().unwrap()));
+        assert_eq!(increment_ipv6_address(&"0:0:0:0:0:2:ffff:ffff"                  .parse::<Ipv6Addr>().unwrap()), Some("0:0:0:0:0:3:0:0"   .parse().unwrap()));
+        assert_eq!(increment_ipv6_address(&"0:0:0:0:3:ffff:ffff:ffff"               .parse::<Ipv6Addr>().unwrap()), Some("0:0:0:0:4:0:0:0"   .parse().unwrap()));
+        assert_eq!(increment_ipv6_address(&"ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff".parse::<Ipv6Addr>().unwrap()), None);
+    }
+
+    /// Parses CIDRs and bare addresses, and rejects host bits and long prefixes
+    #[test]
+    fn test_parse_cidr() {
+        assert_eq!(cidr("10.10.0.0/16").to_string(), "10.10.0.0/16");
+        assert_eq!(cidr("10.10.0.1").to_string(), "10.10.0.1/32");
+        assert_eq!(cidr("fd00::/64").to_string(), "fd00::/64");
+        assert_eq!(cidr("0.0.0.0/0").to_string(), "0.0.0.0/0");
+        assert!("10.10.0.1/16".parse::<Cidr>().is_err());
+        assert!("10.10.0.0/33".parse::<Cidr>().is_err());
+        assert!("fd00::1/64".parse::<Cidr>().is_err());
+        assert!("fd00::/64/1".parse::<Cidr>().is_err());
+        assert!("example.com/24".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn test_cidr_first_last_contains() {
+        let net = cidr("10.10.0.0/16");
+        assert_eq!(net.first(), "10.10.0.0".parse::<IpAddr>().unwrap());
+        assert_eq!(net.last(), "10.10.255.255".parse::<IpAddr>().unwrap());
+        assert!(net.contains("10.10.3.4".parse().unwrap()));
+        assert!(!net.contains("10.11.0.0".parse().unwrap()));
+        assert!(!net.contains("::a0a:1".parse().unwrap()));
+
+        let net = cidr("fd00::/64");
+        assert_eq!(net.last(), "fd00::ffff:ffff:ffff:ffff".parse::<IpAddr>().unwrap());
+        assert!(net.contains("fd00::1".parse().unwrap()));
+        assert!(!net.contains("fd00:0:0:1::".parse().unwrap()));
+
+        assert!(cidr("0.0.0.0/0").contains("255.255.255.255".parse().unwrap()));
+        assert!(cidr("::/0").contains("ffff::1".parse().unwrap()));
+    }
+
+    /// Network and broadcast addresses are not usable
+    #[test]
+    fn test_usable_range() {
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        assert_eq!(cidr("10.10.0.0/24").usable_range(), Some((ip("10.10.0.1"), ip("10.10.0.254"))));
+        assert_eq!(cidr("10.10.0.0/30").usable_range(), Some((ip("10.10.0.1"), ip("10.10.0.2"))));
+        assert_eq!(cidr("10.10.0.0/31").usable_range(), Some((ip("10.10.0.0"), ip("10.10.0.1"))));
+        assert_eq!(cidr("10.10.0.7/32").usable_range(), Some((ip("10.10.0.7"), ip("10.10.0.7"))));
+        assert_eq!(cidr("fd00::/120").usable_range(), Some((ip("fd00::1"), ip("fd00::ff"))));
+    }
+
+    #[test]
+    fn test_find_unused_address_lowest_free() {
+        let net = cidr("10.10.0.0/29");
+        let policy = AllocationPolicy::LowestFree;
+        assert_eq!(find_unused_address(&net, &[], &ips(&[]), policy), Some("10.10.0.1".parse().unwrap()));
+        assert_eq!(find_unused_address(&net, &[], &ips(&["10.10.0.1", "10.10.0.3"]), policy), Some("10.10.0.2".parse().unwrap()));
+        // Broadcast address is never handed out
+        assert_eq!(find_unused_address(&net, &[], &ips(&["10.10.0.1", "10.10.0.2", "10.10.0.3", "10.10.0.4", "10.10.0.5"]), policy), Some("10.10.0.6".parse().unwrap()));
+        assert_eq!(find_unused_address(&net, &[], &ips(&["10.10.0.1", "10.10.0.2", "10.10.0.3", "10.10.0.4", "10.10.0.5", "10.10.0.6"]), policy), None);
+    }
+
+    #[test]
+    fn test_find_unused_address_skips_reserved() {
+        let net = cidr("10.10.0.0/24");
+        let policy = AllocationPolicy::LowestFree;
+        let reserved = [cidr("10.10.0.0/28"), cidr("fd00::/120")];
+        assert_eq!(find_unused_address(&net, &reserved, &ips(&[]), policy), Some("10.10.0.16".parse().unwrap()));
+        let reserved = [cidr("10.10.0.0/25"), cidr("10.10.0.128/25")];
+        assert_eq!(find_unused_address(&net, &reserved, &ips(&[]), policy), None);
+        // Reserved range at the very end of the address space
+        let net = cidr("ffff:ffff:ffff:ffff:ffff:ffff:ffff:ff00/120");
+        assert_eq!(find_unused_address(&net, &[net], &ips(&[]), policy), None);
+    }
+
+    #[test]
+    fn test_find_unused_address_after_highest() {
+        let net = cidr("10.10.0.0/29");
+        let policy = AllocationPolicy::AfterHighest;
+        assert_eq!(find_unused_address(&net, &[], &ips(&[]), policy), Some("10.10.0.1".parse().unwrap()));
+        assert_eq!(find_unused_address(&net, &[], &ips(&["10.10.0.3"]), policy), Some("10.10.0.4".parse().unwrap()));
+        // Addresses outside the pool do not count
+        assert_eq!(find_unused_address(&net, &[], &ips(&["10.10.0.3", "10.10.1.9"]), policy), Some("10.10.0.4".parse().unwrap()));
+        // Wraps around when the highest address is in use
+        assert_eq!(find_unused_address(&net, &[], &ips(&["10.10.0.1", "10.10.0.6"]), policy), Some("10.10.0.2".parse().unwrap()));
+        assert_eq!(find_unused_address(&net, &[], &ips(&["10.10.0.3", "10.10.0.6"]), policy), Some("10.10.0.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_merge_ranges() {
+        assert_eq!(merge_ranges(vec![]), vec![]);
+        assert_eq!(merge_ranges(vec![(5, 6), (1, 2), (3, 3), (10, 20), (12, 13)]), vec![(1, 3), (5, 6), (10, 20)]);
+        assert_eq!(merge_ranges(vec![(0, u128::MAX), (5, 6)]), vec![(0, u128::MAX)]);
+    }
+
+    #[test]
+    fn test_utilization() {
+        // Usable range is 10.10.0.1 - 10.10.0.14
+        let net = cidr("10.10.0.0/28");
+        assert_eq!(utilization(&net, &[], &ips(&[]), &ips(&[])), Some(Utilization {
+            size: 14, used: 0, reserved: 0, quarantined: 0, free: 14, free_ranges: 1, largest_free_range: 14,
+        }));
+        let reserved = [cidr("10.10.0.0/30"), cidr("10.10.0.2/31"), cidr("fd00::/120")];
+        let used = ips(&["10.10.0.3", "10.10.0.6", "10.10.0.99", "fd00::1"]);
+        let quarantined = ips(&["10.10.0.6", "10.10.0.9", "10.10.0.1"]);
+        // used: .3 and .6; reserved: .1 and .2; quarantined: .9; free: .4-.5, .7-.8, .10-.14
+        assert_eq!(utilization(&net, &reserved, &used, &quarantined), Some(Utilization {
+            size: 14, used: 2, reserved: 2, quarantined: 1, free: 9, free_ranges: 3, largest_free_range: 5,
+        }));
+        // Everything unavailable
+        assert_eq!(utilization(&net, &[net], &ips(&[]), &ips(&[])), Some(Utilization {
+            size: 14, used: 0, reserved: 14, quarantined: 0, free: 0, free_ranges: 0, largest_free_range: 0,
+        }));
+        // IPv6 network reaching the end of the address space
+        let net = cidr("ffff:ffff:ffff:ffff::/64");
+        let used = ips(&["ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff"]);
+        assert_eq!(utilization(&net, &[], &used, &ips(&[])), Some(Utilization {
+            size: u128::from(u64::MAX), used: 1, reserved: 0, quarantined: 0, free: u128::from(u64::MAX) - 1, free_ranges: 1, largest_free_range: u128::from(u64::MAX) - 1,
+        }));
+    }
+
+    #[test]
+    fn test_decimal_as_hex() {
+        assert_eq!(decimal_as_hex(0), 0x0);
+        assert_eq!(decimal_as_hex(7), 0x7);
+        assert_eq!(decimal_as_hex(17), 0x17);
+        assert_eq!(decimal_as_hex(100), 0x100);
+        assert_eq!(decimal_as_hex(255), 0x255);
+    }
+
+    #[test]
+    fn test_embed_ipv4_address() {
+        let embed = |v4: &str, v6: &str, ip: &str| {
+            embed_ipv4_address(&cidr(v4), &cidr(v6), ip.parse().unwrap()).map(|ip| ip.to_string())
+        };
+        assert_eq!(embed("10.10.0.0/16", "fd00::/64", "10.10.3.17").unwrap(), "fd00::3:17");
+        assert_eq!(embed("10.10.0.0/16", "fd00::/64", "10.10.0.255").unwrap(), "fd00::255");
+        assert_eq!(embed("10.10.0.0/24", "fd00:1::/112", "10.10.0.17").unwrap(), "fd00:1::17");
+        // Octet that is partly network bits is embedded whole
+        assert_eq!(embed("10.10.16.0/20", "fd00::/64", "10.10.17.1").unwrap(), "fd00::17:1");
+        // Address outside the IPv4 network
+        assert!(embed("10.10.0.0/16", "fd00::/64", "10.11.0.1").is_err());
+        // IPv6 network too small for two host octets
+        assert!(embed("10.10.0.0/16", "fd00::/120", "10.10.0.1").is_err());
+        // No host octets at all
+        assert!(embed("10.10.0.1/32", "fd00::/64", "10.10.0.1").is_err());
+    }
+
+    #[test]
+    fn test_extract_ipv4_address() {
+        let extract = |ipv4_cidr: &str, ipv6_cidr: &str, ip: &str| {
+            extract_ipv4_address(&cidr(ipv4_cidr), &cidr(ipv6_cidr), ip.parse().unwrap()).map(|ip| ip.to_string())
+        };
+        assert_eq!(extract("10.10.0.0/16", "fd00::/64", "fd00::3:17").unwrap(), "10.10.3.17");
+        assert_eq!(extract("10.10.0.0/16", "fd00::/64", "fd00::255:0").unwrap(), "10.10.255.0");
+        assert_eq!(extract("10.10.16.0/20", "fd00::/64", "fd00::17:1").unwrap(), "10.10.17.1");
+        // Not decimal digits, or more than 255
+        assert!(extract("10.10.0.0/16", "fd00::/64", "fd00::3:1a").is_err());
+        assert!(extract("10.10.0.0/16", "fd00::/64", "fd00::3:256").is_err());
+        // Bits set outside the embedded octets
+        assert!(extract("10.10.0.0/16", "fd00::/64", "fd00::1:3:17").is_err());
+        // Outside the IPv6 network, or the IPv4 network
+        assert!(extract("10.10.0.0/16", "fd00::/64", "fd01::3:17").is_err());
+        assert!(extract("10.10.16.0/20", "fd00::/64", "fd00::32:1").is_err());
+    }
+
+    fn pool(ipv6_mode: Ipv6Mode) -> IpPool {
+        IpPool {
+            name: "mesh".to_string(),
+            ipv4_cidr: cidr("10.10.0.0/16"),
+            ipv6_cidr: cidr("fd00::/64"),
+            reserved: vec![cidr("10.10.0.0/24")],
+            policy: AllocationPolicy::LowestFree,
+            ipv6_mode,
+        }
+    }
+
+    #[test]
+    fn test_allocate_independent() {
+        let pool = pool(Ipv6Mode::Independent);
+        let (ipv4, ipv6) = pool.allocate(None, None, &ips(&["10.10.1.0", "10.10.1.1", "fd00::1"])).unwrap();
+        assert_eq!((ipv4.to_string(), ipv6.to_string()), ("10.10.1.2".to_string(), "fd00::2".to_string()));
+        let (ipv4, ipv6) = pool.allocate(Some("10.10.9.9".parse().unwrap()), None, &ips(&[])).unwrap();
+        assert_eq!((ipv4.to_string(), ipv6.to_string()), ("10.10.9.9".to_string(), "fd00::1".to_string()));
+    }
+
+    #[test]
+    fn test_allocate_embed_ipv4() {
+        let pool = pool(Ipv6Mode::EmbedIpv4);
+        let (ipv4, ipv6) = pool.allocate(None, None, &ips(&["10.10.1.0", "10.10.1.1"])).unwrap();
+        assert_eq!((ipv4.to_string(), ipv6.to_string()), ("10.10.1.2".to_string(), "fd00::1:2".to_string()));
+        // Skips an IPv4 address whose paired IPv6 address is already taken
+        let (ipv4, ipv6) = pool.allocate(None, None, &ips(&["10.10.1.0", "10.10.1.1", "fd00::1:2"])).unwrap();
+        assert_eq!((ipv4.to_string(), ipv6.to_string()), ("10.10.1.3".to_string(), "fd00::1:3".to_string()));
+        // Derives the IPv6 address from an explicit IPv4 address
+        let (_, ipv6) = pool.allocate(Some("10.10.42.17".parse().unwrap()), None, &ips(&[])).unwrap();
+        assert_eq!(ipv6.to_string(), "fd00::42:17");
+        // Explicit IPv6 address is kept
+        let (_, ipv6) = pool.allocate(Some("10.10.42.17".parse().unwrap()), Some("fd00::9".parse().unwrap()), &ips(&[])).unwrap();
+        assert_eq!(ipv6.to_string(), "fd00::9");
+        // Derives the IPv4 address from an explicit IPv6 address
+        let (ipv4, _) = pool.allocate(None, Some("fd00::42:17".parse().unwrap()), &ips(&[])).unwrap();
+        assert_eq!(ipv4.to_string(), "10.10.42.17");
+        // ... if that IPv4 address is free
+        assert!(pool.allocate(None, Some("fd00::42:17".parse().unwrap()), &ips(&["10.10.42.17"])).is_err());
+        assert!(pool.allocate(None, Some("fd00::0:17".parse().unwrap()), &ips(&[])).is_err());
+        // ... and the IPv6 address embeds one at all
+        assert!(pool.allocate(None, Some("fd00::9:1a".parse().unwrap()), &ips(&[])).is_err());
+    }
+
+    #[test]
+    fn test_covering_cidr() {
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        assert_eq!(covering_cidr(ip("10.10.0.1"), ip("10.10.0.254")), cidr("10.10.0.0/24"));
+        assert_eq!(covering_cidr(ip("10.10.0.1"), ip("10.10.1.0")), cidr("10.10.0.0/23"));
+        assert_eq!(covering_cidr(ip("10.10.0.7"), ip("10.10.0.7")), cidr("10.10.0.7/32"));
+        assert_eq!(covering_cidr(ip("fd00::1"), ip("fd00::ffff")), cidr("fd00::/112"));
+        assert_eq!(covering_cidr(ip("::"), ip("ffff::")), cidr("::/0"));
+    }
+
+    #[test]
+    fn test_range_to_cidrs() {
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        assert_eq!(range_to_cidrs(ip("10.10.0.0"), ip("10.10.0.255")), vec![cidr("10.10.0.0/24")]);
+        assert_eq!(range_to_cidrs(ip("10.10.0.101"), ip("10.10.0.255")), vec![
+            cidr("10.10.0.101/32"), cidr("10.10.0.102/31"), cidr("10.10.0.104/29"), cidr("10.10.0.112/28"),
+            cidr("10.10.0.128/25"),
+        ]);
+        assert_eq!(range_to_cidrs(ip("255.255.255.255"), ip("255.255.255.255")), vec![cidr("255.255.255.255/32")]);
+        assert_eq!(range_to_cidrs(ip("::"), ip("ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff")), vec![cidr("::/0")]);
+    }
+
+    /// A range pool allocates exactly the addresses in its ranges
+    #[test]
+    fn test_range_pool() {
+        let pool = range_pool("range", "10.10.0.3".parse().unwrap(), "10.10.0.5".parse().unwrap(), "fd00::3".parse().unwrap(), "fd00::5".parse().unwrap());
+        assert_eq!(pool.ipv4_cidr, cidr("10.10.0.0/29"));
+        assert_eq!(pool.ipv6_cidr, cidr("fd00::/125"));
+        let mut used = ips(&[]);
+        for (ipv4, ipv6) in &[("10.10.0.3", "fd00::3"), ("10.10.0.4", "fd00::4"), ("10.10.0.5", "fd00::5")] {
+            let (allocated_ipv4, allocated_ipv6) = pool.allocate(None, None, &used).unwrap();
+            assert_eq!((allocated_ipv4.to_string(), allocated_ipv6.to_string()), (ipv4.to_string(), ipv6.to_string()));
+            used.insert(IpAddr::V4(allocated_ipv4));
+            used.insert(IpAddr::V6(allocated_ipv6));
+        }
+        assert!(pool.allocate(None, None, &used).is_err());
+    }
+
+    #[test]
+    fn test_ipv6_mode_roundtrip() {
+        for mode in &[Ipv6Mode::Independent, Ipv6Mode::EmbedIpv4] {
+            assert_eq!(mode.to_string().parse::<Ipv6Mode>().unwrap(), *mode);
+        }
+        assert!("random".parse::<Ipv6Mode>().is_err());
+    }
+
+    #[test]
+    fn test_allocation_policy_roundtrip() {
+        for policy in &[AllocationPolicy::LowestFree, AllocationPolicy::AfterHighest] {
+            assert_eq!(policy.to_string().parse::<AllocationPolicy>().unwrap(), *policy);
+        }
+        assert!("random".parse::<AllocationPolicy>().is_err());
+    }
+}
@@ -0,0 +1,20 @@
+//! The machine inventory model behind `i`, and everything that can be
+//! generated from it without a database.
+//!
+//! - `model`: machines, their addresses, and the maps of network links and
+//!   keepalives between them
+//! - `routing`: which address to use to reach a machine from another one, and
+//!   the WireGuard peers of a machine
+//! - `generate`: ssh_config, wg-quick, and Nix output
+//! - `nix`: the Nix values that `generate` builds
+//!
+//! The `i` binary reads these from Postgres or SQLite; other tools can build
+//! them in memory.
+
+#![deny(unsafe_code)]
+#![feature(format_args_capture)]
+
+pub mod model;
+pub mod routing;
+pub mod generate;
+pub mod nix;
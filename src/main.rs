@@ -1,157 +1,63 @@
 #![deny(unsafe_code)]
 #![feature(format_args_capture)]
 
+#[macro_use] mod macros;
 mod wireguard;
-mod nix;
+mod ipam;
+mod inventory;
+mod apply;
+mod backup;
+mod migrations;
+mod profile;
+mod config;
+mod tls;
+mod store;
+mod dry_run;
+mod check;
+mod generated_files;
 mod table_cell;
-#[macro_use] mod macros;
+#[cfg(test)]
+mod test_fixtures;
 
-use std::iter;
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::env;
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::io::Write;
-use std::fs::File;
+use std::fs;
 use std::str;
+use std::str::FromStr;
 use std::string::ToString;
-use std::convert::TryFrom;
+use std::path::{Path, PathBuf};
 use tabwriter::TabWriter;
-use postgres::{Client, Transaction, NoTls};
+use postgres::Transaction;
 use anyhow::{ensure, anyhow, bail, Context, Result};
 use structopt::StructOpt;
 use natural_sort::HumanStr;
-use itertools::{Itertools, iproduct};
-use chrono::{DateTime, Utc};
+use itertools::Itertools;
+use chrono::{DateTime, SecondsFormat, Utc};
 
-use nix::ToNix;
+use infrabase::generate::{self, WireguardModuleOptions};
+use infrabase::model::{get_sorted_machines, MachineState};
 use table_cell::ToTableCell;
-
-fn import_env() -> Result<()> {
-    let path = dirs::config_dir().unwrap().join("infrabase").join("env");
-    dotenv::from_path(&path)
-        .with_context(|| format!("Unable to read configuration from {:?}", &path))
-}
-
-fn postgres_client() -> Result<Client> {
-    let database_url = env_var("DATABASE_URL")?;
-    Ok(Client::connect(&database_url, NoTls)?)
-}
-
-#[derive(Debug)]
-pub struct Machine {
-    pub hostname: String,
-    pub wireguard_ipv4_address: Option<Ipv4Addr>,
-    pub wireguard_ipv6_address: Option<Ipv6Addr>,
-    pub wireguard_port: Option<i32>,
-    pub wireguard_privkey: Option<String>,
-    pub wireguard_pubkey: Option<String>,
-    pub ssh_port: Option<i32>,
-    pub ssh_user: Option<String>,
-    pub added_time: DateTime<Utc>,
-    pub owner: String,
-    pub provider_id: Option<i32>,
-    pub provider_reference: Option<String>,
-    pub networks: Vec<String>,
-    pub addresses: Vec<MachineAddress>,
-}
-
-#[derive(Debug)]
-pub struct MachineAddress {
-    pub hostname: String,
-    pub network: String,
-    pub address: IpAddr,
-    pub ssh_port: Option<i32>,
-    pub wireguard_port: Option<i32>,
-}
-
-/// A map of hostname -> Machine
-type MachinesMap = HashMap<String, Machine>;
-
-/// A map of (network, other_network) -> priority
-type NetworkLinksPriorityMap = HashMap<(String, String), i32>;
-
-/// A map of (source_machine, target_machine) -> interval
-type WireguardKeepaliveIntervalMap = HashMap<(String, String), i32>;
-
-fn get_network_links_priority_map(transaction: &mut Transaction) -> Result<NetworkLinksPriorityMap> {
-    let map = transaction.query("SELECT name, other_network, priority FROM network_links", &[])?
-        .into_iter()
-        .map(|row| ((row.get(0), row.get(1)), row.get(2)))
-        .collect::<HashMap<_, _>>();
-    Ok(map)
-}
-
-fn get_wireguard_keepalive_map(transaction: &mut Transaction) -> Result<WireguardKeepaliveIntervalMap> {
-    let map = transaction.query("SELECT source_machine, target_machine, interval_sec FROM wireguard_keepalives", &[])?
-        .into_iter()
-        .map(|row| ((row.get(0), row.get(1)), row.get(2)))
-        .collect::<HashMap<_, _>>();
-    Ok(map)
-}
-
-/// Get IPv4Addr from IpAddr or panic
-fn get_ipv4addr(ipaddr: IpAddr) -> Ipv4Addr {
-    match ipaddr {
-        IpAddr::V4(ip) => ip,
-        IpAddr::V6(_) => panic!("Got Ipv6Addr: {:?}", ipaddr),
-    }
-}
-
-/// Get IPv6Addr from IpAddr or panic
-fn get_ipv6addr(ipaddr: IpAddr) -> Ipv6Addr {
-    match ipaddr {
-        IpAddr::V6(ip) => ip,
-        IpAddr::V4(_) => panic!("Got Ipv4Addr: {:?}", ipaddr),
-    }
-}
-
-fn get_machines_with_addresses(transaction: &mut Transaction) -> Result<MachinesMap> {
-    let mut machines = HashMap::new();
-    for row in transaction.query(
-        "SELECT hostname, wireguard_ipv4_address, wireguard_ipv6_address, wireguard_port, wireguard_privkey, wireguard_pubkey,
-                ssh_port, ssh_user, added_time, owner, provider_id, provider_reference, networks
-         FROM machines_view", &[]
-    )? {
-        let wireguard_ipv4_address_ipaddr: Option<IpAddr> = row.get(1);
-        let wireguard_ipv6_address_ipaddr: Option<IpAddr> = row.get(2);
-        let wireguard_ipv4_address = wireguard_ipv4_address_ipaddr.map(get_ipv4addr);
-        let wireguard_ipv6_address = wireguard_ipv6_address_ipaddr.map(get_ipv6addr);
-        let machine = Machine {
-            hostname: row.get(0),
-            wireguard_ipv4_address,
-            wireguard_ipv6_address,
-            wireguard_port: row.get(3),
-            wireguard_privkey: row.get(4),
-            wireguard_pubkey: row.get(5),
-            ssh_port: row.get(6),
-            ssh_user: row.get(7),
-            added_time: row.get(8),
-            owner: row.get(9),
-            provider_id: row.get(10),
-            provider_reference: row.get(11),
-            networks: row.get(12),
-            addresses: vec![],
-        };
-        machines.insert(machine.hostname.clone(), machine);
-    }
-    for row in transaction.query(
-        "SELECT hostname, network, address, ssh_port, wireguard_port
-         FROM machine_addresses
-         WHERE hostname = ANY($1)", &[&machines.keys().collect::<Vec<&String>>()]
-    )? {
-        let address = MachineAddress {
-            hostname: row.get(0),
-            network: row.get(1),
-            address: row.get(2),
-            ssh_port: row.get(3),
-            wireguard_port: row.get(4),
-        };
-        let machine = machines
-            .get_mut(&address.hostname)
-            .expect("Database gave us an address for a machine that doesn't exist");
-        machine.addresses.push(address);
+use config::Config;
+use store::Store;
+use store::snapshot::Snapshot;
+use dry_run::DryRun;
+use ipam::{AllocationPolicy, Cidr, IpPool, Ipv6Mode};
+
+/// Load the environment file of `profile` if there is one, then read its
+/// config file with environment variables as overrides
+fn load_config(profile: &str) -> Result<Config> {
+    let env_path = profile::env_path(profile)?;
+    let config_path = profile::config_path(profile)?;
+    if env_path.exists() {
+        dotenv::from_path(&env_path)
+            .with_context(|| format!("Unable to read configuration for profile {:?} from {:?}", profile, &env_path))?;
+    } else if profile != profile::DEFAULT_PROFILE && !config_path.exists() {
+        bail!("Profile {:?} has neither {:?} nor {:?}", profile, config_path, env_path);
     }
-    Ok(machines)
+    Config::load(&config_path, &|var| env::var(var).ok())
+        .with_context(|| format!("Invalid configuration for profile {:?}", profile))
 }
 
 fn print_tabwriter(tw: TabWriter<Vec<u8>>) -> Result<()> {
@@ -169,106 +75,76 @@ fn write_column_names(tw: &mut TabWriter<Vec<u8>>, headers: Vec<&str>) -> Result
     Ok(())
 }
 
-fn list_providers(transaction: &mut Transaction) -> Result<()> {
+fn list_providers(store: &mut dyn Store) -> Result<()> {
     let mut tw = TabWriter::new(vec![]);
     write_column_names(&mut tw, vec!["ID", "NAME", "EMAIL"])?;
-    for row in transaction.query("SELECT id, name, email FROM providers", &[])? {
-        let id: i32 = row.get(0);
-        let name: String = row.get(1);
-        let email: String = row.get(2);
+    for provider in store.get_providers()? {
+        let inventory::Provider { id, name, email } = provider;
         writeln!(tw, "{id}\t{name}\t{email}")?;
     }
     print_tabwriter(tw)
 }
 
-fn list_wireguard_keepalives(transaction: &mut Transaction) -> Result<()> {
+fn list_wireguard_keepalives(store: &mut dyn Store) -> Result<()> {
     let mut tw = TabWriter::new(vec![]);
     write_column_names(&mut tw, vec!["SOURCE", "TARGET", "INTERVAL"])?;
-    for row in transaction.query("SELECT source_machine, target_machine, interval_sec FROM wireguard_keepalives ORDER BY (source_machine, target_machine)", &[])? {
-        let source_machine: String = row.get(0);
-        let target_machine: String = row.get(1);
-        let interval_sec: i32 = row.get(2);
+    for ((source_machine, target_machine), interval_sec) in store.get_wireguard_keepalive_map()?.into_iter().sorted() {
         writeln!(tw, "{source_machine}\t{target_machine}\t{interval_sec}")?;
     }
     print_tabwriter(tw)
 }
 
-fn add_wireguard_keepalive(mut transaction: Transaction, source: &str, target: &str, interval_sec: Option<u16>) -> Result<()> {
+fn add_wireguard_keepalive(mut store: Box<dyn Store + '_>, config: &Config, source: &str, target: &str, interval_sec: Option<u16>) -> Result<()> {
     let interval_sec = unwrap_or_else!(
         interval_sec,
-        env_var("DEFAULT_WIREGUARD_KEEPALIVE_INTERVAL_SEC")?.parse::<u16>()
-            .context("Could not parse DEFAULT_WIREGUARD_KEEPALIVE_INTERVAL_SEC as a u16")?
+        config::require(&config.defaults.wireguard_keepalive_interval_sec, "DEFAULT_WIREGUARD_KEEPALIVE_INTERVAL_SEC")?
     );
-    transaction.execute(
-        "INSERT INTO wireguard_keepalives (source_machine, target_machine, interval_sec)
-         VALUES ($1::varchar, $2::varchar, $3::integer)",
-        &[&source, &target, &i32::from(interval_sec)],
-    )?;
-    transaction.commit()?;
-    Ok(())
+    store.execute_change(&apply::Change::Add(apply::Record::WireguardKeepalive(inventory::WireguardKeepalive {
+        source_machine: source.to_string(),
+        target_machine: target.to_string(),
+        interval_sec: i32::from(interval_sec),
+    })))?;
+    store.commit()
 }
 
-fn remove_wireguard_keepalive(mut transaction: Transaction, source: &str, target: &str) -> Result<()> {
-    let num_deleted = transaction.execute(
-        "DELETE FROM wireguard_keepalives WHERE source_machine = $1 AND target_machine = $2",
-        &[&source, &target],
-    )?;
+fn remove_wireguard_keepalive(mut store: Box<dyn Store + '_>, source: &str, target: &str) -> Result<()> {
+    let num_deleted = store.remove_wireguard_keepalive(source, target)?;
     ensure!(num_deleted == 1, "Could not find keepalive ({:?}, {:?}) in database", source, target);
-    transaction.commit()?;
-    Ok(())
+    store.commit()
 }
 
 fn add_address(
-    mut transaction: Transaction,
+    mut store: Box<dyn Store + '_>,
+    config: &Config,
     hostname: &str,
     network: &str,
     address: &IpAddr,
     ssh_port: Option<u16>,
     wireguard_port: Option<u16>
 ) -> Result<()> {
-    let ssh_port = unwrap_or_else!(
-        ssh_port,
-        env_var("DEFAULT_SSH_PORT")?.parse::<u16>()
-            .context("Could not parse DEFAULT_SSH_PORT as a u16")?
-    );
-    let wireguard_port = unwrap_or_else!(
-        wireguard_port,
-        env_var("DEFAULT_WIREGUARD_PORT")?.parse::<u16>()
-            .context("Could not parse DEFAULT_WIREGUARD_PORT as a u16")?
-    );
-    transaction.execute(
-        "INSERT INTO machine_addresses (hostname, network, address, ssh_port, wireguard_port)
-         VALUES ($1::varchar, $2::varchar, $3::inet, $4::integer, $5::integer)",
-        &[&hostname, &network, &address, &i32::from(ssh_port), &i32::from(wireguard_port)],
-    )?;
-    transaction.commit()?;
-    Ok(())
+    let ssh_port = unwrap_or_else!(ssh_port, config::require(&config.defaults.ssh_port, "DEFAULT_SSH_PORT")?);
+    let wireguard_port = unwrap_or_else!(wireguard_port, config::require(&config.defaults.wireguard_port, "DEFAULT_WIREGUARD_PORT")?);
+    store.execute_change(&apply::Change::Add(apply::Record::MachineAddress(inventory::MachineAddress {
+        hostname: hostname.to_string(),
+        network: network.to_string(),
+        address: *address,
+        ssh_port: Some(i32::from(ssh_port)),
+        wireguard_port: Some(i32::from(wireguard_port)),
+    })))?;
+    store.commit()
 }
 
-fn remove_address(mut transaction: Transaction, hostname: &str, network: &str, address: &IpAddr) -> Result<()> {
-    let num_deleted = transaction.execute(
-        "DELETE FROM machine_addresses WHERE hostname = $1 AND network = $2 AND address = $3",
-        &[&hostname, &network, &address],
-    )?;
+fn remove_address(mut store: Box<dyn Store + '_>, hostname: &str, network: &str, address: &IpAddr) -> Result<()> {
+    let num_deleted = store.remove_address(hostname, network, address)?;
     ensure!(num_deleted == 1, "Could not find address ({:?}, {:?}, {:?}) in database", hostname, network, address);
-    transaction.commit()?;
-    Ok(())
+    store.commit()
 }
 
-fn list_addresses(transaction: &mut Transaction) -> Result<()> {
-    let mut addresses = vec![];
-    let query = 
-        "SELECT machine_addresses.hostname, network, address, ssh_port, wireguard_port FROM machine_addresses
-         JOIN machines ON machines.hostname = machine_addresses.hostname";
-    for row in transaction.query(&*query, &[])? {
-        addresses.push(MachineAddress {
-            hostname: row.get(0),
-            network: row.get(1),
-            address: row.get(2),
-            ssh_port: row.get(3),
-            wireguard_port: row.get(4)
-        });
-    }
+fn list_addresses(store: &mut dyn Store) -> Result<()> {
+    let mut addresses = store.get_machines_with_addresses()?
+        .into_values()
+        .flat_map(|machine| machine.addresses)
+        .collect::<Vec<_>>();
 
     // natural_sort refuses to compare string segments with integer segments,
     // so if returns None, fall back to String cmp.
@@ -292,35 +168,21 @@ fn list_addresses(transaction: &mut Transaction) -> Result<()> {
     print_tabwriter(tw)
 }
 
-/// Convert a MachinesMap to a Vec of &Machine naturally sorted by hostname
-fn get_sorted_machines(machines_map: &MachinesMap) -> Vec<&Machine> {
-    let mut machines = machines_map
-        .iter()
-        .map(|(_, machine)| machine)
-        .collect::<Vec<_>>();
-    // natural_sort refuses to compare string segments with integer segments,
-    // so if returns None, fall back to String cmp.
-    machines.sort_unstable_by(|m1, m2| {
-        HumanStr::new(&m1.hostname)
-            .partial_cmp(&HumanStr::new(&m2.hostname))
-            .unwrap_or_else(|| m1.hostname.cmp(&m2.hostname))
-    });
-    machines
-}
-
 fn write_table_cell<T: ToTableCell>(tw: &mut TabWriter<Vec<u8>>, value: T) -> std::io::Result<()> {
     tw.write_all(value.to_cell().as_bytes())?;
     tw.write_all(b"\t")
 }
 
-fn list_machines(mut transaction: &mut Transaction) -> Result<()> {
-    let machines_map = get_machines_with_addresses(&mut transaction)?;
+/// List machines, including retired ones if `all`
+fn list_machines(store: &mut dyn Store, all: bool) -> Result<()> {
+    let machines_map = store.get_machines_with_addresses()?;
     let machines = get_sorted_machines(&machines_map);
     let mut tw = TabWriter::new(vec![]);
-    let columns = vec!["HOSTNAME", "WG IPV4", "WG IPV6", "OWNER", "PROV", "REFERENCE", "ADDRESSES"];
+    let columns = vec!["HOSTNAME", "STATE", "WG IPV4", "WG IPV6", "OWNER", "PROV", "REFERENCE", "ADDRESSES"];
     write_column_names(&mut tw, columns)?;
-    for machine in machines.into_iter() {
+    for machine in machines.into_iter().filter(|machine| all || machine.state != MachineState::Retired) {
         write_table_cell(&mut tw, &machine.hostname)?;
+        write_table_cell(&mut tw, machine.state.to_string())?;
         write_table_cell(&mut tw, machine.wireguard_ipv4_address)?;
         write_table_cell(&mut tw, machine.wireguard_ipv6_address)?;
         write_table_cell(&mut tw, &machine.owner)?;
@@ -334,137 +196,405 @@ fn list_machines(mut transaction: &mut Transaction) -> Result<()> {
     print_tabwriter(tw)
 }
 
-fn format_nix_address(address: &MachineAddress) -> String {
-    format!("{} = {{ ip = {}; ssh_port = {}; wireguard_port = {}; }}; ",
-            address.network,
-            address.address.to_nix(),
-            address.ssh_port.to_nix(),
-            address.wireguard_port.to_nix()
-    )
+fn nix_data(store: &mut dyn Store) -> Result<()> {
+    let machines_map = store.get_machines_with_addresses()?;
+    let keepalives_map = store.get_wireguard_keepalive_map()?;
+    println!("{}", generate::nix_data(&machines_map, &keepalives_map).to_pretty());
+    Ok(())
 }
 
-fn nix_data(mut transaction: &mut Transaction) -> Result<()> {
-    let machines_map = get_machines_with_addresses(&mut transaction)?;
-    let machines = get_sorted_machines(&machines_map);
+/// Read the whole inventory.  WireGuard private keys are only included if `with_privkeys`.
+fn get_inventory(store: &mut dyn Store, with_privkeys: bool) -> Result<inventory::Inventory> {
+    let owners = store.get_owners()?;
+    let networks = store.get_networks()?;
+    let providers = store.get_providers()?;
+    let network_links = store.get_network_links_priority_map()?
+        .into_iter()
+        .sorted()
+        .map(|((network, other_network), priority)| inventory::NetworkLink { network, other_network, priority })
+        .collect();
+    let ip_pools = store.get_ip_pools()?
+        .into_iter()
+        .map(|pool| inventory::IpPool {
+            name: pool.name,
+            ipv4_cidr: pool.ipv4_cidr,
+            ipv6_cidr: pool.ipv6_cidr,
+            reserved: pool.reserved,
+            policy: pool.policy,
+            ipv6_mode: pool.ipv6_mode,
+        })
+        .collect();
+    let ip_reservations = store.get_ip_reservations()?;
+
+    let machines_map = store.get_machines_with_addresses()?;
+    let mut machines = vec![];
+    let mut machine_addresses = vec![];
+    for machine in get_sorted_machines(&machines_map) {
+        let ssh = match (machine.ssh_port, &machine.ssh_user) {
+            (Some(port), Some(user)) => Some(inventory::SshServer { port, user: user.clone() }),
+            _ => None,
+        };
+        let wireguard = match (machine.wireguard_ipv4_address, machine.wireguard_ipv6_address, machine.wireguard_port, &machine.wireguard_pubkey) {
+            (Some(ipv4_address), Some(ipv6_address), Some(port), Some(pubkey)) => Some(inventory::WireguardInterface {
+                ipv4_address,
+                ipv6_address,
+                port,
+                pubkey: pubkey.clone(),
+                privkey: if with_privkeys { machine.wireguard_privkey.clone() } else { None },
+            }),
+            _ => None,
+        };
+        machines.push(inventory::Machine {
+            hostname: machine.hostname.clone(),
+            added_time: machine.added_time,
+            owner: machine.owner.clone(),
+            state: machine.state,
+            provider_id: machine.provider_id,
+            provider_reference: machine.provider_reference.clone(),
+            ssh,
+            wireguard,
+        });
+        let mut addresses = machine.addresses.iter().collect::<Vec<_>>();
+        addresses.sort_unstable_by(|a1, a2| (&a1.network, a1.address).cmp(&(&a2.network, a2.address)));
+        for address in addresses {
+            machine_addresses.push(inventory::MachineAddress {
+                hostname: address.hostname.clone(),
+                network: address.network.clone(),
+                address: address.address,
+                ssh_port: address.ssh_port,
+                wireguard_port: address.wireguard_port,
+            });
+        }
+    }
 
-    println!("{{");
-    let mut tw = TabWriter::new(vec![]).padding(1);
-    for machine in machines.into_iter() {
-        writeln!(tw, "  {}\t= {{ owner = {};\twireguard_ipv4_address = {};\twireguard_ipv6_address = {};\twireguard_port = {};\tssh_port = {};\tprovider_id = {};\tprovider_reference = {};\taddresses = {{ {}}}; }};",
-                 machine.hostname,
-                 machine.owner.to_nix(),
-                 &machine.wireguard_ipv4_address.to_nix(),
-                 &machine.wireguard_ipv6_address.to_nix(),
-                 machine.wireguard_port.to_nix(),
-                 machine.ssh_port.to_nix(),
-                 &machine.provider_id.to_nix(),
-                 &machine.provider_reference.to_nix(),
-                 machine.addresses.iter().map(format_nix_address).join("")
-        )?;
-    }
-    print_tabwriter(tw)?;
-    println!("}}");
-    Ok(())
+    let mut wireguard_keepalives = store.get_wireguard_keepalive_map()?
+        .into_iter()
+        .map(|((source_machine, target_machine), interval_sec)| inventory::WireguardKeepalive { source_machine, target_machine, interval_sec })
+        .collect::<Vec<_>>();
+    wireguard_keepalives.sort_unstable_by(|k1, k2| (&k1.source_machine, &k1.target_machine).cmp(&(&k2.source_machine, &k2.target_machine)));
+
+    Ok(inventory::Inventory {
+        version: inventory::FORMAT_VERSION,
+        owners,
+        networks,
+        providers,
+        network_links,
+        ip_pools,
+        ip_reservations,
+        machines,
+        machine_addresses,
+        wireguard_keepalives,
+    })
 }
 
-fn print_wireguard_privkey(transaction: &mut Transaction, hostname: &str) -> Result<()> {
-    let rows = transaction.query("SELECT hostname, wireguard_privkey FROM machines_view WHERE hostname = $1", &[&hostname])?;
-    ensure!(!rows.is_empty(), "Could not find machine {:?} in database", hostname);
-    let row = &rows[0];
-    let privkey: Option<&str> = row.get(1);
-    ensure!(privkey.is_some(), "Machine {:?} does not have WireGuard IP", hostname);
-    println!("{}", privkey.unwrap());
+fn export(store: &mut dyn Store, format: inventory::Format, with_privkeys: bool) -> Result<()> {
+    let inventory = get_inventory(store, with_privkeys)?;
+    print!("{}", inventory.serialize(format)?);
     Ok(())
 }
 
-fn get_existing_wireguard_ipv4_addresses(transaction: &mut Transaction) -> Result<impl Iterator<Item=Ipv4Addr>> {
-    let iter = transaction.query("SELECT wireguard_ipv4_address FROM wireguard_interfaces", &[])?
-        .into_iter()
-        .filter_map(|row| {
-            let wireguard_ipaddr: Option<IpAddr> = row.get(0);
-            wireguard_ipaddr.map(get_ipv4addr)
-        });
-    Ok(iter)
+fn save_snapshot(store: &mut dyn Store, config: &Config, path: &Path) -> Result<()> {
+    let inventory = get_inventory(store, true)?;
+    let quarantine_days = config.quarantine_days();
+    let quarantined = store.get_quarantined_wireguard_addresses(quarantine_days)?;
+    Snapshot::new(inventory, quarantine_days, quarantined).save(path)
 }
 
-fn get_existing_wireguard_ipv6_addresses(transaction: &mut Transaction) -> Result<impl Iterator<Item=Ipv6Addr>> {
-    let iter = transaction.query("SELECT wireguard_ipv6_address FROM wireguard_interfaces", &[])?
-        .into_iter()
-        .filter_map(|row| {
-            let wireguard_ipaddr: Option<IpAddr> = row.get(0);
-            wireguard_ipaddr.map(get_ipv6addr)
-        });
-    Ok(iter)
+fn print_wireguard_privkey(store: &mut dyn Store, hostname: &str) -> Result<()> {
+    let machines = store.get_machines_with_addresses()?;
+    let machine = machines.get(hostname).ok_or_else(|| anyhow!("Could not find machine {:?} in database", hostname))?;
+    let privkey = machine.wireguard_privkey.as_ref().ok_or_else(|| anyhow!("Machine {:?} does not have WireGuard IP", hostname))?;
+    println!("{}", privkey);
+    Ok(())
 }
 
-#[allow(clippy::trivially_copy_pass_by_ref)]
-fn increment_ipv4_address(ip: &Ipv4Addr) -> Option<Ipv4Addr> {
-    let mut octets = ip.octets();
-    if octets == [255, 255, 255, 255] {
-        return None;
+/// Find the pool named `name`, or if None, the pool named by `default`, or if
+/// that is None, the only pool in `pools`.
+fn find_ip_pool<'a>(pools: &'a [IpPool], name: Option<String>, default: Option<&str>) -> Result<&'a IpPool> {
+    let name = ok_or_else!(name, default.map(ToString::to_string));
+    match name {
+        Some(name) => {
+            pools.iter().find(|pool| pool.name == name)
+                .ok_or_else(|| anyhow!("Could not find pool {:?} in database", name))
+        }
+        None => {
+            ensure!(pools.len() == 1, "No pool was provided, defaults.pool is not configured, and there is not exactly one pool in the database");
+            Ok(&pools[0])
+        }
     }
-    for i in (0..4).rev() {
-        if octets[i] < 255 {
-            octets[i] += 1;
-            break;
-        } else {
-            octets[i] = 0;
+}
+
+/// Name of the pool made from the range in the ipam config section
+const RANGE_POOL_NAME: &str = "ipam range";
+
+/// Get the pools to allocate WireGuard addresses from: the pools in the
+/// database, or if there are none, a pool made from the range configured with
+/// ipam.wireguard_ipv4_start etc., which is how addresses were allocated before
+/// IP pools existed
+fn get_allocation_pools(store: &mut dyn Store, config: &Config) -> Result<Vec<IpPool>> {
+    let pools = store.get_ip_pools()?;
+    let range = &config.ipam;
+    match (range.wireguard_ipv4_start, range.wireguard_ipv4_end, range.wireguard_ipv6_start, range.wireguard_ipv6_end) {
+        (Some(ipv4_start), Some(ipv4_end), Some(ipv6_start), Some(ipv6_end)) if pools.is_empty() => {
+            Ok(vec![ipam::range_pool(RANGE_POOL_NAME, ipv4_start, ipv4_end, ipv6_start, ipv6_end)])
         }
+        _ => Ok(pools),
     }
-    Some(Ipv4Addr::new(octets[0], octets[1], octets[2], octets[3]))
 }
 
-fn increment_ipv6_address(ip: &Ipv6Addr) -> Option<Ipv6Addr> {
-    let mut segments = ip.segments();
-    if segments == [0xffff, 0xffff, 0xffff, 0xffff, 0xffff, 0xffff, 0xffff, 0xffff] {
-        return None;
+/// Get the pool chosen by `find_ip_pool`, defaulting to the configured pool
+fn get_ip_pool(store: &mut dyn Store, config: &Config, name: Option<String>) -> Result<IpPool> {
+    let pools = get_allocation_pools(store, config)?;
+    Ok(find_ip_pool(&pools, name, config.defaults.pool.as_deref())?.clone())
+}
+
+/// WireGuard addresses that must not be allocated to a new machine
+struct UnavailableAddresses {
+    /// Addresses of existing machines
+    used: HashSet<IpAddr>,
+    /// Recently freed addresses with when their quarantine ends, see
+    /// `get_quarantined_wireguard_addresses`
+    quarantined: HashMap<IpAddr, DateTime<Utc>>,
+    /// Ranges reserved with `ipam reserve`
+    reservations: Vec<Cidr>,
+}
+
+impl UnavailableAddresses {
+    /// `pool` with the reservations added to its reserved ranges
+    fn restrict_pool(&self, pool: &IpPool) -> IpPool {
+        IpPool {
+            reserved: pool.reserved.iter().chain(self.reservations.iter()).copied().collect(),
+            ..pool.clone()
+        }
+    }
+
+    fn quarantined_addresses(&self) -> HashSet<IpAddr> {
+        self.quarantined.keys().copied().collect()
     }
-    for i in (0..8).rev() {
-        if segments[i] < 0xffff {
-            segments[i] += 1;
-            break;
-        } else {
-            segments[i] = 0;
+
+    fn used_or_quarantined(&self) -> HashSet<IpAddr> {
+        self.used.union(&self.quarantined_addresses()).copied().collect()
+    }
+
+    /// Fail if `ip`, given explicitly for a new machine, was freed too recently
+    /// to be handed out again.  An address still in use by a machine is left
+    /// to the check for duplicate addresses.
+    fn check_not_quarantined(&self, ip: IpAddr) -> Result<()> {
+        if let (Some(until), false) = (self.quarantined.get(&ip), self.used.contains(&ip)) {
+            bail!("WireGuard IP {} was freed recently and is quarantined until {}, because peers may still route it to \
+                   the machine that had it; set WIREGUARD_IP_QUARANTINE_DAYS=0 to use it anyway",
+                  ip, until.to_rfc3339_opts(SecondsFormat::Secs, true));
         }
+        Ok(())
     }
-    Some(Ipv6Addr::new(segments[0], segments[1], segments[2], segments[3], segments[4], segments[5], segments[6], segments[7]))
-}
 
-fn get_unused_wireguard_ipv4_address(mut transaction: &mut Transaction, start_ip: Ipv4Addr, end_ip: Ipv4Addr) -> Result<Option<Ipv4Addr>> {
-    let existing = get_existing_wireguard_ipv4_addresses(&mut transaction)?.collect::<HashSet<Ipv4Addr>>();
-    let ip_iter = iter::successors(Some(start_ip), increment_ipv4_address);
-    for proposed_ip in ip_iter {
-        if !existing.contains(&proposed_ip) {
-            return Ok(Some(proposed_ip));
+    /// Pick WireGuard addresses from `pool` for a new machine, allocating whichever
+    /// of `ipv4` and `ipv6` is None.  Addresses in use, recently freed addresses,
+    /// and reserved addresses are never allocated, and recently freed addresses
+    /// cannot be given explicitly either.
+    fn allocate(&self, pool: &IpPool, ipv4: Option<Ipv4Addr>, ipv6: Option<Ipv6Addr>) -> Result<(Ipv4Addr, Ipv6Addr)> {
+        let pool = self.restrict_pool(pool);
+        for ip in ipv4.map(IpAddr::V4).into_iter().chain(ipv6.map(IpAddr::V6)) {
+            if let Some(cidr) = pool.reserved.iter().find(|cidr| cidr.contains(ip)) {
+                bail!("WireGuard IP {} is reserved by {}", ip, cidr);
+            }
+            self.check_not_quarantined(ip)?;
         }
-        if proposed_ip == end_ip {
-            break;
+        pool.allocate(ipv4, ipv6, &self.used_or_quarantined())
+    }
+}
+
+fn get_unavailable_wireguard_addresses(store: &mut dyn Store, config: &Config) -> Result<UnavailableAddresses> {
+    let quarantine_days = config.quarantine_days();
+    let used = store.get_machines_with_addresses()?
+        .values()
+        .flat_map(|machine| {
+            machine.wireguard_ipv4_address.map(IpAddr::V4).into_iter()
+                .chain(machine.wireguard_ipv6_address.map(IpAddr::V6))
+        })
+        .collect::<HashSet<IpAddr>>();
+    let quarantined = store.get_quarantined_wireguard_addresses(quarantine_days)?;
+    let reservations = store.get_ip_reservations()?.into_iter().map(|reservation| reservation.cidr).collect();
+    Ok(UnavailableAddresses { used, quarantined, reservations })
+}
+
+/// Pick WireGuard addresses from `pool` for a new machine, see `UnavailableAddresses::allocate`
+fn get_unused_wireguard_addresses(
+    store: &mut dyn Store,
+    config: &Config,
+    pool: &IpPool,
+    ipv4: Option<Ipv4Addr>,
+    ipv6: Option<Ipv6Addr>,
+) -> Result<(Ipv4Addr, Ipv6Addr)> {
+    get_unavailable_wireguard_addresses(store, config)?.allocate(pool, ipv4, ipv6)
+}
+
+/// Print the utilization of each pool
+fn list_ipam(store: &mut dyn Store, config: &Config) -> Result<()> {
+    let pools = store.get_ip_pools()?;
+    let unavailable = get_unavailable_wireguard_addresses(store, config)?;
+    let used_or_quarantined = unavailable.used_or_quarantined();
+    let quarantined = unavailable.quarantined_addresses();
+
+    let mut tw = TabWriter::new(vec![]);
+    let columns = vec!["POOL", "CIDR", "SIZE", "USED", "RESERVED", "QUARANTINED", "FREE", "FREE RANGES", "LARGEST FREE", "NEXT"];
+    write_column_names(&mut tw, columns)?;
+    for pool in &pools {
+        let pool = unavailable.restrict_pool(pool);
+        let next = pool.allocate(None, None, &used_or_quarantined).ok();
+        let rows = vec![
+            (&pool.ipv4_cidr, next.map(|(ipv4, _)| IpAddr::V4(ipv4))),
+            (&pool.ipv6_cidr, next.map(|(_, ipv6)| IpAddr::V6(ipv6))),
+        ];
+        for (cidr, next) in rows {
+            let u = unwrap_or_else!(
+                ipam::utilization(cidr, &pool.reserved, &unavailable.used, &quarantined),
+                continue
+            );
+            let name = &pool.name;
+            let next = next.to_cell();
+            writeln!(tw, "{name}\t{cidr}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{next}",
+                     u.size, u.used, u.reserved, u.quarantined, u.free, u.free_ranges, u.largest_free_range)?;
         }
     }
-    Ok(None)
+    print_tabwriter(tw)
 }
 
-fn get_unused_wireguard_ipv6_address(mut transaction: &mut Transaction, start_ip: Ipv6Addr, end_ip: Ipv6Addr) -> Result<Option<Ipv6Addr>> {
-    let existing = get_existing_wireguard_ipv6_addresses(&mut transaction)?.collect::<HashSet<Ipv6Addr>>();
-    let ip_iter = iter::successors(Some(start_ip), increment_ipv6_address);
-    for proposed_ip in ip_iter {
-        if !existing.contains(&proposed_ip) {
-            return Ok(Some(proposed_ip));
+fn list_ip_reservations(store: &mut dyn Store) -> Result<()> {
+    let mut tw = TabWriter::new(vec![]);
+    write_column_names(&mut tw, vec!["CIDR", "ADDED", "REASON"])?;
+    for reservation in store.get_ip_reservations()? {
+        let cidr = reservation.cidr;
+        let added_time = reservation.added_time.format("%Y-%m-%d");
+        let reason = reservation.reason.to_cell();
+        writeln!(tw, "{cidr}\t{added_time}\t{reason}")?;
+    }
+    print_tabwriter(tw)
+}
+
+fn reserve_ip_range(mut store: Box<dyn Store + '_>, cidr: &Cidr, reason: Option<String>) -> Result<()> {
+    store.reserve_ip_range(cidr, reason.as_deref())?;
+    store.commit()
+}
+
+fn release_ip_range(mut store: Box<dyn Store + '_>, cidr: &Cidr) -> Result<()> {
+    let num_deleted = store.release_ip_range(cidr)?;
+    ensure!(num_deleted == 1, "Could not find reservation {} in database", cidr);
+    store.commit()
+}
+
+/// Report machines whose WireGuard IPv6 address does not follow the IPv4 -> IPv6
+/// mapping of the pool their IPv4 address is in
+fn check_ipam(store: &mut dyn Store) -> Result<()> {
+    let pools = store.get_ip_pools()?;
+    let machines_map = store.get_machines_with_addresses()?;
+    let machines = get_sorted_machines(&machines_map);
+    let mut mismatches = 0;
+    let mut tw = TabWriter::new(vec![]);
+    write_column_names(&mut tw, vec!["HOSTNAME", "POOL", "WG IPV4", "WG IPV6", "EXPECTED IPV6"])?;
+    for machine in machines.into_iter() {
+        let (ipv4, ipv6) = match (machine.wireguard_ipv4_address, machine.wireguard_ipv6_address) {
+            (Some(ipv4), Some(ipv6)) => (ipv4, ipv6),
+            _ => continue,
+        };
+        for pool in pools.iter().filter(|pool| pool.ipv4_cidr.contains(IpAddr::V4(ipv4))) {
+            if let Some(expected) = pool.expected_ipv6_address(ipv4)? {
+                if expected != ipv6 {
+                    let hostname = &machine.hostname;
+                    let pool_name = &pool.name;
+                    writeln!(tw, "{hostname}\t{pool_name}\t{ipv4}\t{ipv6}\t{expected}")?;
+                    mismatches += 1;
+                }
+            }
         }
-        if proposed_ip == end_ip {
-            break;
+    }
+    if mismatches > 0 {
+        print_tabwriter(tw)?;
+    }
+    ensure!(mismatches == 0, "{} machine(s) do not follow the IPv4 -> IPv6 mapping of their pool", mismatches);
+    Ok(())
+}
+
+/// Print the problems that `check::check` finds, failing if any of them are errors
+fn check_inventory(store: &mut dyn Store) -> Result<()> {
+    let machines_map = store.get_machines_with_addresses()?;
+    let network_links_priority_map = store.get_network_links_priority_map()?;
+    let keepalives_map = store.get_wireguard_keepalive_map()?;
+    let networks = store.get_networks()?;
+    let get_pubkey = |privkey: &str| Ok(String::from_utf8(wireguard::get_pubkey(privkey.as_bytes()).context("Could not run `wg pubkey`")?)?);
+    let problems = check::check(&machines_map, &network_links_priority_map, &keepalives_map, &networks, &get_pubkey)?;
+    for problem in &problems {
+        println!("{}: {}", problem.severity, problem.message);
+        for row in &problem.rows {
+            println!("    {row}");
         }
     }
-    Ok(None)
+    let errors = problems.iter().filter(|problem| problem.severity == check::Severity::Error).count();
+    let warnings = problems.len() - errors;
+    ensure!(errors == 0, "Found {} error(s) and {} warning(s)", errors, warnings);
+    if warnings > 0 {
+        println!("Found {warnings} warning(s)");
+    } else {
+        println!("No problems found");
+    }
+    Ok(())
+}
+
+fn list_ip_pools(store: &mut dyn Store) -> Result<()> {
+    let mut tw = TabWriter::new(vec![]);
+    write_column_names(&mut tw, vec!["NAME", "IPV4", "IPV6", "POLICY", "IPV6 MODE", "RESERVED"])?;
+    for pool in store.get_ip_pools()? {
+        let name = &pool.name;
+        let ipv4_cidr = &pool.ipv4_cidr;
+        let ipv6_cidr = &pool.ipv6_cidr;
+        let policy = &pool.policy;
+        let ipv6_mode = &pool.ipv6_mode;
+        let reserved = pool.reserved.iter().join(" ");
+        writeln!(tw, "{name}\t{ipv4_cidr}\t{ipv6_cidr}\t{policy}\t{ipv6_mode}\t{reserved}")?;
+    }
+    print_tabwriter(tw)
+}
+
+fn add_ip_pool(
+    mut store: Box<dyn Store + '_>,
+    name: &str,
+    ipv4_cidr: &Cidr,
+    ipv6_cidr: &Cidr,
+    reserved: &[Cidr],
+    policy: AllocationPolicy,
+    ipv6_mode: Ipv6Mode,
+) -> Result<()> {
+    ensure!(ipv4_cidr.is_ipv4(), "{} is not an IPv4 network", ipv4_cidr);
+    ensure!(!ipv6_cidr.is_ipv4(), "{} is not an IPv6 network", ipv6_cidr);
+    if ipv6_mode == Ipv6Mode::EmbedIpv4 {
+        ipam::check_embeddable(ipv4_cidr, ipv6_cidr)?;
+    }
+    for cidr in reserved {
+        let pool_cidr = if cidr.is_ipv4() { ipv4_cidr } else { ipv6_cidr };
+        ensure!(pool_cidr.contains(cidr.first()) && pool_cidr.contains(cidr.last()),
+                "Reserved range {} is not inside {}", cidr, pool_cidr);
+    }
+    store.add_ip_pool(&IpPool {
+        name: name.to_string(),
+        ipv4_cidr: *ipv4_cidr,
+        ipv6_cidr: *ipv6_cidr,
+        reserved: reserved.to_vec(),
+        policy,
+        ipv6_mode,
+    })?;
+    store.commit()
 }
 
-fn env_var(var: &str) -> Result<String> {
-    env::var(var).with_context(|| anyhow!("Could not get variable {:?} from environment", var))
+fn remove_ip_pool(mut store: Box<dyn Store + '_>, name: &str) -> Result<()> {
+    let num_deleted = store.remove_ip_pool(name)?;
+    ensure!(num_deleted == 1, "Could not find pool {:?} in database", name);
+    store.commit()
 }
 
 #[allow(clippy::too_many_arguments)]
 fn add_machine(
-    mut transaction: Transaction,
+    mut store: Box<dyn Store + '_>,
+    config: &Config,
     hostname: &str,
     owner: Option<String>,
     ssh_port: Option<u16>,
@@ -474,322 +604,783 @@ fn add_machine(
     wireguard_port: Option<u16>,
     provider: Option<i32>,
     provider_reference: Option<String>,
+    pool: Option<String>,
+    state: MachineState,
 ) -> Result<()> {
-    // Required environmental variables
-    let ipv4_start = env_var("WIREGUARD_IPV4_START")?.parse::<Ipv4Addr>().context("Could not parse WIREGUARD_IPV4_START as an Ipv4Addr")?;
-    let ipv4_end   = env_var("WIREGUARD_IPV4_END")  ?.parse::<Ipv4Addr>().context("Could not parse WIREGUARD_IPV4_END as an Ipv4Addr")?;
-    let ipv6_start = env_var("WIREGUARD_IPV6_START")?.parse::<Ipv6Addr>().context("Could not parse WIREGUARD_IPV6_START as an Ipv6Addr")?;
-    let ipv6_end   = env_var("WIREGUARD_IPV6_END")  ?.parse::<Ipv6Addr>().context("Could not parse WIREGUARD_IPV6_END as an Ipv6Addr")?;
-
-    // Optional environmental variables
+    let defaults = &config.defaults;
     let ssh_port = unwrap_or_else!(
         ssh_port,
-        env_var("DEFAULT_SSH_PORT")
-            .context("No SSH user was provided, and could not get variable \"DEFAULT_SSH_PORT\" from environment")?
-            .parse::<u16>()
-            .context("No SSH port was provided, and could not parse DEFAULT_SSH_PORT as a u16")?
+        config::require(&defaults.ssh_port, "DEFAULT_SSH_PORT").context("No SSH port was provided")?
     );
     let ssh_user = unwrap_or_else!(
         ssh_user,
-        env_var("DEFAULT_SSH_USER")
-            .context("No SSH user was provided, and could not get variable \"DEFAULT_SSH_USER\" from environment")?
+        config::require(&defaults.ssh_user, "DEFAULT_SSH_USER").context("No SSH user was provided")?
     );
     let wireguard_port = unwrap_or_else!(
         wireguard_port,
-        env_var("DEFAULT_WIREGUARD_PORT")
-            .context("No WireGuard port was provided, and could not get variable \"DEFAULT_WIREGUARD_PORT\" from environment")?
-            .parse::<u16>()
-            .context("No WireGuard port was provided, and could not parse DEFAULT_WIREGUARD_PORT as a u16")?
+        config::require(&defaults.wireguard_port, "DEFAULT_WIREGUARD_PORT").context("No WireGuard port was provided")?
     );
     let owner = unwrap_or_else!(
         owner,
-        env_var("DEFAULT_OWNER")
-            .context("No owner was provided, and could not get variable \"DEFAULT_OWNER\" from environment")?
+        config::require(&defaults.owner, "DEFAULT_OWNER").context("No owner was provided")?
     );
-    let provider_id = ok_or_else!(
-        provider,
-        match env_var("DEFAULT_PROVIDER") {
-            Ok(s) => Some(s.parse::<i32>().context("Could not parse DEFAULT_PROVIDER as an i32")?),
-            Err(_) => None,
+    let provider_id = ok_or_else!(provider, defaults.provider);
+
+    let pool = get_ip_pool(&mut *store, config, pool)?;
+    store.lock_wireguard_addresses()?;
+    let (wireguard_ipv4_address, wireguard_ipv6_address) =
+        get_unused_wireguard_addresses(&mut *store, config, &pool, wireguard_ipv4_address, wireguard_ipv6_address)?;
+    let keypair = wireguard::generate_keypair()?;
+
+    store.execute_change(&apply::Change::Add(apply::Record::Machine(inventory::Machine {
+        hostname: hostname.to_string(),
+        added_time: Utc::now(),
+        owner,
+        state,
+        provider_id,
+        provider_reference,
+        ssh: Some(inventory::SshServer { port: i32::from(ssh_port), user: ssh_user }),
+        wireguard: Some(inventory::WireguardInterface {
+            ipv4_address: wireguard_ipv4_address,
+            ipv6_address: wireguard_ipv6_address,
+            port: i32::from(wireguard_port),
+            pubkey: String::from_utf8(keypair.pubkey)?,
+            privkey: Some(String::from_utf8(keypair.privkey)?),
+        }),
+    })))?;
+    store.commit()
+}
+
+/// Remove a machine and everything that refers to it, leaving only its history
+fn remove_machine(mut store: Box<dyn Store + '_>, hostname: &str) -> Result<()> {
+    ensure!(store.get_machines_with_addresses()?.contains_key(hostname), "Could not find machine {:?} in database", hostname);
+    store.remove_machine(hostname)?;
+    store.commit()
+}
+
+/// Put a removed machine back as it was in the history, after checking that
+/// nothing took or reserved its WireGuard addresses or took its ports on its
+/// addresses since.  Its own addresses are quarantined, which does not apply
+/// to the machine they were freed from.
+fn undelete_machine(mut store: Box<dyn Store + '_>, config: &Config, hostname: &str, as_of: Option<DateTime<Utc>>) -> Result<()> {
+    store.lock_wireguard_addresses()?;
+    let machines = store.get_machines_with_addresses()?;
+    ensure!(!machines.contains_key(hostname), "Machine {:?} is in the inventory; only removed machines can be undeleted", hostname);
+    let removed = match (store.get_removed_machine(hostname, as_of)?, as_of) {
+        (Some(removed), _) => removed,
+        (None, Some(as_of)) => bail!("Could not find machine {:?} in history as of {}", hostname, as_of.to_rfc3339_opts(chrono::SecondsFormat::AutoSi, true)),
+        (None, None) => bail!("Could not find machine {:?} in history", hostname),
+    };
+
+    let mut problems = vec![];
+    let machine = &removed.machine;
+    if !store.get_owners()?.contains(&machine.owner) {
+        problems.push(format!("owner {:?} does not exist", machine.owner));
+    }
+    if let Some(provider_id) = machine.provider_id {
+        if !store.get_providers()?.iter().any(|provider| provider.id == provider_id) {
+            problems.push(format!("provider {provider_id} does not exist"));
         }
-    );
+    }
+    if let Some(wireguard) = &machine.wireguard {
+        let unavailable = get_unavailable_wireguard_addresses(&mut *store, config)?;
+        let pools = get_allocation_pools(&mut *store, config)?;
+        let reserved = pools.iter().flat_map(|pool| &pool.reserved).chain(&unavailable.reservations).collect::<Vec<_>>();
+        for ip in &[IpAddr::V4(wireguard.ipv4_address), IpAddr::V6(wireguard.ipv6_address)] {
+            if let Some(cidr) = reserved.iter().find(|cidr| cidr.contains(*ip)) {
+                problems.push(format!("WireGuard address {ip} is reserved by {cidr}"));
+            }
+        }
+        for other in get_sorted_machines(&machines) {
+            if other.wireguard_ipv4_address == Some(wireguard.ipv4_address) {
+                problems.push(format!("WireGuard address {} is used by {}", wireguard.ipv4_address, other.hostname));
+            }
+            if other.wireguard_ipv6_address == Some(wireguard.ipv6_address) {
+                problems.push(format!("WireGuard address {} is used by {}", wireguard.ipv6_address, other.hostname));
+            }
+        }
+    }
+    let networks = store.get_networks()?;
+    for address in &removed.addresses {
+        if !networks.contains(&address.network) {
+            problems.push(format!("network {:?} does not exist", address.network));
+        }
+        for other in get_sorted_machines(&machines).into_iter().flat_map(|machine| &machine.addresses) {
+            if other.address != address.address {
+                continue;
+            }
+            if let Some(port) = address.ssh_port.filter(|port| other.ssh_port == Some(*port)) {
+                problems.push(format!("SSH port {} on {} is used by {}", port, address.address, other.hostname));
+            }
+            if let Some(port) = address.wireguard_port.filter(|port| other.wireguard_port == Some(*port)) {
+                problems.push(format!("WireGuard port {} on {} is used by {}", port, address.address, other.hostname));
+            }
+        }
+    }
+    ensure!(problems.is_empty(), "Cannot undelete {:?}:\n  {}", hostname, problems.join("\n  "));
 
-    let wireguard_ipv4_address = match wireguard_ipv4_address {
-        Some(ip) => ip,
-        None => {
-            get_unused_wireguard_ipv4_address(&mut transaction, ipv4_start, ipv4_end)?
-                .context("Could not find an unused WireGuard IPv4 address between WIREGUARD_IPV4_START and WIREGUARD_IPV4_END")?
+    println!("Undeleting {hostname} as of {}", removed.as_of.to_rfc3339_opts(chrono::SecondsFormat::AutoSi, true));
+    store.execute_change(&apply::Change::Add(apply::Record::Machine(removed.machine)))?;
+    for address in removed.addresses {
+        store.execute_change(&apply::Change::Add(apply::Record::MachineAddress(address)))?;
+    }
+    for keepalive in removed.keepalives {
+        let other = if keepalive.source_machine == hostname { &keepalive.target_machine } else { &keepalive.source_machine };
+        if other != hostname && !machines.contains_key(other) {
+            eprintln!("Not restoring keepalive {} -> {}, because {other} is not in the inventory", keepalive.source_machine, keepalive.target_machine);
+            continue;
         }
+        store.execute_change(&apply::Change::Add(apply::Record::WireguardKeepalive(keepalive)))?;
+    }
+    store.commit()
+}
+
+fn set_machine_state(mut store: Box<dyn Store + '_>, hostname: &str, state: MachineState) -> Result<()> {
+    ensure!(store.get_machines_with_addresses()?.contains_key(hostname), "Could not find machine {:?} in database", hostname);
+    store.set_machine_state(hostname, state)?;
+    store.commit()
+}
+
+/// Get the defaults for new machines in `i apply`, the same ones `i add` uses
+fn get_machine_defaults(config: &Config) -> apply::Defaults {
+    let defaults = &config.defaults;
+    apply::Defaults {
+        owner: defaults.owner.clone(),
+        provider_id: defaults.provider,
+        ssh_port: defaults.ssh_port.map(i32::from),
+        ssh_user: defaults.ssh_user.clone(),
+        wireguard_port: defaults.wireguard_port.map(i32::from),
+    }
+}
+
+/// Allocates WireGuard addresses like `i add`, remembering what it already
+/// allocated so that no address is handed out twice
+struct WireguardAllocator {
+    pools: Vec<IpPool>,
+    default_pool: Option<String>,
+    unavailable: UnavailableAddresses,
+}
+
+impl apply::Allocator for WireguardAllocator {
+    fn allocate_addresses(&mut self, pool: Option<&str>, ipv4: Option<Ipv4Addr>, ipv6: Option<Ipv6Addr>) -> Result<(Ipv4Addr, Ipv6Addr)> {
+        let pool = find_ip_pool(&self.pools, pool.map(ToString::to_string), self.default_pool.as_deref())?;
+        let (ipv4, ipv6) = self.unavailable.allocate(pool, ipv4, ipv6)?;
+        self.unavailable.used.insert(IpAddr::V4(ipv4));
+        self.unavailable.used.insert(IpAddr::V6(ipv6));
+        Ok((ipv4, ipv6))
+    }
+
+    fn generate_keypair(&mut self) -> Result<(String, String)> {
+        let keypair = wireguard::generate_keypair()?;
+        Ok((String::from_utf8(keypair.privkey)?, String::from_utf8(keypair.pubkey)?))
+    }
+
+    fn get_pubkey(&mut self, privkey: &str) -> Result<String> {
+        Ok(String::from_utf8(wireguard::get_pubkey(privkey.as_bytes())?)?)
+    }
+}
+
+/// Ask a yes/no question on the terminal, defaulting to no
+fn confirm(question: &str) -> Result<bool> {
+    print!("{question} [y/N] ");
+    std::io::stdout().flush()?;
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+/// Print the changes needed to make the inventory match the file at `path`,
+/// and make them in one transaction once confirmed
+fn apply(mut store: Box<dyn Store + '_>, config: &Config, path: &Path, yes: bool) -> Result<()> {
+    let format = inventory::Format::from_path(path)?;
+    let text = fs::read_to_string(path).with_context(|| format!("Could not read {:?}", path))?;
+    let desired: apply::Desired = format.parse(&text).with_context(|| format!("Could not parse {:?}", path))?;
+
+    store.lock_wireguard_addresses()?;
+    let current = get_inventory(&mut *store, true)?;
+    let mut unavailable = get_unavailable_wireguard_addresses(&mut *store, config)?;
+    let requested = desired.requested_wireguard_addresses();
+    for ip in requested.iter().sorted() {
+        unavailable.check_not_quarantined(*ip)?;
+    }
+    unavailable.used.extend(requested);
+    let mut allocator = WireguardAllocator {
+        pools: get_allocation_pools(&mut *store, config)?,
+        default_pool: config.defaults.pool.clone(),
+        unavailable,
     };
-    let wireguard_ipv6_address = match wireguard_ipv6_address {
-        Some(ip) => ip,
-        None => {
-            get_unused_wireguard_ipv6_address(&mut transaction, ipv6_start, ipv6_end)?
-                .context("Could not find an unused WireGuard IPv6 address between WIREGUARD_IPV6_START and WIREGUARD_IPV6_END")?
+    let target = desired.resolve(&current, &get_machine_defaults(config), &mut allocator)?;
+    let changes = apply::plan(&current, &target);
+    if changes.is_empty() {
+        println!("No changes");
+        return Ok(());
+    }
+
+    for change in &changes {
+        let marker = if change.is_destructive() { "!" } else { " " };
+        println!("{marker} {change}");
+    }
+    let count = |f: fn(&&apply::Change) -> bool| changes.iter().filter(f).count();
+    let added = count(|c| matches!(c, apply::Change::Add(_)));
+    let updated = count(|c| matches!(c, apply::Change::Update(..)));
+    let removed = count(|c| matches!(c, apply::Change::Remove(_)));
+    let destructive = count(|c| c.is_destructive());
+    println!("\nPlan: {added} to add, {updated} to change, {removed} to remove; {destructive} destructive (marked with !)");
+
+    if !yes && !confirm("Apply these changes?")? {
+        println!("Not applying changes");
+        return Ok(());
+    }
+    for change in &changes {
+        store.execute_change(change).with_context(|| format!("Could not apply {change}"))?;
+    }
+    store.commit()
+}
+
+/// Get the tables to back up: every table in `backup::TABLES`, followed by
+/// their history tables.  Fails if the database has a table we do not know
+/// about, so that a dump is never silently incomplete.
+fn get_backup_tables(transaction: &mut Transaction) -> Result<Vec<String>> {
+    let existing = transaction.query(
+        "SELECT table_name::text FROM information_schema.tables
+         WHERE table_schema = current_schema() AND table_type = 'BASE TABLE'", &[]
+    )?
+        .into_iter()
+        .map(|row| row.get(0))
+        .collect::<HashSet<String>>();
+    let mut tables = vec![];
+    for table in backup::TABLES {
+        ensure!(existing.contains(*table), "Could not find table {:?} in database", table);
+        tables.push(table.to_string());
+    }
+    for table in backup::TABLES {
+        let history = format!("{table}_history");
+        if existing.contains(&history) {
+            tables.push(history);
         }
-    };
-    let keypair = wireguard::generate_keypair()?;
+    }
+    let unknown = existing.iter()
+        .filter(|table| !tables.contains(table) && table.as_str() != "schema_migrations")
+        .sorted()
+        .collect::<Vec<_>>();
+    ensure!(unknown.is_empty(), "Database has tables that infrabase does not know how to back up: {:?}", unknown);
+    Ok(tables)
+}
 
-    transaction.execute(
-        "INSERT INTO machines (hostname, owner, provider_id, provider_reference)
-                VALUES ($1::varchar, $2::varchar, $3, $4)",
-        &[&hostname, &owner, &provider_id, &provider_reference]
-    )?;
-    transaction.execute(
-        "INSERT INTO ssh_servers (hostname, ssh_port, ssh_user)
-                VALUES ($1::varchar, $2::integer, $3::varchar)",
-        &[&hostname, &i32::from(ssh_port), &ssh_user]
-    )?;
-    transaction.execute(
-        "INSERT INTO wireguard_interfaces (hostname, wireguard_ipv4_address, wireguard_ipv6_address, wireguard_port, wireguard_privkey, wireguard_pubkey)
-                VALUES ($1::varchar, $2::inet, $3::inet, $4::integer, $5::varchar, $6::varchar)",
-        &[&hostname, &IpAddr::V4(wireguard_ipv4_address), &IpAddr::V6(wireguard_ipv6_address), &i32::from(wireguard_port), &str::from_utf8(&keypair.privkey).unwrap(), &str::from_utf8(&keypair.pubkey).unwrap()]
-    )?;
-    transaction.commit()?;
+fn get_table_columns(transaction: &mut Transaction, table: &str) -> Result<Vec<String>> {
+    let columns = transaction.query(
+        "SELECT column_name::text FROM information_schema.columns
+         WHERE table_schema = current_schema() AND table_name = $1
+         ORDER BY ordinal_position", &[&table]
+    )?
+        .into_iter()
+        .map(|row| row.get(0))
+        .collect();
+    Ok(columns)
+}
 
+/// Write every row of every table, including history, to `path`
+fn dump(transaction: &mut Transaction, path: &Path) -> Result<()> {
+    let mut tables = vec![];
+    for name in get_backup_tables(transaction)? {
+        let rows = transaction.query(&*format!("SELECT row_to_json(t)::text AS row FROM {name} t ORDER BY row"), &[])?
+            .into_iter()
+            .map(|row| Ok(serde_json::from_str(row.get(0))?))
+            .collect::<Result<Vec<serde_json::Value>>>()?;
+        tables.push(backup::TableDump { name, rows });
+    }
+    let schema_version = migrations::get_schema_version(transaction)?
+        .ok_or_else(|| anyhow!("The database schema has no version"))?;
+    let dump = backup::Dump::new(schema_version, tables);
+    fs::write(path, serde_json::to_string_pretty(&dump)? + "\n")
+        .with_context(|| format!("Could not write {:?}", path))?;
     Ok(())
 }
 
-fn remove_machine(mut transaction: Transaction, hostname: &str) -> Result<()> {
-    transaction.execute("call remove_machine($1)", &[&hostname])?;
+/// Load a file written by `dump`.  Refuses to touch a schema that already has
+/// rows unless `force`, in which case everything is deleted first.
+fn restore(mut transaction: Transaction, path: &Path, force: bool) -> Result<()> {
+    let text = fs::read_to_string(path).with_context(|| format!("Could not read {:?}", path))?;
+    let dump = backup::Dump::parse(&text).with_context(|| format!("Could not parse {:?}", path))?;
+    let schema_version = migrations::get_schema_version(&mut transaction)?
+        .ok_or_else(|| anyhow!("The database schema has no version"))?;
+    ensure!(dump.schema_version == schema_version,
+        "Dump is from schema version {}, but the database is at version {}; restore into a database at the same version, then run `i db migrate`",
+        dump.schema_version, schema_version);
+    let tables = get_backup_tables(&mut transaction)?;
+    dump.check_tables(&tables)?;
+    for table in &dump.tables {
+        table.check_columns(&get_table_columns(&mut transaction, &table.name)?)?;
+    }
+    for (table, hostname, privkey, pubkey) in dump.wireguard_keys()? {
+        let derived = wireguard::get_pubkey(privkey.as_bytes())?;
+        ensure!(derived == pubkey.as_bytes(), "WireGuard pubkey of {:?} in {} does not match its privkey", hostname, table);
+    }
+
+    let mut non_empty = vec![];
+    for table in &tables {
+        let has_rows: bool = transaction.query_one(&*format!("SELECT EXISTS (SELECT 1 FROM {table})"), &[])?.get(0);
+        if has_rows {
+            non_empty.push(table.as_str());
+        }
+    }
+    ensure!(non_empty.is_empty() || force,
+        "Refusing to restore into a schema that is not empty (tables with rows: {}); use --force to delete everything first",
+        non_empty.join(", "));
+
+    // Disable the periods triggers so that row_start and row_end are restored
+    // as they were instead of being set to now()
+    for table in &tables {
+        transaction.execute(&*format!("ALTER TABLE {table} DISABLE TRIGGER USER"), &[])?;
+    }
+    for table in tables.iter().rev() {
+        transaction.execute(&*format!("DELETE FROM {table}"), &[])?;
+    }
+    let mut num_rows = 0;
+    for table in &tables {
+        let rows = &dump.table(table).expect("check_tables ensures every table is in the dump").rows;
+        if !rows.is_empty() {
+            transaction.execute(
+                &*format!("INSERT INTO {table} SELECT * FROM json_populate_recordset(NULL::{table}, $1::text::json)"),
+                &[&serde_json::to_string(rows)?]
+            )?;
+            num_rows += rows.len();
+        }
+    }
+    for table in &tables {
+        transaction.execute(&*format!("ALTER TABLE {table} ENABLE TRIGGER USER"), &[])?;
+    }
+    store::reset_provider_ids(&mut transaction)?;
     transaction.commit()?;
+    println!("Restored {num_rows} rows into {} tables", tables.len());
     Ok(())
 }
 
-/// Return a Vec of (source_network, dest_network) pairs appropriate for
-/// establishing a connection to `addresses`, highest priority first
-fn get_network_to_network(
-    network_links_priority_map: &NetworkLinksPriorityMap,
-    source_networks: &[String],
-    addresses: &[MachineAddress],
-) -> Vec<(String, String)> {
-    // Convert because we need Strings in our return
-    let source_networks = source_networks.iter().map(String::from).collect::<Vec<_>>();
-
-    // Networks the destination machine is on
-    let dest_networks = addresses.iter().map(|a| a.network.clone()).collect::<Vec<_>>();
-
-    // (source, dest) network pairs
-    let mut network_to_network = iproduct!(source_networks, dest_networks)
-        .filter(|(s, d)| network_links_priority_map.contains_key(&(s.to_string(), d.to_string())))
-        .collect::<Vec<(String, String)>>();
-    network_to_network.sort_unstable_by_key(|(s, d)| network_links_priority_map.get(&(s.to_string(), d.to_string())).unwrap());
-    network_to_network
-}
-
-fn print_ssh_config(mut transaction: &mut Transaction, for_machine: &str) -> Result<()> {
-    let machines_map = get_machines_with_addresses(&mut transaction)?;
-    let source_machine =
-        &machines_map.get(for_machine)
-        .ok_or_else(|| anyhow!("machines_map missing {}", for_machine))?;
-    let network_links_priority_map = get_network_links_priority_map(&mut transaction)?;
-    let machines = get_sorted_machines(&machines_map);
-
-    println!("# infrabase-generated SSH config for {for_machine}\n");
+fn db_init(database: &mut store::Database, schema: &str) -> Result<()> {
+    match database {
+        store::Database::Sqlite(connection) => {
+            store::sqlite::init(connection)?;
+            println!("Created SQLite database at version {}", store::sqlite::SCHEMA_VERSION);
+        }
+        store::Database::Postgres(_) | store::Database::Snapshot(_) => {
+            let mut transaction = database.postgres_unchecked(schema, "db init")?;
+            migrations::init(&mut transaction, schema)?;
+            transaction.commit()?;
+            println!("Created schema {schema} at version {}", migrations::latest_version());
+        }
+    }
+    Ok(())
+}
 
-    for machine in machines.into_iter() {
-        let network_to_network = get_network_to_network(&network_links_priority_map, &source_machine.networks, &machine.addresses);
-        let (address, ssh_port) = match network_to_network.get(0) {
-            None => {
-                // We prefer to SSH over the non-WireGuard IP because WireGuard may be down,
-                // but in cases where there is no reachable address, use the WireGuard IP instead.
-                (machine.wireguard_ipv4_address.map(IpAddr::V4), machine.ssh_port)
-            },
-            Some((_, dest_network)) => {
-                let desired_address = machine.addresses.iter().find(|a| a.network == **dest_network).unwrap();
-                (Some(desired_address.address), desired_address.ssh_port)
+fn db_migrate(database: &mut store::Database, schema: &str) -> Result<()> {
+    match database {
+        store::Database::Sqlite(connection) => {
+            let applied = store::sqlite::migrate(connection)?;
+            if applied.is_empty() {
+                println!("SQLite database is up to date at version {}", store::sqlite::SCHEMA_VERSION);
+            }
+            for version in applied {
+                println!("Upgraded SQLite database to version {version}");
+            }
+        }
+        store::Database::Postgres(_) | store::Database::Snapshot(_) => {
+            let mut transaction = database.postgres_unchecked(schema, "db migrate")?;
+            let applied = migrations::migrate(&mut transaction, schema)?;
+            transaction.commit()?;
+            if applied.is_empty() {
+                println!("Schema {schema} is up to date at version {}", migrations::latest_version());
+            }
+            for migration in applied {
+                println!("Applied migration {} {}", migration.version, migration.name);
             }
-        };
-
-        if let (Some(address), Some(port)) = (address, ssh_port) {
-            let owner = &machine.owner;
-            let hostname = &machine.hostname;
-            let t = "  ";
-            println!("\
-                # owner: {owner}\n\
-                Host {hostname}\n\
-                {t}HostName {address}\n\
-                {t}Port {port}\n\
-            ");
         }
     }
     Ok(())
 }
 
-struct WireguardPeer {
-    hostname: String,
-    wireguard_pubkey: String,
-    wireguard_ipv4_address: Ipv4Addr,
-    wireguard_ipv6_address: Ipv6Addr,
-    endpoint: Option<(IpAddr, u16)>,
-    keepalive: Option<i32>,
-}
-
-/// Get a list of WireGuard peers for a machine, taking into account the source
-/// and destination networks for each machine-machine pair.
-#[allow(clippy::ptr_arg)]
-fn get_wireguard_peers(
-    machines_map: &MachinesMap,
-    network_links_priority_map: &NetworkLinksPriorityMap,
-    keepalives_map: &WireguardKeepaliveIntervalMap,
-    for_machine: &str,
-) -> Result<Vec<WireguardPeer>> {
-    let mut peers = vec![];
-    let source_machine =
-        &machines_map.get(for_machine)
-        .ok_or_else(|| anyhow!("machines_map missing {}", for_machine))?;
-    for machine in machines_map.values() {
-        if machine.hostname == for_machine {
-            // We don't need a [Peer] for ourselves
-            continue;
-        }
-        let network_to_network = get_network_to_network(&network_links_priority_map, &source_machine.networks, &machine.addresses);
-        let endpoint = match network_to_network.get(0) {
-            Some((_, dest_network)) => {
-                let desired_address = machine.addresses.iter().find(|a| a.network == *dest_network);
-                match desired_address {
-                    Some(MachineAddress { address, wireguard_port: Some(port), .. }) => {
-                        Some((*address, u16::try_from(*port)
-                            .with_context(|| anyhow!("Port {} out of expected range 0-65535", *port))?))
-                    },
-                    _ => None,
-                }
-            },
-            None => None,
+fn db_drop(database: &mut store::Database, schema: &str, yes: bool) -> Result<()> {
+    if let store::Database::Sqlite(_) = database {
+        bail!("`i db drop` needs a Postgres database; delete the SQLite database file instead");
+    }
+    let mut transaction = database.postgres_unchecked(schema, "db drop")?;
+    if !yes && !confirm(&format!("Drop schema {schema} and everything in it, including the history?"))? {
+        return Ok(());
+    }
+    migrations::drop_schema(&mut transaction, schema)?;
+    transaction.commit()?;
+    println!("Dropped schema {schema}");
+    Ok(())
+}
+
+/// Print every migration known to this build or recorded in the database, and when it was applied
+fn db_status(transaction: &mut Transaction) -> Result<()> {
+    let applied = migrations::get_applied_migrations(transaction)?
+        .into_iter()
+        .map(|(version, name, applied_time)| (version, (name, applied_time)))
+        .collect::<HashMap<_, _>>();
+    let mut versions = migrations::MIGRATIONS.iter().map(|m| m.version).chain(applied.keys().copied()).collect::<Vec<_>>();
+    versions.sort_unstable();
+    versions.dedup();
+
+    let mut tw = TabWriter::new(vec![]);
+    write_column_names(&mut tw, vec!["VERSION", "NAME", "APPLIED"])?;
+    for version in versions {
+        let known = migrations::MIGRATIONS.iter().find(|m| m.version == version);
+        let (name, applied_time) = match (applied.get(&version), known) {
+            (Some((name, time)), Some(_)) => (name.clone(), time.to_rfc3339_opts(chrono::SecondsFormat::Secs, true)),
+            (Some((name, time)), None) => (name.clone(), format!("{} (unknown to this infrabase)", time.to_rfc3339_opts(chrono::SecondsFormat::Secs, true))),
+            (None, Some(migration)) => (migration.name.to_string(), "pending".to_string()),
+            (None, None) => unreachable!(),
         };
+        writeln!(tw, "{version}\t{name}\t{applied_time}")?;
+    }
+    print_tabwriter(tw)
+}
 
-        // If we have a wireguard peer
-        if let (Some(wireguard_ipv4_address),
-                Some(wireguard_ipv6_address),
-                Some(wireguard_pubkey)) = (machine.wireguard_ipv4_address, machine.wireguard_ipv6_address, &machine.wireguard_pubkey) {
-            let keepalive = keepalives_map.get(&(for_machine.to_string(), machine.hostname.to_string())).copied();
-            peers.push(WireguardPeer {
-                hostname: machine.hostname.clone(),
-                wireguard_pubkey: wireguard_pubkey.clone(),
-                wireguard_ipv4_address,
-                wireguard_ipv6_address,
-                endpoint,
-                keepalive,
-            });
+/// Print every configured profile, without connecting to any database
+fn list_profiles(active: &str) -> Result<()> {
+    let mut tw = TabWriter::new(vec![]);
+    write_column_names(&mut tw, vec!["PROFILE", "ACTIVE", "DATABASE", "SCHEMA", "POOL"])?;
+    for name in profile::list_profiles()? {
+        // Only the profile's own files, not our environment, which belongs to the active profile
+        let env_path = profile::env_path(&name)?;
+        let vars = if env_path.exists() { profile::read_env(&env_path)? } else { HashMap::new() };
+        let config = Config::load(&profile::config_path(&name)?, &|var| vars.get(var).cloned())
+            .with_context(|| format!("Invalid configuration for profile {:?}", name))?;
+        write_table_cell(&mut tw, &name)?;
+        write_table_cell(&mut tw, if name == active { "*".to_string() } else { "".to_string() })?;
+        write_table_cell(&mut tw, config.database.url.as_deref().map(profile::hide_password))?;
+        write_table_cell(&mut tw, config.schema().to_string())?;
+        write_table_cell(&mut tw, &config.defaults.pool)?;
+        tw.write_all(b"\n")?;
+    }
+    print_tabwriter(tw)
+}
+
+/// Print the effective configuration of `profile` as TOML
+fn show_config(profile: &str, config: &Config) -> Result<()> {
+    println!("# Profile {:?}, from {:?} and the environment", profile, profile::config_path(profile)?);
+    print!("{}", config.to_toml_without_secrets()?);
+    Ok(())
+}
+
+/// Print warnings about the configuration of `profile`, which `load_config`
+/// already validated
+fn check_config(profile: &str, config: &Config) -> Result<()> {
+    let file = Config::load(&profile::config_path(profile)?, &|_| None)?;
+    for warning in config.warnings(&file, &|var| env::var(var).ok())? {
+        eprintln!("warning: {warning}");
+    }
+    println!("Configuration for profile {:?} is valid", profile);
+    Ok(())
+}
+
+/// An address to look up with `i whois`, optionally with a port
+#[derive(Debug, PartialEq, Eq)]
+struct WhoisQuery {
+    address: IpAddr,
+    port: Option<u16>,
+}
+
+impl FromStr for WhoisQuery {
+    type Err = anyhow::Error;
+
+    /// Parse 10.0.0.1, 10.0.0.1:22, fd00::1, or [fd00::1]:22
+    fn from_str(s: &str) -> Result<WhoisQuery> {
+        if let Ok(socket_addr) = s.parse::<SocketAddr>() {
+            return Ok(WhoisQuery { address: socket_addr.ip(), port: Some(socket_addr.port()) });
         }
+        let address = s.parse::<IpAddr>()
+            .with_context(|| anyhow!("Could not parse {:?} as ADDRESS or ADDRESS:PORT", s))?;
+        Ok(WhoisQuery { address, port: None })
     }
-    Ok(peers)
 }
 
-fn sort_wireguard_peers(peers: &mut Vec<WireguardPeer>) {
-    peers.sort_unstable_by(|p1, p2| {
-        HumanStr::new(&p1.hostname)
-            .partial_cmp(&HumanStr::new(&p2.hostname))
-            .unwrap()
-    });
+/// Describe what a machine uses `port` for, given its SSH and WireGuard ports.
+/// If `port` is None, describe all of its ports.
+fn describe_port_use(port: Option<u16>, ssh_port: Option<i32>, wireguard_port: Option<i32>) -> String {
+    let uses = vec![("SSH", ssh_port), ("WireGuard", wireguard_port)]
+        .into_iter()
+        .filter_map(|(service, service_port)| {
+            let service_port = service_port?;
+            match port {
+                None => Some(format!("{service} on {service_port}")),
+                Some(port) if i32::from(port) == service_port => Some(service.to_string()),
+                Some(_) => None,
+            }
+        })
+        .collect::<Vec<_>>();
+    match (port, uses.is_empty()) {
+        (Some(port), true) => format!("nothing known on port {port}"),
+        _ => uses.join(", "),
+    }
 }
 
-fn print_wg_quick(mut transaction: &mut Transaction, for_machine: &str) -> Result<()> {
-    let machines_map = get_machines_with_addresses(&mut transaction)?;
-    let network_links_priority_map = get_network_links_priority_map(&mut transaction)?;
-    let keepalives_map = get_wireguard_keepalive_map(&mut transaction)?;
-    let my_machine = unwrap_or_else!(
-        machines_map.get(for_machine),
-        bail!("Could not find machine {:?} in database", for_machine)
-    );
+/// Print the machines, pools, and reservations that own an address, and return
+/// how many were found
+fn whois(store: &mut dyn Store, query: &WhoisQuery) -> Result<usize> {
+    let WhoisQuery { address, port } = *query;
+    let mut found = 0;
 
-    ensure!(my_machine.wireguard_ipv4_address.is_some(), "Machine {:?} does not have WireGuard IPv4 address", for_machine);
-    ensure!(my_machine.wireguard_ipv6_address.is_some(), "Machine {:?} does not have WireGuard IPv6 address", for_machine);
-
-    {
-        let privkey = my_machine.wireguard_privkey.as_ref().unwrap();
-        let my_ipv4_address = &my_machine.wireguard_ipv4_address.unwrap();
-        let my_ipv6_address = &my_machine.wireguard_ipv6_address.unwrap();
-        let listen_port = &my_machine.wireguard_port.unwrap();
-        println!("\
-            # infrabase-generated wg-quick config for {for_machine}\n\
-            \n\
-            [Interface]\n\
-            Address = {my_ipv4_address}/32, {my_ipv6_address}/128\n\
-            PrivateKey = {privkey}\n\
-            ListenPort = {listen_port}\n\
-        ");
-    }
-
-    let mut peers = get_wireguard_peers(&machines_map, &network_links_priority_map, &keepalives_map, for_machine)?;
-    sort_wireguard_peers(&mut peers);
-    for peer in peers {
-        let maybe_endpoint = match peer.endpoint {
-            Some((address, port)) => format!("Endpoint = {address}:{port}\n"),
-            None => "".to_string(),
-        };
-        let maybe_keepalive = match peer.keepalive {
-            Some(interval) => format!("PersistentKeepalive = {interval}\n"),
-            None => "".to_string()
+    let mut tw = TabWriter::new(vec![]);
+    write_column_names(&mut tw, vec!["OWNER", "NETWORK", "MATCH", "USE"])?;
+    let machines_map = store.get_machines_with_addresses()?;
+    let machines = get_sorted_machines(&machines_map);
+    for machine in &machines {
+        let hostname = &machine.hostname;
+        let kind = if machine.wireguard_ipv4_address.map(IpAddr::V4) == Some(address) {
+            "WireGuard IPv4 address"
+        } else if machine.wireguard_ipv6_address.map(IpAddr::V6) == Some(address) {
+            "WireGuard IPv6 address"
+        } else {
+            continue;
         };
-        {
-            let peer_hostname = &peer.hostname;
-            let peer_pubkey = &peer.wireguard_pubkey;
-            let peer_ipv4_address = &peer.wireguard_ipv4_address;
-            let peer_ipv6_address = &peer.wireguard_ipv6_address;
-            println!("\
-                # {peer_hostname}\n\
-                [Peer]\n\
-                PublicKey = {peer_pubkey}\n\
-                AllowedIPs = {peer_ipv4_address}/32, {peer_ipv6_address}/128\n\
-                {maybe_endpoint}\
-                {maybe_keepalive}\
-            ");
+        let uses = describe_port_use(port, machine.ssh_port, machine.wireguard_port);
+        let uses = if uses.is_empty() { kind.to_string() } else { format!("{kind}; {uses}") };
+        writeln!(tw, "{hostname}\t-\t{address}\t{uses}")?;
+        found += 1;
+    }
+    for machine in &machines {
+        for machine_address in machine.addresses.iter().filter(|a| a.address == address).sorted_by(|a, b| a.network.cmp(&b.network)) {
+            let (hostname, network) = (&machine_address.hostname, &machine_address.network);
+            let uses = describe_port_use(port, machine_address.ssh_port, machine_address.wireguard_port);
+            writeln!(tw, "{hostname}\t{network}\t{address}\t{uses}")?;
+            found += 1;
+        }
+    }
+    // There are no routed networks in the inventory, so the CIDRs that can own an
+    // address are the pools and reservations
+    for pool in store.get_ip_pools()? {
+        let name = &pool.name;
+        for cidr in &[pool.ipv4_cidr, pool.ipv6_cidr] {
+            if cidr.contains(address) {
+                writeln!(tw, "pool {name}\t-\t{cidr}\tWireGuard address pool")?;
+                found += 1;
+            }
+        }
+        for cidr in pool.reserved.iter().filter(|cidr| cidr.contains(address)) {
+            writeln!(tw, "pool {name}\t-\t{cidr}\treserved in pool")?;
+            found += 1;
+        }
+    }
+    for reservation in store.get_ip_reservations()?.into_iter().filter(|r| r.cidr.contains(address)) {
+        let cidr = reservation.cidr;
+        let reason = reservation.reason.map(|r| format!(": {r}")).unwrap_or_default();
+        writeln!(tw, "reservation\t-\t{cidr}\treserved{reason}")?;
+        found += 1;
+    }
+    if found > 0 {
+        print_tabwriter(tw)?;
+    }
+    Ok(found)
+}
+
+/// Print the machines that owned `address` in the past, after a blank line if
+/// `after_current`, and return how many were found
+fn whois_history(transaction: &mut Transaction, address: IpAddr, after_current: bool) -> Result<usize> {
+    let mut tw = TabWriter::new(vec![]);
+    write_column_names(&mut tw, vec!["HOSTNAME", "NETWORK", "USE", "FROM", "UNTIL"])?;
+    let mut found = 0;
+    for row in transaction.query(
+        "SELECT hostname, '-', 'WireGuard address', row_start, row_end FROM wireguard_interfaces_history
+         WHERE wireguard_ipv4_address = $1 OR wireguard_ipv6_address = $1
+         UNION ALL
+         SELECT hostname, network::varchar, 'address', row_start, row_end FROM machine_addresses_history
+         WHERE address = $1
+         ORDER BY row_end DESC", &[&address]
+    )? {
+        let hostname: String = row.get(0);
+        let network: String = row.get(1);
+        let kind: String = row.get(2);
+        let row_start: DateTime<Utc> = row.get(3);
+        let row_end: DateTime<Utc> = row.get(4);
+        let (from, until) = (row_start.format("%Y-%m-%d %H:%M"), row_end.format("%Y-%m-%d %H:%M"));
+        writeln!(tw, "{hostname}\t{network}\t{kind}\t{from}\t{until}")?;
+        found += 1;
+    }
+    if found > 0 {
+        if after_current {
+            println!();
         }
+        print_tabwriter(tw)?;
     }
+    Ok(found)
+}
+
+fn print_ssh_config(store: &mut dyn Store, for_machine: &str) -> Result<()> {
+    let machines_map = store.get_machines_with_addresses()?;
+    let network_links_priority_map = store.get_network_links_priority_map()?;
+    print!("{}", generate::ssh_config(&machines_map, &network_links_priority_map, for_machine)?);
+    Ok(())
+}
+
+fn print_wg_quick(store: &mut dyn Store, for_machine: &str) -> Result<()> {
+    let machines_map = store.get_machines_with_addresses()?;
+    let network_links_priority_map = store.get_network_links_priority_map()?;
+    let keepalives_map = store.get_wireguard_keepalive_map()?;
+    print!("{}", generate::wg_quick(&machines_map, &network_links_priority_map, &keepalives_map, for_machine)?);
     Ok(())
 }
 
-/// Write a .nix file for each machine listing its WireGuard peers
-fn write_wireguard_peers(mut transaction: &mut Transaction, with_names: bool) -> Result<()> {
-    let machines_map = get_machines_with_addresses(&mut transaction)?;
-    let network_links_priority_map = get_network_links_priority_map(&mut transaction)?;
-    let keepalives_map = get_wireguard_keepalive_map(&mut transaction)?;
+/// Fill in the WIREGUARD_PEERS_PATH_TEMPLATE `template` for a machine
+fn wireguard_peers_path(template: &str, hostname: &str, ipv4_address: Ipv4Addr, ipv6_address: Ipv6Addr) -> String {
+    template
+        .replace("{hostname}", hostname)
+        .replace("{wireguard_ipv4_address}", &ipv4_address.to_string())
+        .replace("{wireguard_ipv6_address}", &ipv6_address.to_string())
+}
+
+/// Write a .nix file for each machine listing its WireGuard peers, or with
+/// `module`, a NixOS module that sets up its WireGuard interface.  Files that
+/// would not change are left alone.  Prints what happened to each file.
+fn write_wireguard_peers(store: &mut dyn Store, config: &Config, with_names: bool, module: Option<&WireguardModuleOptions>, prune: bool, yes: bool) -> Result<()> {
+    let machines_map = store.get_machines_with_addresses()?;
+    let network_links_priority_map = store.get_network_links_priority_map()?;
+    let keepalives_map = store.get_wireguard_keepalive_map()?;
     let machines = get_sorted_machines(&machines_map);
 
-    let path_template = env_var("WIREGUARD_PEERS_PATH_TEMPLATE")?;
+    let path_template = config::require(&config.wireguard.peers_path_template, "WIREGUARD_PEERS_PATH_TEMPLATE")?;
+    let manifest_path = generated_files::Manifest::path(&path_template);
+    let previous = generated_files::Manifest::load(&manifest_path)?;
 
+    // Hostname by path of every file that should be tracked in the new manifest
+    let mut tracked = BTreeMap::new();
+    let mut stale = vec![];
+    let (mut created, mut updated, mut unchanged, mut skipped) = (0, 0, 0, 0);
     for machine in machines.into_iter() {
-        let path = path_template
-            .replace("{hostname}", &machine.hostname)
-            .replace("{wireguard_ipv4_address}", &machine.wireguard_ipv4_address.unwrap().to_string())
-            .replace("{wireguard_ipv6_address}", &machine.wireguard_ipv6_address.unwrap().to_string());
-        let mut file = File::create(path)?;
-        file.write_all(b"[\n")?;
-        let mut peers = get_wireguard_peers(&machines_map, &network_links_priority_map, &keepalives_map, &machine.hostname)?;
-        sort_wireguard_peers(&mut peers);
-        for peer in peers {
-            let maybe_endpoint = match peer.endpoint {
-                Some((address, port)) => format!("endpoint = \"{address}:{port}\"; "),
-                None => "".to_string(),
-            };
-            let maybe_keepalive = match peer.keepalive {
-                Some(interval) => format!("persistentKeepalive = {interval}; "),
-                None => "".to_string()
-            };
-            if with_names {
-                writeln!(file, "  {{ name = {}; allowedIPs = [ \"{}/32\" \"{}/128\" ]; publicKey = {}; {maybe_endpoint}{maybe_keepalive}}}",
-                         peer.hostname.to_nix(),
-                         peer.wireguard_ipv4_address,
-                         peer.wireguard_ipv6_address,
-                         peer.wireguard_pubkey.to_nix())?;
-            } else {
-                writeln!(file, "  {{ allowedIPs = [ \"{}/32\" \"{}/128\" ]; publicKey = {}; {maybe_endpoint}{maybe_keepalive}}}",
-                         peer.wireguard_ipv4_address,
-                         peer.wireguard_ipv6_address,
-                         peer.wireguard_pubkey.to_nix())?;
+        let hostname = &machine.hostname;
+        let path = match (machine.wireguard_ipv4_address, machine.wireguard_ipv6_address) {
+            (Some(ipv4_address), Some(ipv6_address)) => wireguard_peers_path(&path_template, hostname, ipv4_address, ipv6_address),
+            _ => {
+                println!("skipped    {hostname}, which has no WireGuard interface");
+                skipped += 1;
+                continue;
+            }
+        };
+        if machine.state == MachineState::Retired {
+            // Only files written by an earlier run are ours to prune
+            if previous.files.contains_key(&path) && Path::new(&path).exists() {
+                println!("stale      {path} ({hostname} is retired)");
+                stale.push((path, hostname.clone()));
+            }
+            continue;
+        }
+        let peers = generate::wireguard_peers_nix(&machines_map, &network_links_priority_map, &keepalives_map, hostname, with_names, module)?;
+        match generated_files::write(Path::new(&path), &peers)? {
+            generated_files::Written::Created => {
+                println!("created    {path}");
+                created += 1;
+            }
+            generated_files::Written::Updated => {
+                println!("updated    {path}");
+                updated += 1;
+            }
+            generated_files::Written::Unchanged => unchanged += 1,
+        }
+        tracked.insert(path, hostname.clone());
+    }
+    for (path, hostname) in previous.files {
+        if tracked.contains_key(&path) || stale.iter().any(|(stale_path, _)| *stale_path == path) || !Path::new(&path).exists() {
+            continue;
+        }
+        if machines_map.contains_key(&hostname) {
+            println!("stale      {path} (no longer written for {hostname})");
+        } else {
+            println!("stale      {path} ({hostname} is not in the inventory)");
+        }
+        stale.push((path, hostname));
+    }
+
+    let mut deleted = 0;
+    if prune && !stale.is_empty() {
+        if yes || confirm(&format!("Delete {} stale file(s)?", stale.len()))? {
+            for (path, _) in stale.drain(..) {
+                fs::remove_file(&path).with_context(|| format!("Could not delete {:?}", path))?;
+                println!("deleted    {path}");
+                deleted += 1;
             }
+        } else {
+            println!("Not deleting stale files");
         }
-        file.write_all(b"]\n")?;
     }
+    let stale_count = stale.len();
+    tracked.extend(stale);
+    generated_files::Manifest::new(tracked).save(&manifest_path)?;
+
+    print!("{created} created, {updated} updated, {unchanged} unchanged, {stale_count} stale, {skipped} skipped");
+    if prune {
+        print!(", {deleted} deleted");
+    }
+    println!();
     Ok(())
 }
 
+/// Read the inventory, and the ssh_config and WireGuard peers file of every
+/// machine, for comparing before and after a dry run
+fn read_dry_run_state(store: &mut dyn Store, config: &Config) -> Result<dry_run::State> {
+    let inventory = get_inventory(store, true)?;
+    let machines_map = store.get_machines_with_addresses()?;
+    let network_links_priority_map = store.get_network_links_priority_map()?;
+    let keepalives_map = store.get_wireguard_keepalive_map()?;
+
+    let mut files = BTreeMap::new();
+    for machine in get_sorted_machines(&machines_map).into_iter().filter(|machine| machine.state != MachineState::Retired) {
+        let hostname = &machine.hostname;
+        files.insert(format!("ssh-config --for {hostname}"), generate::ssh_config(&machines_map, &network_links_priority_map, hostname)?);
+        if let (Some(ipv4_address), Some(ipv6_address)) = (machine.wireguard_ipv4_address, machine.wireguard_ipv6_address) {
+            let name = match &config.wireguard.peers_path_template {
+                Some(template) => wireguard_peers_path(template, hostname, ipv4_address, ipv6_address),
+                None => format!("write-wg-peers file for {hostname}"),
+            };
+            files.insert(name, generate::wireguard_peers_nix(&machines_map, &network_links_priority_map, &keepalives_map, hostname, true, None)?);
+        }
+    }
+    Ok(dry_run::State { inventory, files })
+}
+
+/// Start a transaction for a command that changes the inventory, which with
+/// `dry_run` prints the changes instead of committing them
+fn writable_store<'a>(database: &'a mut store::Database, schema: &str, config: &'a Config, dry_run: bool) -> Result<Box<dyn Store + 'a>> {
+    let store = database.store(schema)?;
+    if !dry_run {
+        return Ok(store);
+    }
+    let read_state = Box::new(move |store: &mut dyn Store| read_dry_run_state(store, config));
+    Ok(Box::new(DryRun::new(store, read_state)?))
+}
+
 #[derive(StructOpt, Debug)]
 #[structopt(name = "infrabase")]
 #[structopt(help_message = "Print help information")]
 #[structopt(version_message = "Print version information")]
 /// the machine inventory system
+struct Infrabase {
+    /// Profile to use, see `profile ls`
+    ///
+    /// If one is not provided, INFRABASE_PROFILE will be used from the environment
+    /// if set, otherwise the default profile.
+    #[structopt(long, global = true)]
+    profile: Option<String>,
+
+    /// Read the inventory from a file saved by `snapshot save` instead of the database
+    ///
+    /// Every command that only reads the inventory can be used with a snapshot,
+    /// except `whois --history`, which reads the history kept in Postgres.
+    #[structopt(long, global = true, value_name = "FILE", parse(from_os_str))]
+    snapshot: Option<PathBuf>,
+
+    /// Print what a command would change in the inventory and in generated files, without changing anything
+    ///
+    /// The command runs in a transaction as usual, including allocating WireGuard IPs
+    /// and generating keys, which is then rolled back instead of committed.
+    #[structopt(long, global = true)]
+    dry_run: bool,
+
+    #[structopt(subcommand)]
+    command: InfrabaseCommand,
+}
+
+#[derive(StructOpt, Debug)]
 enum InfrabaseCommand {
     /// Subcommands to work with WireGuard persistent keepalives
     #[structopt(name = "wg-keepalive")]
@@ -805,28 +1396,139 @@ enum InfrabaseCommand {
 
     #[structopt(name = "write-wg-peers")]
     /// Write out all WireGuard peers files used for NixOS configuration
+    ///
+    /// Each file is replaced in one step, and only if its contents change.  Machines
+    /// without a WireGuard interface are skipped.  The files written are recorded in
+    /// .infrabase-generated.json in their directory, so that files left from removed
+    /// or retired machines can be reported as stale and deleted with --prune.
     WriteWireguardPeers {
         /// Omit the `name = "..."` not supported in upstream nixpkgs
         #[structopt(long = "no-names")]
         no_names: bool,
+
+        /// Write a complete NixOS module per machine that configures
+        /// networking.wireguard.interfaces and opens the WireGuard port,
+        /// instead of a bare list of peers
+        #[structopt(long)]
+        module: bool,
+
+        /// Interface name to use in the module
+        #[structopt(long, default_value = "wg0")]
+        interface: String,
+
+        /// Path of the private key file on each machine to use in the module
+        #[structopt(long, default_value = "/etc/wireguard/private.key")]
+        private_key_file: String,
+
+        /// Delete stale files, after listing them and asking for confirmation
+        #[structopt(long)]
+        prune: bool,
+
+        /// With --prune, delete stale files without asking
+        #[structopt(long, requires = "prune")]
+        yes: bool,
     },
 
+    /// Subcommands to create and upgrade the database schema
+    #[structopt(name = "db")]
+    Db(DbCommand),
+
     /// Subcommands to work with providers
     #[structopt(name = "provider")]
     Provider(ProviderCommand),
 
+    /// Subcommands to work with profiles, which each have their own database and defaults
+    #[structopt(name = "profile")]
+    Profile(ProfileCommand),
+
+    /// Subcommands to inspect the configuration of the active profile
+    #[structopt(name = "config")]
+    Config(ConfigCommand),
+
     /// Subcommands to work with addresses
     #[structopt(name = "address")]
     Address(AddressCommand),
 
+    /// Subcommands to work with WireGuard IP pools
+    #[structopt(name = "pool")]
+    Pool(PoolCommand),
+
+    /// Subcommands to inspect WireGuard IP address management
+    #[structopt(name = "ipam")]
+    Ipam(IpamCommand),
+
+    /// Subcommands to save the inventory for rendering configuration without the database
+    #[structopt(name = "snapshot")]
+    Snapshot(SnapshotCommand),
+
     #[structopt(name = "ls")]
     /// List machines
-    List,
+    List {
+        /// Also list retired machines
+        #[structopt(long)]
+        all: bool,
+    },
+
+    #[structopt(name = "check")]
+    /// Check the inventory for problems that the database does not prevent
+    ///
+    /// Looks for peers that cannot reach each other, WireGuard addresses and ports
+    /// used twice, keys that do not match, and missing network links.  Prints each
+    /// error and warning with the rows that cause it, and fails if there are errors.
+    Check,
 
     #[structopt(name = "nix-data")]
     /// Output machine and address data in Nix format for use in configuration
     NixData,
 
+    #[structopt(name = "export")]
+    /// Output the complete inventory as JSON, YAML, or TOML
+    Export {
+        /// Output format: json, yaml, or toml
+        #[structopt(long, default_value = "json")]
+        format: inventory::Format,
+
+        /// Include WireGuard private keys
+        #[structopt(long)]
+        with_privkeys: bool,
+    },
+
+    #[structopt(name = "dump")]
+    /// Write every table, including history, to a file that `restore` can load
+    Dump {
+        /// File to write the dump to
+        #[structopt(name = "FILE", parse(from_os_str))]
+        file: PathBuf,
+    },
+
+    #[structopt(name = "restore")]
+    /// Load a file written by `dump` into an empty schema created with `db init`
+    Restore {
+        /// File to read the dump from
+        #[structopt(name = "FILE", parse(from_os_str))]
+        file: PathBuf,
+
+        /// Delete everything in the schema, including history, before restoring
+        #[structopt(long)]
+        force: bool,
+    },
+
+    #[structopt(name = "apply")]
+    /// Make the inventory match a YAML, TOML, or JSON file, after showing what will change
+    ///
+    /// The file has the same layout as the output of `export`.  Lists left out
+    /// of the file are not changed; lists in the file replace the whole table.
+    /// Machines get the same defaults as `add` for fields that are left out.
+    Apply {
+        /// File to read the desired inventory from
+        #[structopt(name = "FILE", parse(from_os_str))]
+        file: PathBuf,
+
+        /// Apply the changes without asking for confirmation
+        #[structopt(long)]
+        yes: bool,
+    },
+
     #[structopt(name = "add")]
     /// Add machine
     Add {
@@ -854,16 +1556,28 @@ enum InfrabaseCommand {
 
         /// WireGuard IPv4 IP
         ///
-        /// If one is not provided, an unused IP address will be selected.
+        /// If one is not provided, an unused IP address will be selected from the pool.
+        /// Addresses freed less than WIREGUARD_IP_QUARANTINE_DAYS (default 14) days ago
+        /// and addresses reserved with `ipam reserve` are never selected.
         #[structopt(long)]
         wireguard_ipv4_address: Option<Ipv4Addr>,
 
         /// WireGuard IPv6 IP
         ///
-        /// If one is not provided, an unused IP address will be selected.
+        /// If one is not provided, an unused IP address will be selected from the pool.
         #[structopt(long)]
         wireguard_ipv6_address: Option<Ipv6Addr>,
 
+        /// IP pool to allocate WireGuard IPs from
+        ///
+        /// If one is not provided, DEFAULT_POOL will be used from the environment
+        /// if set, otherwise the only pool in the database will be used.  With no
+        /// pools in the database, addresses are allocated between
+        /// WIREGUARD_IPV4_START and WIREGUARD_IPV4_END, and WIREGUARD_IPV6_START
+        /// and WIREGUARD_IPV6_END, if they are set.
+        #[structopt(long)]
+        pool: Option<String>,
+
         /// WireGuard port
         ///
         /// If one is not provided, DEFAULT_WIREGUARD_PORT will be used from the environment.
@@ -883,14 +1597,56 @@ enum InfrabaseCommand {
         /// at the provider, like a contract ID or a server number.
         #[structopt(long)]
         provider_reference: Option<String>,
+
+        /// Lifecycle state: planned, active, decommissioning, or retired
+        #[structopt(long, default_value = "active")]
+        state: MachineState,
     },
 
     #[structopt(name = "rm")]
-    /// Remove machine
+    /// Retire machine, or delete it with --purge
     Remove {
         /// Machine hostname
         #[structopt(name = "HOSTNAME")]
         hostname: String,
+
+        /// Delete the machine and its addresses and keepalives instead of retiring it
+        ///
+        /// Its WireGuard addresses stay quarantined, like those of any removed machine.
+        #[structopt(long)]
+        purge: bool,
+    },
+
+    #[structopt(name = "retire")]
+    /// Retire machine, leaving it out of WireGuard peers and ssh_config but keeping it listed
+    Retire {
+        /// Machine hostname
+        #[structopt(name = "HOSTNAME")]
+        hostname: String,
+    },
+
+    #[structopt(name = "unretire")]
+    /// Make a retired machine active again
+    Unretire {
+        /// Machine hostname
+        #[structopt(name = "HOSTNAME")]
+        hostname: String,
+    },
+
+    #[structopt(name = "undelete")]
+    /// Put back a machine removed with `rm --purge`, with its keys, addresses, and keepalives
+    ///
+    /// The machine is rebuilt from the history, after checking that its WireGuard
+    /// addresses and its ports on each address are still free.
+    Undelete {
+        /// Machine hostname
+        #[structopt(name = "HOSTNAME")]
+        hostname: String,
+
+        /// Put the machine back as it was at this time, e.g. 2021-03-01T12:00:00Z,
+        /// instead of as it was just before it was removed
+        #[structopt(long)]
+        as_of: Option<DateTime<Utc>>,
     },
 
     #[structopt(name = "ssh-config")]
@@ -901,6 +1657,18 @@ enum InfrabaseCommand {
         r#for: String,
     },
 
+    #[structopt(name = "whois")]
+    /// Find the machine, network, or pool that owns an address, and what a port is used for
+    Whois {
+        /// The address to look up, e.g. 10.10.0.17, 203.0.113.5:904, or [fd00::1]:22
+        #[structopt(name = "ADDRESS[:PORT]")]
+        query: WhoisQuery,
+
+        /// Also list machines that owned the address in the past; needs Postgres
+        #[structopt(long)]
+        history: bool,
+    },
+
     #[structopt(name = "wg-quick")]
     /// Output a wg-quick config for a machine
     WgQuick {
@@ -910,6 +1678,41 @@ enum InfrabaseCommand {
     },
 }
 
+impl InfrabaseCommand {
+    /// Whether the command only reads the inventory, and so can read a snapshot
+    fn is_read_only(&self) -> bool {
+        matches!(self,
+            InfrabaseCommand::List { .. } |
+            InfrabaseCommand::Check |
+            InfrabaseCommand::NixData |
+            InfrabaseCommand::Export { .. } |
+            InfrabaseCommand::SshConfig { .. } |
+            InfrabaseCommand::WgQuick { .. } |
+            InfrabaseCommand::Whois { history: false, .. } |
+            InfrabaseCommand::WireguardPrivkey { .. } |
+            InfrabaseCommand::WriteWireguardPeers { .. } |
+            InfrabaseCommand::Address(AddressCommand::List) |
+            InfrabaseCommand::Provider(ProviderCommand::List) |
+            InfrabaseCommand::Pool(PoolCommand::List) |
+            InfrabaseCommand::WireguardKeepalive(WireguardKeepaliveCommand::List) |
+            InfrabaseCommand::Ipam(IpamCommand::List) |
+            InfrabaseCommand::Ipam(IpamCommand::Check) |
+            InfrabaseCommand::Ipam(IpamCommand::Reservations))
+    }
+
+    /// Whether `--dry-run` can be used with the command.  Commands that write
+    /// files or manage the schema do so outside the transaction that a dry run
+    /// rolls back.
+    fn allows_dry_run(&self) -> bool {
+        !matches!(self,
+            InfrabaseCommand::Db(_) |
+            InfrabaseCommand::Dump { .. } |
+            InfrabaseCommand::Restore { .. } |
+            InfrabaseCommand::Snapshot(SnapshotCommand::Save { .. }) |
+            InfrabaseCommand::WriteWireguardPeers { .. })
+    }
+}
+
 #[derive(StructOpt, Debug)]
 enum WireguardKeepaliveCommand {
     #[structopt(name = "ls")]
@@ -947,6 +1750,63 @@ enum WireguardKeepaliveCommand {
     },
 }
 
+#[derive(StructOpt, Debug)]
+enum DbCommand {
+    #[structopt(name = "init")]
+    /// Create the schema at the latest version
+    Init,
+
+    #[structopt(name = "migrate")]
+    /// Apply migrations that were not applied yet
+    ///
+    /// A schema created with up.sql by an older infrabase is adopted as version 1.
+    Migrate,
+
+    #[structopt(name = "status")]
+    /// List migrations and when they were applied
+    Status,
+
+    #[structopt(name = "drop")]
+    /// Drop the schema and everything in it, after asking for confirmation
+    Drop {
+        /// Drop the schema without asking
+        #[structopt(long)]
+        yes: bool,
+    },
+}
+
+#[derive(StructOpt, Debug)]
+enum ConfigCommand {
+    #[structopt(name = "show")]
+    /// Print the configuration in effect, with environment variables applied
+    Show,
+
+    #[structopt(name = "check")]
+    /// Validate the configuration and warn about obsolete or overriding variables
+    Check,
+}
+
+#[derive(StructOpt, Debug)]
+enum ProfileCommand {
+    #[structopt(name = "ls")]
+    /// List profiles
+    List,
+}
+
+#[derive(StructOpt, Debug)]
+enum SnapshotCommand {
+    #[structopt(name = "save")]
+    /// Save everything that read-only commands read, including WireGuard private keys, to a file
+    ///
+    /// Commands given `--snapshot FILE` read the file instead of the database,
+    /// and print the same output as they did when the snapshot was saved.
+    Save {
+        /// File to write the snapshot to
+        #[structopt(name = "FILE", parse(from_os_str))]
+        file: PathBuf,
+    },
+}
+
 #[derive(StructOpt, Debug)]
 enum ProviderCommand {
     #[structopt(name = "ls")]
@@ -954,6 +1814,85 @@ enum ProviderCommand {
     List,
 }
 
+#[derive(StructOpt, Debug)]
+enum PoolCommand {
+    #[structopt(name = "ls")]
+    /// List IP pools
+    List,
+
+    #[structopt(name = "add")]
+    /// Add IP pool
+    Add {
+        /// Pool name
+        #[structopt(name = "NAME")]
+        name: String,
+
+        /// IPv4 network to allocate WireGuard IPv4 addresses from, e.g. 10.10.0.0/16
+        #[structopt(name = "IPV4_CIDR")]
+        ipv4_cidr: Cidr,
+
+        /// IPv6 network to allocate WireGuard IPv6 addresses from, e.g. fd00::/64
+        #[structopt(name = "IPV6_CIDR")]
+        ipv6_cidr: Cidr,
+
+        /// A range inside the pool that must not be allocated (may be repeated)
+        #[structopt(long = "reserve", name = "CIDR")]
+        reserved: Vec<Cidr>,
+
+        /// Allocation policy: lowest_free or after_highest
+        #[structopt(long, default_value = "lowest_free")]
+        policy: AllocationPolicy,
+
+        /// How IPv6 addresses are picked: independent, or embed_ipv4 to derive each
+        /// IPv6 address from the host part of the IPv4 address (10.10.3.17 -> fd00::3:17)
+        #[structopt(long, default_value = "independent")]
+        ipv6_mode: Ipv6Mode,
+    },
+
+    #[structopt(name = "rm")]
+    /// Remove IP pool
+    Remove {
+        /// Pool name
+        #[structopt(name = "NAME")]
+        name: String,
+    },
+}
+
+#[derive(StructOpt, Debug)]
+enum IpamCommand {
+    #[structopt(name = "ls")]
+    /// Show how full each pool is and which addresses would be allocated next
+    List,
+
+    #[structopt(name = "check")]
+    /// Report machines whose WireGuard IPv6 address does not follow their pool's IPv4 -> IPv6 mapping
+    Check,
+
+    #[structopt(name = "reservations")]
+    /// List reserved WireGuard address ranges
+    Reservations,
+
+    #[structopt(name = "reserve")]
+    /// Reserve a range of WireGuard addresses so that it is never allocated
+    Reserve {
+        /// The range to reserve, e.g. 10.10.0.1 or 10.10.255.0/24
+        #[structopt(name = "CIDR")]
+        cidr: Cidr,
+
+        /// Why the range is reserved
+        #[structopt(long)]
+        reason: Option<String>,
+    },
+
+    #[structopt(name = "release")]
+    /// Remove a reservation
+    Release {
+        /// The reserved range
+        #[structopt(name = "CIDR")]
+        cidr: Cidr,
+    },
+}
+
 #[derive(StructOpt, Debug)]
 enum AddressCommand {
     #[structopt(name = "ls")]
@@ -1006,64 +1945,157 @@ enum AddressCommand {
 }
 
 fn main() -> Result<()> {
-    import_env()?;
-    env_logger::init();
-    let mut client = postgres_client()?;
-    let mut transaction = client.transaction()?;
-    transaction.execute("SET search_path TO infra", &[])?;
+    let args = Infrabase::from_args();
+    let profile = unwrap_or_else!(
+        args.profile,
+        env::var("INFRABASE_PROFILE").unwrap_or_else(|_| profile::DEFAULT_PROFILE.to_string())
+    );
+    if let InfrabaseCommand::Profile(ProfileCommand::List) = args.command {
+        return list_profiles(&profile);
+    }
 
-    let matches = InfrabaseCommand::from_args();
+    let config = load_config(&profile)?;
+    env_logger::init();
+    if let InfrabaseCommand::Config(cmd) = &args.command {
+        return match cmd {
+            ConfigCommand::Show => show_config(&profile, &config),
+            ConfigCommand::Check => check_config(&profile, &config),
+        };
+    }
+    let schema = config.schema().to_string();
+    let matches = args.command;
+    let dry_run = args.dry_run;
+    ensure!(!dry_run || matches.allows_dry_run(),
+            "--dry-run cannot be used with db, dump, restore, snapshot save, or write-wg-peers, which would change files anyway");
+    let mut database = match args.snapshot {
+        Some(path) => {
+            ensure!(matches.is_read_only(), "This command changes the inventory or needs the database, so it cannot be used with --snapshot");
+            store::Database::Snapshot(Snapshot::load(&path)?)
+        },
+        None => store::Database::connect(&config.database, matches!(matches, InfrabaseCommand::Db(DbCommand::Init)))?,
+    };
     match matches {
+        InfrabaseCommand::Db(cmd) => {
+            match cmd {
+                DbCommand::Init => db_init(&mut database, &schema)?,
+                DbCommand::Migrate => db_migrate(&mut database, &schema)?,
+                DbCommand::Status => db_status(&mut database.postgres_unchecked(&schema, "db status")?)?,
+                DbCommand::Drop { yes } => db_drop(&mut database, &schema, yes)?,
+            }
+        },
+        InfrabaseCommand::Profile(_) | InfrabaseCommand::Config(_) => {
+            unreachable!("profile and config commands are handled before connecting")
+        },
         InfrabaseCommand::Provider(cmd) => {
             match cmd {
-                ProviderCommand::List => list_providers(&mut transaction)?,
+                ProviderCommand::List => list_providers(&mut *database.read_only_store(&schema)?)?,
             }
         },
         InfrabaseCommand::Address(cmd) => {
             match cmd {
-                AddressCommand::List => list_addresses(&mut transaction)?,
+                AddressCommand::List => list_addresses(&mut *database.read_only_store(&schema)?)?,
                 AddressCommand::Add { hostname, network, address, ssh_port, wireguard_port } => {
-                    add_address(transaction, &hostname, &network, &address, ssh_port, wireguard_port)?
+                    add_address(writable_store(&mut database, &schema, &config, dry_run)?, &config, &hostname, &network, &address, ssh_port, wireguard_port)?
                 },
                 AddressCommand::Remove { hostname, network, address } => {
-                    remove_address(transaction, &hostname, &network, &address)?
+                    remove_address(writable_store(&mut database, &schema, &config, dry_run)?, &hostname, &network, &address)?
                 },
             }
         },
+        InfrabaseCommand::Pool(cmd) => {
+            match cmd {
+                PoolCommand::List => list_ip_pools(&mut *database.read_only_store(&schema)?)?,
+                PoolCommand::Add { name, ipv4_cidr, ipv6_cidr, reserved, policy, ipv6_mode } => {
+                    add_ip_pool(writable_store(&mut database, &schema, &config, dry_run)?, &name, &ipv4_cidr, &ipv6_cidr, &reserved, policy, ipv6_mode)?
+                },
+                PoolCommand::Remove { name } => {
+                    remove_ip_pool(writable_store(&mut database, &schema, &config, dry_run)?, &name)?
+                },
+            }
+        },
+        InfrabaseCommand::Ipam(cmd) => {
+            match cmd {
+                IpamCommand::List => list_ipam(&mut *database.read_only_store(&schema)?, &config)?,
+                IpamCommand::Check => check_ipam(&mut *database.read_only_store(&schema)?)?,
+                IpamCommand::Reservations => list_ip_reservations(&mut *database.read_only_store(&schema)?)?,
+                IpamCommand::Reserve { cidr, reason } => reserve_ip_range(writable_store(&mut database, &schema, &config, dry_run)?, &cidr, reason)?,
+                IpamCommand::Release { cidr } => release_ip_range(writable_store(&mut database, &schema, &config, dry_run)?, &cidr)?,
+            }
+        },
+        InfrabaseCommand::Snapshot(cmd) => {
+            match cmd {
+                SnapshotCommand::Save { file } => save_snapshot(&mut *database.read_only_store(&schema)?, &config, &file)?,
+            }
+        },
         InfrabaseCommand::WireguardKeepalive(cmd) => {
             match cmd {
-                WireguardKeepaliveCommand::List => list_wireguard_keepalives(&mut transaction)?,
+                WireguardKeepaliveCommand::List => list_wireguard_keepalives(&mut *database.read_only_store(&schema)?)?,
                 WireguardKeepaliveCommand::Add { source, target, interval_sec } => {
-                    add_wireguard_keepalive(transaction, &source, &target, interval_sec)?
+                    add_wireguard_keepalive(writable_store(&mut database, &schema, &config, dry_run)?, &config, &source, &target, interval_sec)?
                 },
                 WireguardKeepaliveCommand::Remove { source, target } => {
-                    remove_wireguard_keepalive(transaction, &source, &target)?
+                    remove_wireguard_keepalive(writable_store(&mut database, &schema, &config, dry_run)?, &source, &target)?
                 },
             }
         },
         InfrabaseCommand::WireguardPrivkey { hostname } => {
-            print_wireguard_privkey(&mut transaction, &hostname)?;
+            print_wireguard_privkey(&mut *database.read_only_store(&schema)?, &hostname)?;
+        },
+        InfrabaseCommand::WriteWireguardPeers { no_names, module, interface, private_key_file, prune, yes } => {
+            let module_options = WireguardModuleOptions { interface, private_key_file };
+            write_wireguard_peers(&mut *database.read_only_store(&schema)?, &config, !no_names, if module { Some(&module_options) } else { None }, prune, yes)?;
         },
-        InfrabaseCommand::WriteWireguardPeers { no_names } => {
-            write_wireguard_peers(&mut transaction, !no_names)?;
+        InfrabaseCommand::List { all } => {
+            list_machines(&mut *database.read_only_store(&schema)?, all)?;
         },
-        InfrabaseCommand::List => {
-            list_machines(&mut transaction)?;
+        InfrabaseCommand::Check => {
+            check_inventory(&mut *database.read_only_store(&schema)?)?;
         },
         InfrabaseCommand::NixData => {
-            nix_data(&mut transaction)?;
+            nix_data(&mut *database.read_only_store(&schema)?)?;
+        },
+        InfrabaseCommand::Export { format, with_privkeys } => {
+            export(&mut *database.read_only_store(&schema)?, format, with_privkeys)?;
+        },
+        InfrabaseCommand::Dump { file } => {
+            dump(&mut database.postgres_read_only(&schema, "dump")?, &file)?;
+        },
+        InfrabaseCommand::Restore { file, force } => {
+            restore(database.postgres(&schema, "restore")?, &file, force)?;
+        },
+        InfrabaseCommand::Apply { file, yes } => {
+            apply(writable_store(&mut database, &schema, &config, dry_run)?, &config, &file, yes || dry_run)?;
+        },
+        InfrabaseCommand::Add { hostname, owner, ssh_port, ssh_user, wireguard_ipv4_address, wireguard_ipv6_address, pool, wireguard_port, provider, provider_reference, state } => {
+            add_machine(writable_store(&mut database, &schema, &config, dry_run)?, &config, &hostname, owner, ssh_port, ssh_user, wireguard_ipv4_address, wireguard_ipv6_address, wireguard_port, provider, provider_reference, pool, state)?;
         },
-        InfrabaseCommand::Add { hostname, owner, ssh_port, ssh_user, wireguard_ipv4_address, wireguard_ipv6_address, wireguard_port, provider, provider_reference } => {
-            add_machine(transaction, &hostname, owner, ssh_port, ssh_user, wireguard_ipv4_address, wireguard_ipv6_address, wireguard_port, provider, provider_reference)?;
+        InfrabaseCommand::Remove { hostname, purge: true } => {
+            remove_machine(writable_store(&mut database, &schema, &config, dry_run)?, &hostname)?;
         },
-        InfrabaseCommand::Remove { hostname } => {
-            remove_machine(transaction, &hostname)?;
+        InfrabaseCommand::Remove { hostname, purge: false } | InfrabaseCommand::Retire { hostname } => {
+            set_machine_state(writable_store(&mut database, &schema, &config, dry_run)?, &hostname, MachineState::Retired)?;
+        },
+        InfrabaseCommand::Unretire { hostname } => {
+            set_machine_state(writable_store(&mut database, &schema, &config, dry_run)?, &hostname, MachineState::Active)?;
+        },
+        InfrabaseCommand::Undelete { hostname, as_of } => {
+            undelete_machine(writable_store(&mut database, &schema, &config, dry_run)?, &config, &hostname, as_of)?;
         },
         InfrabaseCommand::SshConfig { r#for } => {
-            print_ssh_config(&mut transaction, &r#for)?;
+            print_ssh_config(&mut *database.read_only_store(&schema)?, &r#for)?;
+        },
+        InfrabaseCommand::Whois { query, history: false } => {
+            let found = whois(&mut *database.read_only_store(&schema)?, &query)?;
+            ensure!(found > 0, "Nothing in the inventory owns {}", query.address);
+        },
+        InfrabaseCommand::Whois { query, history: true } => {
+            let mut transaction = database.postgres_read_only(&schema, "whois --history")?;
+            let found = whois(&mut transaction, &query)?;
+            let found = found + whois_history(&mut transaction, query.address, found > 0)?;
+            ensure!(found > 0, "Nothing in the database owns {}", query.address);
         },
         InfrabaseCommand::WgQuick { r#for } => {
-            print_wg_quick(&mut transaction, &r#for)?;
+            print_wg_quick(&mut *database.read_only_store(&schema)?, &r#for)?;
         },
     }
     Ok(())
@@ -1071,28 +2103,69 @@ fn main() -> Result<()> {
 
 #[cfg(test)]
 mod tests {
-    use super::{increment_ipv4_address, increment_ipv6_address};
-    use std::net::{Ipv4Addr, Ipv6Addr};
+    use structopt::StructOpt;
+    use std::collections::HashSet;
+    use chrono::{Duration, Utc};
+    use super::{describe_port_use, Infrabase, UnavailableAddresses, WhoisQuery};
+
+    #[test]
+    fn test_parse_whois_query() {
+        let parse = |s: &str| s.parse::<WhoisQuery>().unwrap();
+        assert_eq!(parse("10.0.0.1"), WhoisQuery { address: "10.0.0.1".parse().unwrap(), port: None });
+        assert_eq!(parse("10.0.0.1:22"), WhoisQuery { address: "10.0.0.1".parse().unwrap(), port: Some(22) });
+        assert_eq!(parse("fd00::1"), WhoisQuery { address: "fd00::1".parse().unwrap(), port: None });
+        assert_eq!(parse("[fd00::1]:904"), WhoisQuery { address: "fd00::1".parse().unwrap(), port: Some(904) });
+        assert!("10.0.0.1:99999".parse::<WhoisQuery>().is_err());
+        assert!("example.com".parse::<WhoisQuery>().is_err());
+    }
+
+    /// Recently freed addresses cannot be given explicitly, unless a machine still has them
+    #[test]
+    fn test_check_not_quarantined() {
+        let freed = "10.10.0.1".parse().unwrap();
+        let mut unavailable = UnavailableAddresses {
+            used: HashSet::new(),
+            quarantined: vec![(freed, Utc::now() + Duration::days(3))].into_iter().collect(),
+            reservations: vec![],
+        };
+        let error = unavailable.check_not_quarantined(freed).unwrap_err();
+        assert!(error.to_string().starts_with("WireGuard IP 10.10.0.1 was freed recently and is quarantined until "));
+        assert!(unavailable.check_not_quarantined("10.10.0.2".parse().unwrap()).is_ok());
+        unavailable.used.insert(freed);
+        assert!(unavailable.check_not_quarantined(freed).is_ok());
+    }
+
+    /// `whois` reads a snapshot, but its history is only in the database
+    #[test]
+    fn test_whois_is_read_only() {
+        let command = |args: &[&str]| Infrabase::from_iter_safe(args).unwrap().command;
+        assert!(command(&["i", "whois", "10.0.0.1"]).is_read_only());
+        assert!(!command(&["i", "whois", "10.0.0.1", "--history"]).is_read_only());
+    }
 
+    /// `--dry-run` is refused where it would not stop files being written
     #[test]
-    fn test_increment_ipv4_address() {
-        assert_eq!(increment_ipv4_address(&Ipv4Addr::new(0,   0,   0,   0)),   Some(Ipv4Addr::new(0, 0, 0,   1)));
-        assert_eq!(increment_ipv4_address(&Ipv4Addr::new(0,   0,   0,   1)),   Some(Ipv4Addr::new(0, 0, 0,   2)));
-        assert_eq!(increment_ipv4_address(&Ipv4Addr::new(0,   0,   1,   255)), Some(Ipv4Addr::new(0, 0, 2,   0)));
-        assert_eq!(increment_ipv4_address(&Ipv4Addr::new(0,   0,   255, 0)),   Some(Ipv4Addr::new(0, 0, 255, 1)));
-        assert_eq!(increment_ipv4_address(&Ipv4Addr::new(0,   2,   255, 255)), Some(Ipv4Addr::new(0, 3, 0,   0)));
-        assert_eq!(increment_ipv4_address(&Ipv4Addr::new(3,   255, 255, 255)), Some(Ipv4Addr::new(4, 0, 0,   0)));
-        assert_eq!(increment_ipv4_address(&Ipv4Addr::new(255, 255, 255, 255)), None);
+    fn test_allows_dry_run() {
+        let command = |args: &[&str]| Infrabase::from_iter_safe(args).unwrap().command;
+        assert!(command(&["i", "--dry-run", "add", "host1"]).allows_dry_run());
+        assert!(command(&["i", "--dry-run", "ipam", "reserve", "10.10.0.0/24"]).allows_dry_run());
+        assert!(!command(&["i", "--dry-run", "write-wg-peers"]).allows_dry_run());
+        assert!(!command(&["i", "--dry-run", "write-wg-peers", "--prune", "--yes"]).allows_dry_run());
+        assert!(!command(&["i", "--dry-run", "dump", "backup.json"]).allows_dry_run());
+        assert!(!command(&["i", "--dry-run", "snapshot", "save", "snapshot.json"]).allows_dry_run());
+        assert!(!command(&["i", "--dry-run", "db", "migrate"]).allows_dry_run());
     }
 
     #[test]
-    fn test_increment_ipv6_address() {
-        assert_eq!(increment_ipv6_address(&"0:0:0:0:0:0:0:0"                        .parse::<Ipv6Addr>().unwrap()), Some("0:0:0:0:0:0:0:1"   .parse().unwrap()));
-        assert_eq!(increment_ipv6_address(&"0:0:0:0:0:0:0:1"                        .parse::<Ipv6Addr>().unwrap()), Some("0:0:0:0:0:0:0:2"   .parse().unwrap()));
-        assert_eq!(increment_ipv6_address(&"0:0:0:0:0:0:1:ffff"                     .parse::<Ipv6Addr>().unwrap()), Some("0:0:0:0:0:0:2:0"   .parse().unwrap()));
-        assert_eq!(increment_ipv6_address(&"0:0:0:0:0:0:ffff:0"                     .parse::<Ipv6Addr>().unwrap()), Some("0:0:0:0:0:0:ffff:1".parse().unwrap()));
-        assert_eq!(increment_ipv6_address(&"0:0:0:0:0:2:ffff:ffff"                  .parse::<Ipv6Addr>().unwrap()), Some("0:0:0:0:0:3:0:0"   .parse().unwrap()));
-        assert_eq!(increment_ipv6_address(&"0:0:0:0:3:ffff:ffff:ffff"               .parse::<Ipv6Addr>().unwrap()), Some("0:0:0:0:4:0:0:0"   .parse().unwrap()));
-        assert_eq!(increment_ipv6_address(&"ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff".parse::<Ipv6Addr>().unwrap()), None);
+    fn test_describe_port_use() {
+        assert_eq!(describe_port_use(None, Some(22), Some(904)), "SSH on 22, WireGuard on 904");
+        assert_eq!(describe_port_use(None, None, Some(904)), "WireGuard on 904");
+        assert_eq!(describe_port_use(None, None, None), "");
+        assert_eq!(describe_port_use(Some(904), Some(22), Some(904)), "WireGuard");
+        assert_eq!(describe_port_use(Some(22), Some(22), Some(22)), "SSH, WireGuard");
+        assert_eq!(describe_port_use(Some(80), Some(22), Some(904)), "nothing known on port 80");
+        // A WireGuard address, which peers reach on the machine's listen port
+        assert_eq!(describe_port_use(Some(51820), Some(22), Some(51820)), "WireGuard");
+        assert_eq!(describe_port_use(Some(51820), Some(22), None), "nothing known on port 51820");
     }
 }
@@ -0,0 +1,177 @@
+//! Versioned schema migrations, embedded in the binary.
+//!
+//! Each migration is a file in schema/migrations/ that runs with the search_path
+//! set to the infrabase schema.  The versions that were applied are recorded in
+//! the `schema_migrations` table in the same schema.  To change the schema, add
+//! a new file and a new entry to `MIGRATIONS`; never edit a migration that was
+//! already released.
+
+use postgres::Transaction;
+use anyhow::{bail, ensure, Result};
+use chrono::{DateTime, Utc};
+
+pub(crate) struct Migration {
+    pub version: i32,
+    pub name: &'static str,
+    pub sql: &'static str,
+}
+
+/// All migrations, in the order they are applied
+pub(crate) const MIGRATIONS: &[Migration] = &[
+    Migration { version: 1, name: "initial", sql: include_str!("../schema/migrations/0001_initial.sql") },
+    Migration { version: 2, name: "ip_pools", sql: include_str!("../schema/migrations/0002_ip_pools.sql") },
+    Migration { version: 3, name: "machine_states", sql: include_str!("../schema/migrations/0003_machine_states.sql") },
+];
+
+/// The schema version this build of infrabase understands
+pub(crate) fn latest_version() -> i32 {
+    MIGRATIONS.last().expect("there is at least one migration").version
+}
+
+/// Migrations after `version`
+pub(crate) fn pending(version: i32) -> impl Iterator<Item=&'static Migration> {
+    MIGRATIONS.iter().filter(move |migration| migration.version > version)
+}
+
+const CREATE_MIGRATIONS_TABLE: &str = "
+    CREATE TABLE schema_migrations (
+        version       integer      PRIMARY KEY,
+        name          text         NOT NULL,
+        applied_time  timestamptz  NOT NULL DEFAULT now()
+    )";
+
+pub(crate) fn schema_exists(transaction: &mut Transaction, schema: &str) -> Result<bool> {
+    let exists = transaction.query_one(
+        "SELECT EXISTS (SELECT 1 FROM information_schema.schemata WHERE schema_name = $1)", &[&schema]
+    )?.get(0);
+    Ok(exists)
+}
+
+fn table_exists(transaction: &mut Transaction, table: &str) -> Result<bool> {
+    let exists = transaction.query_one("SELECT to_regclass($1) IS NOT NULL", &[&table])?.get(0);
+    Ok(exists)
+}
+
+/// Get (version, name, applied_time) of every applied migration
+pub(crate) fn get_applied_migrations(transaction: &mut Transaction) -> Result<Vec<(i32, String, DateTime<Utc>)>> {
+    if !table_exists(transaction, "schema_migrations")? {
+        return Ok(vec![]);
+    }
+    let applied = transaction.query("SELECT version, name, applied_time FROM schema_migrations ORDER BY version", &[])?
+        .into_iter()
+        .map(|row| (row.get(0), row.get(1), row.get(2)))
+        .collect();
+    Ok(applied)
+}
+
+/// Get the version of the schema, or None if no migrations were applied
+pub(crate) fn get_schema_version(transaction: &mut Transaction) -> Result<Option<i32>> {
+    Ok(get_applied_migrations(transaction)?.last().map(|(version, _, _)| *version))
+}
+
+/// Fail unless the schema is at exactly the version this build understands
+pub(crate) fn check_schema_version(transaction: &mut Transaction) -> Result<()> {
+    let latest = latest_version();
+    match get_schema_version(transaction)? {
+        None => bail!("The database schema is not managed by infrabase; run `i db init` to create it, or `i db migrate` to adopt a schema created with up.sql"),
+        Some(version) if version < latest => bail!("The database schema is at version {}, but this infrabase needs version {}; run `i db migrate`", version, latest),
+        Some(version) if version > latest => bail!("The database schema is at version {}, which is newer than this infrabase understands (version {}); upgrade infrabase", version, latest),
+        Some(_) => Ok(()),
+    }
+}
+
+fn apply_migration(transaction: &mut Transaction, migration: &Migration) -> Result<()> {
+    transaction.batch_execute(migration.sql)?;
+    transaction.execute(
+        "INSERT INTO schema_migrations (version, name) VALUES ($1, $2)",
+        &[&migration.version, &migration.name]
+    )?;
+    Ok(())
+}
+
+/// Create `schema` and apply every migration.  The search_path must already
+/// be set to `schema`.
+pub(crate) fn init(transaction: &mut Transaction, schema: &str) -> Result<()> {
+    ensure!(!schema_exists(transaction, schema)?, "Schema {:?} already exists; use `i db migrate` to upgrade it", schema);
+    transaction.batch_execute(&format!("CREATE SCHEMA {schema}"))?;
+    transaction.batch_execute(CREATE_MIGRATIONS_TABLE)?;
+    for migration in MIGRATIONS {
+        apply_migration(transaction, migration)?;
+    }
+    Ok(())
+}
+
+/// Drop `schema` and everything in it, including its history
+pub(crate) fn drop_schema(transaction: &mut Transaction, schema: &str) -> Result<()> {
+    ensure!(schema_exists(transaction, schema)?, "Schema {:?} does not exist", schema);
+    transaction.batch_execute(&format!("DROP SCHEMA {schema} CASCADE"))?;
+    Ok(())
+}
+
+/// Apply every migration that was not applied yet, and return them.
+///
+/// A schema that was created with up.sql before migrations existed is adopted
+/// as version 1.
+pub(crate) fn migrate(transaction: &mut Transaction, schema: &str) -> Result<Vec<&'static Migration>> {
+    ensure!(schema_exists(transaction, schema)?, "Schema {:?} does not exist; use `i db init` to create it", schema);
+    if !table_exists(transaction, "schema_migrations")? {
+        ensure!(table_exists(transaction, "machines")?,
+            "Schema {:?} has neither schema_migrations nor machines; refusing to guess its version", schema);
+        transaction.batch_execute(CREATE_MIGRATIONS_TABLE)?;
+        let initial = &MIGRATIONS[0];
+        transaction.execute(
+            "INSERT INTO schema_migrations (version, name) VALUES ($1, $2)",
+            &[&initial.version, &format!("{} (adopted)", initial.name)]
+        )?;
+    }
+    let version = get_schema_version(transaction)?.unwrap_or(0);
+    ensure!(version <= latest_version(),
+        "The database schema is at version {}, which is newer than this infrabase understands (version {})", version, latest_version());
+    let pending = pending(version).collect::<Vec<_>>();
+    for migration in &pending {
+        apply_migration(transaction, migration)?;
+    }
+    Ok(pending)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{latest_version, pending, MIGRATIONS};
+
+    /// Versions start at 1 and have no gaps
+    #[test]
+    fn test_versions_are_consecutive() {
+        for (i, migration) in MIGRATIONS.iter().enumerate() {
+            assert_eq!(migration.version, i as i32 + 1);
+        }
+        assert_eq!(latest_version(), MIGRATIONS.len() as i32);
+    }
+
+    /// `migrate` adopts a schema created with up.sql as version 1, so the initial
+    /// migration must create exactly what up.sql did and nothing added later
+    #[test]
+    fn test_initial_migration_is_up_sql() {
+        let initial = MIGRATIONS[0].sql;
+        for later in &["poolname", "ip_pools", "ip_reservations", "machine_state"] {
+            assert!(!initial.contains(later), "the initial migration creates {}", later);
+        }
+    }
+
+    /// Migrations run inside a transaction with the search_path already set,
+    /// so they must not use psql commands, create the schema, or change the search_path
+    #[test]
+    fn test_migrations_are_plain_sql() {
+        for migration in MIGRATIONS {
+            assert!(!migration.sql.contains("\\set"), "{} uses a psql command", migration.name);
+            assert!(!migration.sql.contains("CREATE SCHEMA"), "{} creates a schema", migration.name);
+            assert!(!migration.sql.contains("search_path"), "{} sets the search_path", migration.name);
+        }
+    }
+
+    #[test]
+    fn test_pending() {
+        assert_eq!(pending(0).count(), MIGRATIONS.len());
+        assert_eq!(pending(latest_version()).count(), 0);
+        assert_eq!(pending(latest_version() + 1).count(), 0);
+    }
+}
@@ -0,0 +1,106 @@
+//! Machines and their addresses, as read from the inventory.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+use anyhow::{bail, Error};
+use chrono::{DateTime, Utc};
+use natural_sort::HumanStr;
+use serde::{Deserialize, Serialize};
+
+/// Where a machine is in its lifecycle.  Retired machines are kept in the
+/// inventory, but are not anyone's WireGuard peer or in anyone's ssh_config.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MachineState {
+    Planned,
+    #[default]
+    Active,
+    Decommissioning,
+    Retired,
+}
+
+impl MachineState {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            MachineState::Planned => "planned",
+            MachineState::Active => "active",
+            MachineState::Decommissioning => "decommissioning",
+            MachineState::Retired => "retired",
+        }
+    }
+}
+
+impl fmt::Display for MachineState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for MachineState {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<MachineState, Error> {
+        Ok(match s {
+            "planned" => MachineState::Planned,
+            "active" => MachineState::Active,
+            "decommissioning" => MachineState::Decommissioning,
+            "retired" => MachineState::Retired,
+            _ => bail!("Unknown machine state {:?}; expected planned, active, decommissioning, or retired", s),
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Machine {
+    pub hostname: String,
+    pub state: MachineState,
+    pub wireguard_ipv4_address: Option<Ipv4Addr>,
+    pub wireguard_ipv6_address: Option<Ipv6Addr>,
+    pub wireguard_port: Option<i32>,
+    pub wireguard_privkey: Option<String>,
+    pub wireguard_pubkey: Option<String>,
+    pub ssh_port: Option<i32>,
+    pub ssh_user: Option<String>,
+    pub added_time: DateTime<Utc>,
+    pub owner: String,
+    pub provider_id: Option<i32>,
+    pub provider_name: Option<String>,
+    pub provider_email: Option<String>,
+    pub provider_reference: Option<String>,
+    /// Networks of `addresses`, or `["NONE"]` if there are none
+    pub networks: Vec<String>,
+    pub addresses: Vec<MachineAddress>,
+}
+
+#[derive(Debug, Clone)]
+pub struct MachineAddress {
+    pub hostname: String,
+    pub network: String,
+    pub address: IpAddr,
+    pub ssh_port: Option<i32>,
+    pub wireguard_port: Option<i32>,
+}
+
+/// A map of hostname -> Machine
+pub type MachinesMap = HashMap<String, Machine>;
+
+/// A map of (network, other_network) -> priority
+pub type NetworkLinksPriorityMap = HashMap<(String, String), i32>;
+
+/// A map of (source_machine, target_machine) -> interval
+pub type WireguardKeepaliveIntervalMap = HashMap<(String, String), i32>;
+
+/// Convert a MachinesMap to a Vec of &Machine naturally sorted by hostname
+pub fn get_sorted_machines(machines_map: &MachinesMap) -> Vec<&Machine> {
+    let mut machines = machines_map.values().collect::<Vec<_>>();
+    // natural_sort refuses to compare string segments with integer segments,
+    // so if returns None, fall back to String cmp.
+    machines.sort_unstable_by(|m1, m2| {
+        HumanStr::new(&m1.hostname)
+            .partial_cmp(&HumanStr::new(&m2.hostname))
+            .unwrap_or_else(|| m1.hostname.cmp(&m2.hostname))
+    });
+    machines
+}
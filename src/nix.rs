@@ -1,43 +1,346 @@
-pub(crate) trait ToNix {
-    fn to_nix(&self) -> String;
+use std::fmt;
+use chrono::{DateTime, SecondsFormat, Utc};
+
+/// A Nix value
+#[derive(Debug, Clone, PartialEq)]
+pub enum Nix {
+    Null,
+    Bool(bool),
+    Int(i64),
+    String(String),
+    List(Vec<Nix>),
+    /// Attributes are printed in the order given
+    AttrSet(Vec<(String, Nix)>),
+}
+
+pub trait ToNix {
+    fn to_nix(&self) -> Nix;
+}
+
+/// Escape `s` for use between the double quotes of a Nix string, so that it
+/// cannot end the string early or start an antiquotation
+fn escape_string(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => escaped.push_str(r#"\""#),
+            '\\' => escaped.push_str(r"\\"),
+            '\n' => escaped.push_str(r"\n"),
+            '\r' => escaped.push_str(r"\r"),
+            '\t' => escaped.push_str(r"\t"),
+            '$' if chars.peek() == Some(&'{') => escaped.push_str(r"\$"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn quote_string(s: &str) -> String {
+    format!(r#""{}""#, escape_string(s))
+}
+
+const KEYWORDS: &[&str] = &["assert", "else", "if", "in", "inherit", "let", "or", "rec", "then", "with"];
+
+/// Whether `name` can be used as an attribute name without quotes
+fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    let first_ok = match chars.next() {
+        Some(c) => c.is_ascii_alphabetic() || c == '_',
+        None => false,
+    };
+    first_ok &&
+        chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '\'' || c == '-') &&
+        !KEYWORDS.contains(&name)
+}
+
+/// Format `name` as a Nix attribute name, quoting it if it is not a valid identifier
+pub fn attr_name(name: &str) -> String {
+    if is_identifier(name) {
+        name.to_string()
+    } else {
+        quote_string(name)
+    }
+}
+
+const INDENT: &str = "  ";
+
+impl Nix {
+    /// Build an attribute set from (name, value) pairs, keeping their order
+    pub fn attrs<'a>(attrs: impl IntoIterator<Item=(&'a str, Nix)>) -> Nix {
+        Nix::AttrSet(attrs.into_iter().map(|(name, value)| (name.to_string(), value)).collect())
+    }
+
+    fn is_scalar(&self) -> bool {
+        !matches!(self, Nix::List(_) | Nix::AttrSet(_))
+    }
+
+    /// Render on a single line, e.g. `{ ip = "10.0.0.1"; ports = [ 22 904 ]; }`
+    pub fn to_inline(&self) -> String {
+        match self {
+            Nix::Null => "null".to_string(),
+            Nix::Bool(b) => b.to_string(),
+            Nix::Int(i) => i.to_string(),
+            Nix::String(s) => quote_string(s),
+            Nix::List(items) if items.is_empty() => "[ ]".to_string(),
+            Nix::List(items) => format!("[ {} ]", items.iter().map(Nix::to_inline).collect::<Vec<_>>().join(" ")),
+            Nix::AttrSet(attrs) if attrs.is_empty() => "{ }".to_string(),
+            Nix::AttrSet(attrs) => {
+                let attrs = attrs.iter()
+                    .map(|(name, value)| format!("{} = {};", attr_name(name), value.to_inline()))
+                    .collect::<Vec<_>>();
+                format!("{{ {} }}", attrs.join(" "))
+            }
+        }
+    }
+
+    /// Render with one attribute or list item per line, indented by two spaces
+    /// per level.  Lists that contain only scalars are kept on one line.
+    pub fn to_pretty(&self) -> String {
+        let mut out = String::new();
+        self.write_pretty(&mut out, 0);
+        out
+    }
+
+    fn write_pretty(&self, out: &mut String, level: usize) {
+        let indent = INDENT.repeat(level + 1);
+        match self {
+            Nix::List(items) if !items.iter().all(Nix::is_scalar) => {
+                out.push_str("[\n");
+                for item in items {
+                    out.push_str(&indent);
+                    item.write_pretty(out, level + 1);
+                    out.push('\n');
+                }
+                out.push_str(&INDENT.repeat(level));
+                out.push(']');
+            }
+            Nix::AttrSet(attrs) if !attrs.is_empty() => {
+                out.push_str("{\n");
+                for (name, value) in attrs {
+                    out.push_str(&indent);
+                    out.push_str(&attr_name(name));
+                    out.push_str(" = ");
+                    value.write_pretty(out, level + 1);
+                    out.push_str(";\n");
+                }
+                out.push_str(&INDENT.repeat(level));
+                out.push('}');
+            }
+            _ => out.push_str(&self.to_inline()),
+        }
+    }
+}
+
+impl fmt::Display for Nix {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.to_inline())
+    }
+}
+
+impl ToNix for Nix {
+    fn to_nix(&self) -> Nix {
+        self.clone()
+    }
+}
+
+impl ToNix for str {
+    fn to_nix(&self) -> Nix {
+        Nix::String(self.to_string())
+    }
 }
 
 impl ToNix for String {
-    fn to_nix(&self) -> String {
-        // TODO: replace " with \"
-        format!(r#""{self}""#)
+    fn to_nix(&self) -> Nix {
+        Nix::String(self.clone())
+    }
+}
+
+impl ToNix for bool {
+    fn to_nix(&self) -> Nix {
+        Nix::Bool(*self)
     }
 }
 
 impl ToNix for std::net::IpAddr {
-    fn to_nix(&self) -> String {
+    fn to_nix(&self) -> Nix {
         self.to_string().to_nix()
     }
 }
 
 impl ToNix for std::net::Ipv4Addr {
-    fn to_nix(&self) -> String {
+    fn to_nix(&self) -> Nix {
         self.to_string().to_nix()
     }
 }
 
 impl ToNix for std::net::Ipv6Addr {
-    fn to_nix(&self) -> String {
+    fn to_nix(&self) -> Nix {
         self.to_string().to_nix()
     }
 }
 
 impl ToNix for i32 {
-    fn to_nix(&self) -> String {
-        self.to_string()
+    fn to_nix(&self) -> Nix {
+        Nix::Int(i64::from(*self))
+    }
+}
+
+impl ToNix for u16 {
+    fn to_nix(&self) -> Nix {
+        Nix::Int(i64::from(*self))
+    }
+}
+
+impl ToNix for DateTime<Utc> {
+    /// An RFC 3339 string like "2020-06-01T12:00:00Z"
+    fn to_nix(&self) -> Nix {
+        self.to_rfc3339_opts(SecondsFormat::Secs, true).to_nix()
     }
 }
 
 impl<T: ToNix> ToNix for Option<T> {
-    fn to_nix(&self) -> String {
+    fn to_nix(&self) -> Nix {
         match self {
             Some(val) => val.to_nix(),
-            None => "null".to_string()
+            None => Nix::Null,
         }
     }
 }
+
+impl<T: ToNix> ToNix for Vec<T> {
+    fn to_nix(&self) -> Nix {
+        Nix::List(self.iter().map(ToNix::to_nix).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{attr_name, Nix, ToNix};
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+    use chrono::{DateTime, Utc};
+
+    /// Plain strings are quoted
+    #[test]
+    fn test_string() {
+        assert_eq!("hello".to_nix().to_string(), r#""hello""#);
+        assert_eq!("".to_string().to_nix().to_string(), r#""""#);
+    }
+
+    /// Quotes, backslashes, antiquotations, and control characters are escaped
+    #[test]
+    fn test_string_escaping() {
+        assert_eq!(r#"a "quoted" word"#.to_nix().to_string(), r#""a \"quoted\" word""#);
+        assert_eq!(r"back\slash".to_nix().to_string(), r#""back\\slash""#);
+        assert_eq!("${builtins.currentTime}".to_nix().to_string(), r#""\${builtins.currentTime}""#);
+        assert_eq!("$5 and $".to_nix().to_string(), r#""$5 and $""#);
+        assert_eq!("line\nline\ttab\r".to_nix().to_string(), r#""line\nline\ttab\r""#);
+        assert_eq!(r"\${".to_nix().to_string(), r#""\\\${""#);
+    }
+
+    /// A string cannot break out of its quotes to inject Nix code
+    #[test]
+    fn test_string_injection() {
+        let reference = r#"x"; owner = "mallory"; y = ""#.to_string();
+        assert_eq!(reference.to_nix().to_string(), r#""x\"; owner = \"mallory\"; y = \"""#);
+    }
+
+    #[test]
+    fn test_ip_addresses() {
+        assert_eq!(Ipv4Addr::new(10, 0, 0, 1).to_nix(), Nix::String("10.0.0.1".to_string()));
+        assert_eq!("fd00::1".parse::<Ipv6Addr>().unwrap().to_nix().to_string(), r#""fd00::1""#);
+        assert_eq!("10.0.0.1".parse::<IpAddr>().unwrap().to_nix().to_string(), r#""10.0.0.1""#);
+        assert_eq!("fd00::1".parse::<IpAddr>().unwrap().to_nix().to_string(), r#""fd00::1""#);
+    }
+
+    #[test]
+    fn test_integers() {
+        assert_eq!(0.to_nix().to_string(), "0");
+        assert_eq!(51820.to_nix().to_string(), "51820");
+        assert_eq!((-1).to_nix().to_string(), "-1");
+        assert_eq!(65535u16.to_nix(), Nix::Int(65535));
+    }
+
+    #[test]
+    fn test_bool() {
+        assert_eq!(true.to_nix().to_string(), "true");
+        assert_eq!(false.to_nix().to_string(), "false");
+    }
+
+    #[test]
+    fn test_datetime() {
+        let time = "2020-06-01T12:30:00+00:00".parse::<DateTime<Utc>>().unwrap();
+        assert_eq!(time.to_nix().to_string(), r#""2020-06-01T12:30:00Z""#);
+    }
+
+    #[test]
+    fn test_option() {
+        assert_eq!(Some(22).to_nix().to_string(), "22");
+        assert_eq!(None::<i32>.to_nix().to_string(), "null");
+        assert_eq!(Some("a\"b".to_string()).to_nix().to_string(), r#""a\"b""#);
+        assert_eq!(None::<String>.to_nix().to_string(), "null");
+    }
+
+    #[test]
+    fn test_vec() {
+        assert_eq!(vec![1, 2].to_nix().to_string(), "[ 1 2 ]");
+        assert_eq!(Vec::<i32>::new().to_nix().to_string(), "[ ]");
+        assert_eq!(vec![Some("a".to_string()), None].to_nix().to_string(), r#"[ "a" null ]"#);
+    }
+
+    /// Valid identifiers are left bare, everything else is quoted
+    #[test]
+    fn test_attr_name() {
+        assert_eq!(attr_name("hostname"), "hostname");
+        assert_eq!(attr_name("web-1"), "web-1");
+        assert_eq!(attr_name("_private"), "_private");
+        assert_eq!(attr_name("it's"), "it's");
+        assert_eq!(attr_name("1web"), r#""1web""#);
+        assert_eq!(attr_name("-web"), r#""-web""#);
+        assert_eq!(attr_name("NONE"), "NONE");
+        assert_eq!(attr_name("let"), r#""let""#);
+        assert_eq!(attr_name("or"), r#""or""#);
+        assert_eq!(attr_name(""), r#""""#);
+        assert_eq!(attr_name("a.b"), r#""a.b""#);
+        assert_eq!(attr_name("${x}"), r#""\${x}""#);
+    }
+
+    #[test]
+    fn test_inline() {
+        let value = Nix::attrs(vec![
+            ("ip", "10.0.0.1".to_nix()),
+            ("ports", vec![22, 904].to_nix()),
+            ("1st", Nix::attrs(vec![])),
+        ]);
+        assert_eq!(value.to_inline(), r#"{ ip = "10.0.0.1"; ports = [ 22 904 ]; "1st" = { }; }"#);
+    }
+
+    #[test]
+    fn test_pretty() {
+        let value = Nix::attrs(vec![
+            ("9host", Nix::attrs(vec![
+                ("owner", "ivan".to_nix()),
+                ("ports", vec![22, 904].to_nix()),
+                ("empty", Nix::attrs(vec![])),
+                ("peers", Nix::List(vec![Nix::attrs(vec![("name", "a".to_nix())]), Nix::Null])),
+            ])),
+        ]);
+        assert_eq!(value.to_pretty(), concat!(
+            "{\n",
+            "  \"9host\" = {\n",
+            "    owner = \"ivan\";\n",
+            "    ports = [ 22 904 ];\n",
+            "    empty = { };\n",
+            "    peers = [\n",
+            "      {\n",
+            "        name = \"a\";\n",
+            "      }\n",
+            "      null\n",
+            "    ];\n",
+            "  };\n",
+            "}",
+        ));
+        assert_eq!(Nix::Int(1).to_pretty(), "1");
+        assert_eq!(Nix::List(vec![]).to_pretty(), "[ ]");
+    }
+}
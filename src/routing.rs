@@ -0,0 +1,100 @@
+//! Which address to use to reach a machine from another one, and the
+//! WireGuard peers of a machine.
+
+use std::convert::TryFrom;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use anyhow::{anyhow, Context, Result};
+use itertools::iproduct;
+use natural_sort::HumanStr;
+
+use crate::model::{MachineAddress, MachineState, MachinesMap, NetworkLinksPriorityMap, WireguardKeepaliveIntervalMap};
+
+/// Return a Vec of (source_network, dest_network) pairs appropriate for
+/// establishing a connection to `addresses`, highest priority first
+pub fn get_network_to_network(
+    network_links_priority_map: &NetworkLinksPriorityMap,
+    source_networks: &[String],
+    addresses: &[MachineAddress],
+) -> Vec<(String, String)> {
+    // Convert because we need Strings in our return
+    let source_networks = source_networks.iter().map(String::from).collect::<Vec<_>>();
+
+    // Networks the destination machine is on
+    let dest_networks = addresses.iter().map(|a| a.network.clone()).collect::<Vec<_>>();
+
+    // (source, dest) network pairs
+    let mut network_to_network = iproduct!(source_networks, dest_networks)
+        .filter(|(s, d)| network_links_priority_map.contains_key(&(s.to_string(), d.to_string())))
+        .collect::<Vec<(String, String)>>();
+    network_to_network.sort_unstable_by_key(|(s, d)| network_links_priority_map.get(&(s.to_string(), d.to_string())).unwrap());
+    network_to_network
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct WireguardPeer {
+    pub hostname: String,
+    pub wireguard_pubkey: String,
+    pub wireguard_ipv4_address: Ipv4Addr,
+    pub wireguard_ipv6_address: Ipv6Addr,
+    pub endpoint: Option<(IpAddr, u16)>,
+    pub keepalive: Option<i32>,
+}
+
+/// Get a list of WireGuard peers for a machine, taking into account the source
+/// and destination networks for each machine-machine pair.
+pub fn get_wireguard_peers(
+    machines_map: &MachinesMap,
+    network_links_priority_map: &NetworkLinksPriorityMap,
+    keepalives_map: &WireguardKeepaliveIntervalMap,
+    for_machine: &str,
+) -> Result<Vec<WireguardPeer>> {
+    let mut peers = vec![];
+    let source_machine =
+        &machines_map.get(for_machine)
+        .ok_or_else(|| anyhow!("machines_map missing {}", for_machine))?;
+    for machine in machines_map.values() {
+        if machine.hostname == for_machine || machine.state == MachineState::Retired {
+            // We don't need a [Peer] for ourselves or for machines that are gone
+            continue;
+        }
+        let network_to_network = get_network_to_network(network_links_priority_map, &source_machine.networks, &machine.addresses);
+        let endpoint = match network_to_network.first() {
+            Some((_, dest_network)) => {
+                let desired_address = machine.addresses.iter().find(|a| a.network == *dest_network);
+                match desired_address {
+                    Some(MachineAddress { address, wireguard_port: Some(port), .. }) => {
+                        Some((*address, u16::try_from(*port)
+                            .with_context(|| anyhow!("Port {} out of expected range 0-65535", *port))?))
+                    },
+                    _ => None,
+                }
+            },
+            None => None,
+        };
+
+        // If we have a wireguard peer
+        if let (Some(wireguard_ipv4_address),
+                Some(wireguard_ipv6_address),
+                Some(wireguard_pubkey)) = (machine.wireguard_ipv4_address, machine.wireguard_ipv6_address, &machine.wireguard_pubkey) {
+            let keepalive = keepalives_map.get(&(for_machine.to_string(), machine.hostname.to_string())).copied();
+            peers.push(WireguardPeer {
+                hostname: machine.hostname.clone(),
+                wireguard_pubkey: wireguard_pubkey.clone(),
+                wireguard_ipv4_address,
+                wireguard_ipv6_address,
+                endpoint,
+                keepalive,
+            });
+        }
+    }
+    Ok(peers)
+}
+
+/// Sort peers naturally by hostname
+pub fn sort_wireguard_peers(peers: &mut [WireguardPeer]) {
+    peers.sort_unstable_by(|p1, p2| {
+        HumanStr::new(&p1.hostname)
+            .partial_cmp(&HumanStr::new(&p2.hostname))
+            .unwrap()
+    });
+}
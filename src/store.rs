@@ -0,0 +1,234 @@
+//! Storage of the inventory, in Postgres or in SQLite.
+//!
+//! Commands read and change the inventory through `Store`, which is
+//! implemented for a Postgres transaction in `postgres` and for a SQLite
+//! transaction in `sqlite`.  The backend is picked by the scheme of
+//! DATABASE_URL: `sqlite:///path/to/inventory.db` (or `sqlite://inventory.db`
+//! for a relative path) uses SQLite, and anything else is passed to Postgres.
+//!
+//! Commands that depend on Postgres features, like `dump`, `restore`, and the
+//! history in `whois --history`, use `Database::postgres` and are refused on
+//! SQLite.
+//!
+//! With `--snapshot FILE`, read-only commands read a file saved by
+//! `i snapshot save` instead, through the `Store` in `snapshot`.
+
+mod postgres;
+pub(crate) mod snapshot;
+pub(crate) mod sqlite;
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::Path;
+use anyhow::{bail, Result};
+use chrono::{DateTime, Utc};
+use ::postgres::IsolationLevel;
+use rusqlite::TransactionBehavior;
+use infrabase::model::{MachineState, MachinesMap, NetworkLinksPriorityMap, WireguardKeepaliveIntervalMap};
+
+use crate::apply::Change;
+use crate::config::{self, DatabaseConfig};
+use crate::inventory;
+use crate::ipam::{Cidr, IpPool};
+use crate::{migrations, tls};
+
+pub(crate) use self::postgres::reset_provider_ids;
+
+/// A transaction on the inventory.  Nothing is saved unless `commit` is called.
+pub(crate) trait Store {
+    fn get_owners(&mut self) -> Result<Vec<String>>;
+
+    fn get_networks(&mut self) -> Result<Vec<String>>;
+
+    /// Get all providers, ordered by id
+    fn get_providers(&mut self) -> Result<Vec<inventory::Provider>>;
+
+    fn get_network_links_priority_map(&mut self) -> Result<NetworkLinksPriorityMap>;
+
+    fn get_wireguard_keepalive_map(&mut self) -> Result<WireguardKeepaliveIntervalMap>;
+
+    fn get_machines_with_addresses(&mut self) -> Result<MachinesMap>;
+
+    /// Get all pools, ordered by name
+    fn get_ip_pools(&mut self) -> Result<Vec<IpPool>>;
+
+    /// Get all reservations, ordered by CIDR
+    fn get_ip_reservations(&mut self) -> Result<Vec<inventory::IpReservation>>;
+
+    /// Get WireGuard addresses that stopped being used by a machine less than
+    /// `quarantine_days` days ago, with when their quarantine ends.  Peers that
+    /// were not reconfigured since then may still route traffic for these
+    /// addresses to the old machine.
+    fn get_quarantined_wireguard_addresses(&mut self, quarantine_days: i32) -> Result<HashMap<IpAddr, DateTime<Utc>>>;
+
+    /// Make one change planned by `i apply`, or an equivalent one from another command
+    fn execute_change(&mut self, change: &Change) -> Result<()>;
+
+    /// Remove a machine and everything that refers to it, except history
+    fn remove_machine(&mut self, hostname: &str) -> Result<()>;
+
+    /// Find a machine that is not in the inventory in the history, as it was
+    /// at `as_of`, or just before it was last removed.  Returns None if it did
+    /// not exist then.
+    fn get_removed_machine(&mut self, hostname: &str, as_of: Option<DateTime<Utc>>) -> Result<Option<RemovedMachine>>;
+
+    /// Move a machine to `state` in its lifecycle
+    fn set_machine_state(&mut self, hostname: &str, state: MachineState) -> Result<()>;
+
+    /// Remove an address, returning the number of addresses removed
+    fn remove_address(&mut self, hostname: &str, network: &str, address: &IpAddr) -> Result<u64>;
+
+    /// Remove a keepalive, returning the number of keepalives removed
+    fn remove_wireguard_keepalive(&mut self, source: &str, target: &str) -> Result<u64>;
+
+    fn add_ip_pool(&mut self, pool: &IpPool) -> Result<()>;
+
+    /// Remove a pool, returning the number of pools removed
+    fn remove_ip_pool(&mut self, name: &str) -> Result<u64>;
+
+    fn reserve_ip_range(&mut self, cidr: &Cidr, reason: Option<&str>) -> Result<()>;
+
+    /// Remove a reservation, returning the number of reservations removed
+    fn release_ip_range(&mut self, cidr: &Cidr) -> Result<u64>;
+
+    /// Wait for other transactions that allocate WireGuard addresses to end, and
+    /// keep new ones from starting until this one ends.  Commands that pick unused
+    /// addresses call this before reading which addresses are used, so that two
+    /// of them running at once never pick the same address.
+    fn lock_wireguard_addresses(&mut self) -> Result<()>;
+
+    fn commit(self: Box<Self>) -> Result<()>;
+}
+
+/// A machine as it was before it was removed, with its addresses and the
+/// keepalives from and to it
+pub(crate) struct RemovedMachine {
+    pub as_of: DateTime<Utc>,
+    pub machine: inventory::Machine,
+    pub addresses: Vec<inventory::MachineAddress>,
+    pub keepalives: Vec<inventory::WireguardKeepalive>,
+}
+
+/// A connection to the database named by DATABASE_URL
+pub(crate) enum Database {
+    Postgres(::postgres::Client),
+    Sqlite(rusqlite::Connection),
+    Snapshot(snapshot::Snapshot),
+}
+
+/// Get the path of a sqlite:// URL, or None if `url` is not one
+pub(crate) fn sqlite_path(url: &str) -> Option<&Path> {
+    url.strip_prefix("sqlite://").map(Path::new)
+}
+
+impl Database {
+    /// Connect to the database in `config`.  A SQLite database file is only
+    /// created if `create`, for `i db init`.
+    pub fn connect(config: &DatabaseConfig, create: bool) -> Result<Database> {
+        let url = config::require(&config.url, "DATABASE_URL")?;
+        match sqlite_path(&url) {
+            Some(path) => Ok(Database::Sqlite(sqlite::open(path, create)?)),
+            None => Ok(Database::Postgres(tls::connect(config)?)),
+        }
+    }
+
+    /// Start a transaction that changes the inventory in `schema`, after checking
+    /// that the database schema is at the version this build understands.  SQLite
+    /// databases have no schemas, so `schema` is ignored for them.
+    ///
+    /// On SQLite, the transaction takes the write lock right away, so only one
+    /// such transaction runs at a time.
+    pub fn store(&mut self, schema: &str) -> Result<Box<dyn Store + '_>> {
+        match self {
+            Database::Postgres(client) => {
+                let mut transaction = postgres_transaction(client, schema, false)?;
+                migrations::check_schema_version(&mut transaction)?;
+                Ok(Box::new(transaction))
+            }
+            Database::Sqlite(connection) => {
+                let transaction = connection.transaction_with_behavior(TransactionBehavior::Immediate)?;
+                sqlite::check_schema_version(&transaction)?;
+                Ok(Box::new(transaction))
+            }
+            Database::Snapshot(snapshot) => Ok(Box::new(&*snapshot)),
+        }
+    }
+
+    /// Like `store`, for commands that only read the inventory.  The transaction
+    /// cannot make changes, and sees the inventory as it was at its first query
+    /// for as long as it runs, without blocking commands that change it.
+    pub fn read_only_store(&mut self, schema: &str) -> Result<Box<dyn Store + '_>> {
+        match self {
+            Database::Postgres(client) => {
+                let mut transaction = postgres_transaction(client, schema, true)?;
+                migrations::check_schema_version(&mut transaction)?;
+                Ok(Box::new(transaction))
+            }
+            Database::Sqlite(connection) => {
+                // A deferred transaction only reads until it writes, and it
+                // keeps one view of the database from its first read
+                let transaction = connection.transaction_with_behavior(TransactionBehavior::Deferred)?;
+                sqlite::check_schema_version(&transaction)?;
+                Ok(Box::new(transaction))
+            }
+            Database::Snapshot(snapshot) => Ok(Box::new(&*snapshot)),
+        }
+    }
+
+    /// Start a Postgres transaction on the inventory in `schema` for `command`,
+    /// which needs Postgres, after checking the schema version
+    pub fn postgres(&mut self, schema: &str, command: &str) -> Result<::postgres::Transaction<'_>> {
+        let mut transaction = self.postgres_unchecked(schema, command)?;
+        migrations::check_schema_version(&mut transaction)?;
+        Ok(transaction)
+    }
+
+    /// Like `postgres`, in a read-only transaction like `read_only_store`
+    pub fn postgres_read_only(&mut self, schema: &str, command: &str) -> Result<::postgres::Transaction<'_>> {
+        let mut transaction = match self {
+            Database::Postgres(client) => postgres_transaction(client, schema, true)?,
+            _ => return self.postgres_unchecked(schema, command),
+        };
+        migrations::check_schema_version(&mut transaction)?;
+        Ok(transaction)
+    }
+
+    /// Like `postgres`, without checking the schema version, for creating and
+    /// upgrading the schema
+    pub fn postgres_unchecked(&mut self, schema: &str, command: &str) -> Result<::postgres::Transaction<'_>> {
+        match self {
+            Database::Postgres(client) => postgres_transaction(client, schema, false),
+            Database::Sqlite(_) => bail!("`i {}` needs a Postgres database", command),
+            Database::Snapshot(_) => bail!("`i {}` needs a Postgres database, not a snapshot", command),
+        }
+    }
+}
+
+/// Start a transaction with the search_path set to `schema`.  A `read_only`
+/// transaction is REPEATABLE READ, so that everything it reads is consistent.
+fn postgres_transaction<'a>(client: &'a mut ::postgres::Client, schema: &str, read_only: bool) -> Result<::postgres::Transaction<'a>> {
+    let mut transaction = if read_only {
+        client.build_transaction()
+            .isolation_level(IsolationLevel::RepeatableRead)
+            .read_only(true)
+            .start()?
+    } else {
+        client.transaction()?
+    };
+    transaction.execute(&*format!("SET search_path TO {schema}"), &[])?;
+    Ok(transaction)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::sqlite_path;
+    use std::path::Path;
+
+    #[test]
+    fn test_sqlite_path() {
+        assert_eq!(sqlite_path("sqlite:///home/ivan/inventory.db"), Some(Path::new("/home/ivan/inventory.db")));
+        assert_eq!(sqlite_path("sqlite://inventory.db"), Some(Path::new("inventory.db")));
+        assert_eq!(sqlite_path("postgresql://ivan@localhost/infrabase"), None);
+        assert_eq!(sqlite_path("host=localhost user=ivan"), None);
+    }
+}
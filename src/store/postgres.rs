@@ -0,0 +1,484 @@
+//! `Store` for a Postgres transaction, on the schema in schema/migrations/.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Duration, Utc};
+use postgres::Transaction;
+use infrabase::model::{Machine, MachineAddress, MachineState, MachinesMap, NetworkLinksPriorityMap, WireguardKeepaliveIntervalMap};
+
+use crate::apply::{Change, Record};
+use crate::inventory;
+use crate::ipam::{get_ipv4addr, get_ipv6addr, Cidr, IpPool};
+use super::{RemovedMachine, Store};
+
+/// Key of the advisory lock taken by `lock_wireguard_addresses`
+const WIREGUARD_ADDRESSES_LOCK: i64 = 0x6966_7261_7767_6970;
+
+impl Store for Transaction<'_> {
+    fn get_owners(&mut self) -> Result<Vec<String>> {
+        let owners = self.query("SELECT owner FROM owners ORDER BY owner", &[])?
+            .into_iter().map(|row| row.get(0)).collect();
+        Ok(owners)
+    }
+
+    fn get_networks(&mut self) -> Result<Vec<String>> {
+        let networks = self.query("SELECT name FROM networks ORDER BY name", &[])?
+            .into_iter().map(|row| row.get(0)).collect();
+        Ok(networks)
+    }
+
+    fn get_providers(&mut self) -> Result<Vec<inventory::Provider>> {
+        let providers = self.query("SELECT id, name, email FROM providers ORDER BY id", &[])?
+            .into_iter()
+            .map(|row| inventory::Provider { id: row.get(0), name: row.get(1), email: row.get(2) })
+            .collect();
+        Ok(providers)
+    }
+
+    fn get_network_links_priority_map(&mut self) -> Result<NetworkLinksPriorityMap> {
+        let map = self.query("SELECT name, other_network, priority FROM network_links", &[])?
+            .into_iter()
+            .map(|row| ((row.get(0), row.get(1)), row.get(2)))
+            .collect::<HashMap<_, _>>();
+        Ok(map)
+    }
+
+    fn get_wireguard_keepalive_map(&mut self) -> Result<WireguardKeepaliveIntervalMap> {
+        let map = self.query("SELECT source_machine, target_machine, interval_sec FROM wireguard_keepalives", &[])?
+            .into_iter()
+            .map(|row| ((row.get(0), row.get(1)), row.get(2)))
+            .collect::<HashMap<_, _>>();
+        Ok(map)
+    }
+
+    fn get_machines_with_addresses(&mut self) -> Result<MachinesMap> {
+        let mut machines = HashMap::new();
+        for row in self.query(
+            "SELECT hostname, wireguard_ipv4_address, wireguard_ipv6_address, wireguard_port, wireguard_privkey, wireguard_pubkey,
+                    ssh_port, ssh_user, added_time, owner, provider_id, provider_reference, networks,
+                    provider_name, provider_email, state
+             FROM machines_view", &[]
+        )? {
+            let wireguard_ipv4_address_ipaddr: Option<IpAddr> = row.get(1);
+            let wireguard_ipv6_address_ipaddr: Option<IpAddr> = row.get(2);
+            let wireguard_ipv4_address = wireguard_ipv4_address_ipaddr.map(get_ipv4addr);
+            let wireguard_ipv6_address = wireguard_ipv6_address_ipaddr.map(get_ipv6addr);
+            let state: String = row.get(15);
+            let machine = Machine {
+                hostname: row.get(0),
+                state: state.parse()?,
+                wireguard_ipv4_address,
+                wireguard_ipv6_address,
+                wireguard_port: row.get(3),
+                wireguard_privkey: row.get(4),
+                wireguard_pubkey: row.get(5),
+                ssh_port: row.get(6),
+                ssh_user: row.get(7),
+                added_time: row.get(8),
+                owner: row.get(9),
+                provider_id: row.get(10),
+                provider_name: row.get(13),
+                provider_email: row.get(14),
+                provider_reference: row.get(11),
+                networks: row.get(12),
+                addresses: vec![],
+            };
+            machines.insert(machine.hostname.clone(), machine);
+        }
+        for row in self.query(
+            "SELECT hostname, network, address, ssh_port, wireguard_port
+             FROM machine_addresses
+             WHERE hostname = ANY($1)", &[&machines.keys().collect::<Vec<&String>>()]
+        )? {
+            let address = MachineAddress {
+                hostname: row.get(0),
+                network: row.get(1),
+                address: row.get(2),
+                ssh_port: row.get(3),
+                wireguard_port: row.get(4),
+            };
+            let machine = machines
+                .get_mut(&address.hostname)
+                .expect("Database gave us an address for a machine that doesn't exist");
+            machine.addresses.push(address);
+        }
+        Ok(machines)
+    }
+
+    fn get_ip_pools(&mut self) -> Result<Vec<IpPool>> {
+        let mut pools = vec![];
+        for row in self.query(
+            "SELECT name, ipv4_cidr::text, ipv6_cidr::text, reserved::text[], policy::text, ipv6_mode::text
+             FROM ip_pools ORDER BY name", &[]
+        )? {
+            let reserved: Vec<String> = row.get(3);
+            let policy: String = row.get(4);
+            let ipv6_mode: String = row.get(5);
+            pools.push(IpPool {
+                name: row.get(0),
+                ipv4_cidr: row.get::<_, String>(1).parse()?,
+                ipv6_cidr: row.get::<_, String>(2).parse()?,
+                reserved: reserved.iter().map(|cidr| cidr.parse()).collect::<Result<Vec<Cidr>>>()?,
+                policy: policy.parse()?,
+                ipv6_mode: ipv6_mode.parse()?,
+            });
+        }
+        Ok(pools)
+    }
+
+    fn get_ip_reservations(&mut self) -> Result<Vec<inventory::IpReservation>> {
+        let mut reservations = vec![];
+        for row in self.query("SELECT cidr::text, reason, added_time FROM ip_reservations ORDER BY cidr", &[])? {
+            reservations.push(inventory::IpReservation {
+                cidr: row.get::<_, String>(0).parse()?,
+                reason: row.get(1),
+                added_time: row.get(2),
+            });
+        }
+        Ok(reservations)
+    }
+
+    fn get_quarantined_wireguard_addresses(&mut self, quarantine_days: i32) -> Result<HashMap<IpAddr, DateTime<Utc>>> {
+        let map = self.query(
+            "SELECT address, max(row_end) + make_interval(days => $1) FROM (
+                 SELECT wireguard_ipv4_address AS address, row_end FROM wireguard_interfaces_history
+                 UNION ALL
+                 SELECT wireguard_ipv6_address, row_end FROM wireguard_interfaces_history
+             ) freed
+             GROUP BY address
+             HAVING max(row_end) > now() - make_interval(days => $1)",
+            &[&quarantine_days]
+        )?
+            .into_iter()
+            .map(|row| (row.get(0), row.get(1)))
+            .collect::<HashMap<IpAddr, DateTime<Utc>>>();
+        Ok(map)
+    }
+
+    fn execute_change(&mut self, change: &Change) -> Result<()> {
+        match change {
+            Change::Add(Record::Owner(owner)) => {
+                self.execute("INSERT INTO owners (owner) VALUES ($1::varchar)", &[owner])?;
+            }
+            Change::Remove(Record::Owner(owner)) => {
+                self.execute("DELETE FROM owners WHERE owner = $1", &[owner])?;
+            }
+            Change::Add(Record::Network(name)) => {
+                self.execute("INSERT INTO networks (name) VALUES ($1::varchar)", &[name])?;
+            }
+            Change::Remove(Record::Network(name)) => {
+                self.execute("DELETE FROM networks WHERE name = $1", &[name])?;
+            }
+            Change::Update(_, Record::Owner(_)) | Change::Update(_, Record::Network(_)) => {
+                unreachable!("owners and networks have nothing to update")
+            }
+            Change::Add(Record::Provider(provider)) => {
+                self.execute(
+                    "INSERT INTO providers (id, name, email) VALUES ($1, $2::varchar, $3::varchar)",
+                    &[&provider.id, &provider.name, &provider.email]
+                )?;
+                reset_provider_ids(self)?;
+            }
+            Change::Update(_, Record::Provider(provider)) => {
+                self.execute(
+                    "UPDATE providers SET name = $2::varchar, email = $3::varchar WHERE id = $1",
+                    &[&provider.id, &provider.name, &provider.email]
+                )?;
+            }
+            Change::Remove(Record::Provider(provider)) => {
+                self.execute("DELETE FROM providers WHERE id = $1", &[&provider.id])?;
+            }
+            Change::Add(Record::NetworkLink(link)) => {
+                self.execute(
+                    "INSERT INTO network_links (name, other_network, priority) VALUES ($1::varchar, $2::varchar, $3::integer)",
+                    &[&link.network, &link.other_network, &link.priority]
+                )?;
+            }
+            Change::Update(_, Record::NetworkLink(link)) => {
+                self.execute(
+                    "UPDATE network_links SET priority = $3::integer WHERE name = $1 AND other_network = $2",
+                    &[&link.network, &link.other_network, &link.priority]
+                )?;
+            }
+            Change::Remove(Record::NetworkLink(link)) => {
+                self.execute(
+                    "DELETE FROM network_links WHERE name = $1 AND other_network = $2",
+                    &[&link.network, &link.other_network]
+                )?;
+            }
+            Change::Add(Record::Machine(machine)) => insert_machine(self, machine)?,
+            Change::Update(Record::Machine(current), Record::Machine(target)) => update_machine(self, current, target)?,
+            Change::Remove(Record::Machine(machine)) => self.remove_machine(&machine.hostname)?,
+            Change::Add(Record::MachineAddress(address)) => {
+                self.execute(
+                    "INSERT INTO machine_addresses (hostname, network, address, ssh_port, wireguard_port)
+                     VALUES ($1::varchar, $2::varchar, $3::inet, $4::integer, $5::integer)",
+                    &[&address.hostname, &address.network, &address.address, &address.ssh_port, &address.wireguard_port]
+                )?;
+            }
+            Change::Update(_, Record::MachineAddress(address)) => {
+                self.execute(
+                    "UPDATE machine_addresses SET ssh_port = $4::integer, wireguard_port = $5::integer
+                     WHERE hostname = $1 AND network = $2 AND address = $3",
+                    &[&address.hostname, &address.network, &address.address, &address.ssh_port, &address.wireguard_port]
+                )?;
+            }
+            Change::Remove(Record::MachineAddress(address)) => {
+                self.remove_address(&address.hostname, &address.network, &address.address)?;
+            }
+            Change::Add(Record::WireguardKeepalive(keepalive)) => {
+                self.execute(
+                    "INSERT INTO wireguard_keepalives (source_machine, target_machine, interval_sec)
+                     VALUES ($1::varchar, $2::varchar, $3::integer)",
+                    &[&keepalive.source_machine, &keepalive.target_machine, &keepalive.interval_sec]
+                )?;
+            }
+            Change::Update(_, Record::WireguardKeepalive(keepalive)) => {
+                self.execute(
+                    "UPDATE wireguard_keepalives SET interval_sec = $3::integer WHERE source_machine = $1 AND target_machine = $2",
+                    &[&keepalive.source_machine, &keepalive.target_machine, &keepalive.interval_sec]
+                )?;
+            }
+            Change::Remove(Record::WireguardKeepalive(keepalive)) => {
+                self.remove_wireguard_keepalive(&keepalive.source_machine, &keepalive.target_machine)?;
+            }
+            Change::Update(current, target) => unreachable!("Update from {:?} to a different kind of record {:?}", current, target),
+        }
+        Ok(())
+    }
+
+    fn remove_machine(&mut self, hostname: &str) -> Result<()> {
+        self.execute("call remove_machine($1)", &[&hostname])?;
+        Ok(())
+    }
+
+    fn set_machine_state(&mut self, hostname: &str, state: MachineState) -> Result<()> {
+        set_machine_state(self, hostname, state)
+    }
+
+    fn get_removed_machine(&mut self, hostname: &str, as_of: Option<DateTime<Utc>>) -> Result<Option<RemovedMachine>> {
+        let as_of = match as_of {
+            Some(as_of) => as_of,
+            // Every row removed in one transaction has the same row_end
+            None => match self.query_one("SELECT max(row_end) FROM machines_history WHERE hostname = $1", &[&hostname])?.get::<_, Option<DateTime<Utc>>>(0) {
+                Some(removed_time) => removed_time - Duration::microseconds(1),
+                None => return Ok(None),
+            }
+        };
+        let row = match self.query_opt(
+            "SELECT machines.hostname, added_time, owner, provider_id, provider_reference,
+                    ssh_port, ssh_user,
+                    wireguard_ipv4_address, wireguard_ipv6_address, wireguard_port, wireguard_privkey, wireguard_pubkey,
+                    coalesce(machine_states.state, 'active')::varchar
+             FROM machines_history machines
+             LEFT JOIN ssh_servers_history ssh_servers
+                    ON ssh_servers.hostname = machines.hostname AND ssh_servers.row_start <= $2 AND ssh_servers.row_end > $2
+             LEFT JOIN wireguard_interfaces_history wireguard_interfaces
+                    ON wireguard_interfaces.hostname = machines.hostname AND wireguard_interfaces.row_start <= $2 AND wireguard_interfaces.row_end > $2
+             LEFT JOIN machine_states_history machine_states
+                    ON machine_states.hostname = machines.hostname AND machine_states.row_start <= $2 AND machine_states.row_end > $2
+             WHERE machines.hostname = $1 AND machines.row_start <= $2 AND machines.row_end > $2", &[&hostname, &as_of]
+        )? {
+            Some(row) => row,
+            None => return Ok(None),
+        };
+        let ssh = match (row.get(5), row.get(6)) {
+            (Some(port), Some(user)) => Some(inventory::SshServer { port, user }),
+            _ => None,
+        };
+        let wireguard = match (row.get::<_, Option<IpAddr>>(7), row.get::<_, Option<IpAddr>>(8), row.get(9), row.get(11)) {
+            (Some(ipv4_address), Some(ipv6_address), Some(port), Some(pubkey)) => Some(inventory::WireguardInterface {
+                ipv4_address: get_ipv4addr(ipv4_address),
+                ipv6_address: get_ipv6addr(ipv6_address),
+                port,
+                pubkey,
+                privkey: row.get(10),
+            }),
+            _ => None,
+        };
+        let state: String = row.get(12);
+        let machine = inventory::Machine {
+            hostname: row.get(0),
+            added_time: row.get(1),
+            owner: row.get(2),
+            state: state.parse()?,
+            provider_id: row.get(3),
+            provider_reference: row.get(4),
+            ssh,
+            wireguard,
+        };
+        let addresses = self.query(
+            "SELECT hostname, network, address, ssh_port, wireguard_port
+             FROM machine_addresses_history
+             WHERE hostname = $1 AND row_start <= $2 AND row_end > $2
+             ORDER BY network, address", &[&hostname, &as_of]
+        )?.into_iter().map(|row| inventory::MachineAddress {
+            hostname: row.get(0),
+            network: row.get(1),
+            address: row.get(2),
+            ssh_port: row.get(3),
+            wireguard_port: row.get(4),
+        }).collect();
+        let keepalives = self.query(
+            "SELECT source_machine, target_machine, interval_sec
+             FROM wireguard_keepalives_history
+             WHERE (source_machine = $1 OR target_machine = $1) AND row_start <= $2 AND row_end > $2
+             ORDER BY source_machine, target_machine", &[&hostname, &as_of]
+        )?.into_iter().map(|row| inventory::WireguardKeepalive {
+            source_machine: row.get(0),
+            target_machine: row.get(1),
+            interval_sec: row.get(2),
+        }).collect();
+        Ok(Some(RemovedMachine { as_of, machine, addresses, keepalives }))
+    }
+
+    fn remove_address(&mut self, hostname: &str, network: &str, address: &IpAddr) -> Result<u64> {
+        Ok(self.execute(
+            "DELETE FROM machine_addresses WHERE hostname = $1 AND network = $2 AND address = $3",
+            &[&hostname, &network, &address],
+        )?)
+    }
+
+    fn remove_wireguard_keepalive(&mut self, source: &str, target: &str) -> Result<u64> {
+        Ok(self.execute(
+            "DELETE FROM wireguard_keepalives WHERE source_machine = $1 AND target_machine = $2",
+            &[&source, &target],
+        )?)
+    }
+
+    fn add_ip_pool(&mut self, pool: &IpPool) -> Result<()> {
+        self.execute(
+            "INSERT INTO ip_pools (name, ipv4_cidr, ipv6_cidr, reserved, policy, ipv6_mode)
+             VALUES ($1::varchar, $2::text::cidr, $3::text::cidr, $4::text[]::cidr[], $5::text::allocation_policy, $6::text::ipv6_mode)",
+            &[
+                &pool.name,
+                &pool.ipv4_cidr.to_string(),
+                &pool.ipv6_cidr.to_string(),
+                &pool.reserved.iter().map(ToString::to_string).collect::<Vec<_>>(),
+                &pool.policy.to_string(),
+                &pool.ipv6_mode.to_string(),
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn remove_ip_pool(&mut self, name: &str) -> Result<u64> {
+        Ok(self.execute("DELETE FROM ip_pools WHERE name = $1", &[&name])?)
+    }
+
+    fn reserve_ip_range(&mut self, cidr: &Cidr, reason: Option<&str>) -> Result<()> {
+        self.execute(
+            "INSERT INTO ip_reservations (cidr, reason) VALUES ($1::text::cidr, $2)",
+            &[&cidr.to_string(), &reason],
+        )?;
+        Ok(())
+    }
+
+    fn release_ip_range(&mut self, cidr: &Cidr) -> Result<u64> {
+        Ok(self.execute("DELETE FROM ip_reservations WHERE cidr = $1::text::cidr", &[&cidr.to_string()])?)
+    }
+
+    fn lock_wireguard_addresses(&mut self) -> Result<()> {
+        self.execute("SELECT pg_advisory_xact_lock($1)", &[&WIREGUARD_ADDRESSES_LOCK])?;
+        Ok(())
+    }
+
+    fn commit(self: Box<Self>) -> Result<()> {
+        Ok((*self).commit()?)
+    }
+}
+
+/// Make the providers identity continue after the highest id, after inserting
+/// providers with explicit ids
+pub(crate) fn reset_provider_ids(transaction: &mut Transaction) -> Result<()> {
+    transaction.execute("SELECT setval(pg_get_serial_sequence('providers', 'id'), (SELECT max(id) FROM providers))", &[])?;
+    Ok(())
+}
+
+/// Insert a machine with its SSH server and WireGuard interface.  `added_time` is
+/// kept, so that a machine rebuilt by `undelete` shows when it was first added.
+fn insert_machine(transaction: &mut Transaction, machine: &inventory::Machine) -> Result<()> {
+    transaction.execute(
+        "INSERT INTO machines (hostname, added_time, owner, provider_id, provider_reference)
+                VALUES ($1::varchar, $2, $3::varchar, $4, $5)",
+        &[&machine.hostname, &machine.added_time, &machine.owner, &machine.provider_id, &machine.provider_reference]
+    )?;
+    if let Some(ssh) = &machine.ssh {
+        set_ssh_server(transaction, &machine.hostname, ssh)?;
+    }
+    if let Some(wireguard) = &machine.wireguard {
+        set_wireguard_interface(transaction, &machine.hostname, wireguard)?;
+    }
+    if machine.state != MachineState::Active {
+        set_machine_state(transaction, &machine.hostname, machine.state)?;
+    }
+    Ok(())
+}
+
+/// Update the parts of a machine that differ between `current` and `target`
+fn update_machine(transaction: &mut Transaction, current: &inventory::Machine, target: &inventory::Machine) -> Result<()> {
+    let hostname = &target.hostname;
+    if (&current.owner, current.provider_id, &current.provider_reference) != (&target.owner, target.provider_id, &target.provider_reference) {
+        transaction.execute(
+            "UPDATE machines SET owner = $2::varchar, provider_id = $3, provider_reference = $4 WHERE hostname = $1",
+            &[hostname, &target.owner, &target.provider_id, &target.provider_reference]
+        )?;
+    }
+    if current.ssh != target.ssh {
+        match &target.ssh {
+            Some(ssh) => set_ssh_server(transaction, hostname, ssh)?,
+            None => { transaction.execute("DELETE FROM ssh_servers WHERE hostname = $1", &[hostname])?; },
+        }
+    }
+    if current.wireguard != target.wireguard {
+        match &target.wireguard {
+            Some(wireguard) => set_wireguard_interface(transaction, hostname, wireguard)?,
+            None => { transaction.execute("DELETE FROM wireguard_interfaces WHERE hostname = $1", &[hostname])?; },
+        }
+    }
+    if current.state != target.state {
+        set_machine_state(transaction, hostname, target.state)?;
+    }
+    Ok(())
+}
+
+/// Insert or replace the state of a machine
+fn set_machine_state(transaction: &mut Transaction, hostname: &str, state: MachineState) -> Result<()> {
+    transaction.execute(
+        "INSERT INTO machine_states (hostname, state)
+                VALUES ($1::varchar, $2::varchar)
+         ON CONFLICT (hostname) DO UPDATE SET state = EXCLUDED.state",
+        &[&hostname, &state.as_str()]
+    )?;
+    Ok(())
+}
+
+/// Insert or replace the SSH server of a machine
+fn set_ssh_server(transaction: &mut Transaction, hostname: &str, ssh: &inventory::SshServer) -> Result<()> {
+    transaction.execute(
+        "INSERT INTO ssh_servers (hostname, ssh_port, ssh_user)
+                VALUES ($1::varchar, $2::integer, $3::varchar)
+         ON CONFLICT (hostname) DO UPDATE SET ssh_port = EXCLUDED.ssh_port, ssh_user = EXCLUDED.ssh_user",
+        &[&hostname, &ssh.port, &ssh.user]
+    )?;
+    Ok(())
+}
+
+/// Insert or replace the WireGuard interface of a machine
+fn set_wireguard_interface(transaction: &mut Transaction, hostname: &str, wireguard: &inventory::WireguardInterface) -> Result<()> {
+    let privkey = wireguard.privkey.as_ref()
+        .ok_or_else(|| anyhow!("No WireGuard privkey for machine {:?}", hostname))?;
+    transaction.execute(
+        "INSERT INTO wireguard_interfaces (hostname, wireguard_ipv4_address, wireguard_ipv6_address, wireguard_port, wireguard_privkey, wireguard_pubkey)
+                VALUES ($1::varchar, $2::inet, $3::inet, $4::integer, $5::varchar, $6::varchar)
+         ON CONFLICT (hostname) DO UPDATE SET
+                wireguard_ipv4_address = EXCLUDED.wireguard_ipv4_address,
+                wireguard_ipv6_address = EXCLUDED.wireguard_ipv6_address,
+                wireguard_port = EXCLUDED.wireguard_port,
+                wireguard_privkey = EXCLUDED.wireguard_privkey,
+                wireguard_pubkey = EXCLUDED.wireguard_pubkey",
+        &[&hostname, &IpAddr::V4(wireguard.ipv4_address), &IpAddr::V6(wireguard.ipv6_address), &wireguard.port, privkey, &wireguard.pubkey]
+    )?;
+    Ok(())
+}
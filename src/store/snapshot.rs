@@ -0,0 +1,319 @@
+//! A read-only `Store` over a file written by `i snapshot save`.
+//!
+//! A snapshot is the complete inventory as in `i export --with-privkeys`,
+//! plus the WireGuard addresses that were quarantined when it was saved, as a
+//! JSON document:
+//!
+//! ```text
+//! version                             integer, currently 2
+//! saved_time                          RFC 3339 time the snapshot was saved
+//! quarantine_days                     ipam.quarantine_days when the snapshot was saved
+//! quarantined_wireguard_addresses     { address: RFC 3339 time its quarantine ends }
+//! inventory                           the inventory document, see inventory.rs
+//! ```
+//!
+//! Everything is sorted, so saving an unchanged inventory twice gives the same
+//! file apart from `saved_time`, and output rendered from a snapshot is the
+//! same on every machine.  Snapshots are not signed by infrabase; sign and
+//! verify the file with the tools used for the rest of the deploy.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::net::IpAddr;
+use std::path::Path;
+use anyhow::{bail, ensure, Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use infrabase::model::{Machine, MachineAddress, MachineState, MachinesMap, NetworkLinksPriorityMap, WireguardKeepaliveIntervalMap};
+
+use crate::apply::Change;
+use crate::inventory;
+use crate::ipam::{Cidr, IpPool};
+use super::{RemovedMachine, Store};
+
+/// Version of the snapshot format.  Version 1 listed quarantined addresses
+/// without when their quarantine ends.
+pub(crate) const FORMAT_VERSION: u32 = 2;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct Snapshot {
+    pub version: u32,
+    pub saved_time: DateTime<Utc>,
+    pub quarantine_days: i32,
+    pub quarantined_wireguard_addresses: BTreeMap<IpAddr, DateTime<Utc>>,
+    pub inventory: inventory::Inventory,
+}
+
+impl Snapshot {
+    /// A snapshot of `inventory`, with the addresses quarantined for `quarantine_days` days
+    pub fn new(inventory: inventory::Inventory, quarantine_days: i32, quarantined: HashMap<IpAddr, DateTime<Utc>>) -> Snapshot {
+        Snapshot {
+            version: FORMAT_VERSION,
+            saved_time: Utc::now(),
+            quarantine_days,
+            quarantined_wireguard_addresses: quarantined.into_iter().collect(),
+            inventory,
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let json = serde_json::to_string_pretty(self)? + "\n";
+        fs::write(path, json).with_context(|| format!("Could not write snapshot to {:?}", path))
+    }
+
+    pub fn load(path: &Path) -> Result<Snapshot> {
+        let json = fs::read_to_string(path).with_context(|| format!("Could not read snapshot {:?}", path))?;
+        let snapshot: Snapshot = serde_json::from_str(&json).with_context(|| format!("Could not parse snapshot {:?}", path))?;
+        ensure!(snapshot.version == FORMAT_VERSION,
+                "Snapshot {:?} has version {}, but this infrabase reads version {}", path, snapshot.version, FORMAT_VERSION);
+        ensure!(snapshot.inventory.version == inventory::FORMAT_VERSION,
+                "Snapshot {:?} has an inventory of version {}, but this infrabase reads version {}", path, snapshot.inventory.version, inventory::FORMAT_VERSION);
+        Ok(snapshot)
+    }
+}
+
+/// Fail for a change to a snapshot
+fn read_only<T>() -> Result<T> {
+    bail!("A snapshot is read-only; run this command without --snapshot")
+}
+
+impl Store for &Snapshot {
+    fn get_owners(&mut self) -> Result<Vec<String>> {
+        Ok(self.inventory.owners.clone())
+    }
+
+    fn get_networks(&mut self) -> Result<Vec<String>> {
+        Ok(self.inventory.networks.clone())
+    }
+
+    fn get_providers(&mut self) -> Result<Vec<inventory::Provider>> {
+        Ok(self.inventory.providers.clone())
+    }
+
+    fn get_network_links_priority_map(&mut self) -> Result<NetworkLinksPriorityMap> {
+        Ok(self.inventory.network_links.iter()
+            .map(|link| ((link.network.clone(), link.other_network.clone()), link.priority))
+            .collect::<HashMap<_, _>>())
+    }
+
+    fn get_wireguard_keepalive_map(&mut self) -> Result<WireguardKeepaliveIntervalMap> {
+        Ok(self.inventory.wireguard_keepalives.iter()
+            .map(|keepalive| ((keepalive.source_machine.clone(), keepalive.target_machine.clone()), keepalive.interval_sec))
+            .collect::<HashMap<_, _>>())
+    }
+
+    fn get_machines_with_addresses(&mut self) -> Result<MachinesMap> {
+        let mut machines = HashMap::new();
+        for machine in &self.inventory.machines {
+            let provider = self.inventory.providers.iter().find(|provider| Some(provider.id) == machine.provider_id);
+            let wireguard = machine.wireguard.as_ref();
+            let addresses = self.inventory.machine_addresses.iter()
+                .filter(|address| address.hostname == machine.hostname)
+                .map(|address| MachineAddress {
+                    hostname: address.hostname.clone(),
+                    network: address.network.clone(),
+                    address: address.address,
+                    ssh_port: address.ssh_port,
+                    wireguard_port: address.wireguard_port,
+                })
+                .collect::<Vec<_>>();
+            // Like machines_view in Postgres
+            let networks = if addresses.is_empty() {
+                vec!["NONE".to_string()]
+            } else {
+                addresses.iter().map(|address| address.network.clone()).collect()
+            };
+            machines.insert(machine.hostname.clone(), Machine {
+                hostname: machine.hostname.clone(),
+                state: machine.state,
+                wireguard_ipv4_address: wireguard.map(|wireguard| wireguard.ipv4_address),
+                wireguard_ipv6_address: wireguard.map(|wireguard| wireguard.ipv6_address),
+                wireguard_port: wireguard.map(|wireguard| wireguard.port),
+                wireguard_privkey: wireguard.and_then(|wireguard| wireguard.privkey.clone()),
+                wireguard_pubkey: wireguard.map(|wireguard| wireguard.pubkey.clone()),
+                ssh_port: machine.ssh.as_ref().map(|ssh| ssh.port),
+                ssh_user: machine.ssh.as_ref().map(|ssh| ssh.user.clone()),
+                added_time: machine.added_time,
+                owner: machine.owner.clone(),
+                provider_id: machine.provider_id,
+                provider_name: provider.map(|provider| provider.name.clone()),
+                provider_email: provider.map(|provider| provider.email.clone()),
+                provider_reference: machine.provider_reference.clone(),
+                networks,
+                addresses,
+            });
+        }
+        Ok(machines)
+    }
+
+    fn get_ip_pools(&mut self) -> Result<Vec<IpPool>> {
+        Ok(self.inventory.ip_pools.iter()
+            .map(|pool| IpPool {
+                name: pool.name.clone(),
+                ipv4_cidr: pool.ipv4_cidr,
+                ipv6_cidr: pool.ipv6_cidr,
+                reserved: pool.reserved.clone(),
+                policy: pool.policy,
+                ipv6_mode: pool.ipv6_mode,
+            })
+            .collect())
+    }
+
+    fn get_ip_reservations(&mut self) -> Result<Vec<inventory::IpReservation>> {
+        Ok(self.inventory.ip_reservations.clone())
+    }
+
+    /// The addresses that were quarantined when the snapshot was saved, with
+    /// the `quarantine_days` of that time.  Addresses freed before that are not
+    /// in the snapshot, so a different `quarantine_days` cannot be honored.
+    fn get_quarantined_wireguard_addresses(&mut self, _quarantine_days: i32) -> Result<HashMap<IpAddr, DateTime<Utc>>> {
+        Ok(self.quarantined_wireguard_addresses.iter().map(|(address, until)| (*address, *until)).collect())
+    }
+
+    fn execute_change(&mut self, _change: &Change) -> Result<()> {
+        read_only()
+    }
+
+    fn remove_machine(&mut self, _hostname: &str) -> Result<()> {
+        read_only()
+    }
+
+    fn set_machine_state(&mut self, _hostname: &str, _state: MachineState) -> Result<()> {
+        read_only()
+    }
+
+    fn get_removed_machine(&mut self, _hostname: &str, _as_of: Option<DateTime<Utc>>) -> Result<Option<RemovedMachine>> {
+        bail!("A snapshot has no history; run this command without --snapshot")
+    }
+
+    fn remove_address(&mut self, _hostname: &str, _network: &str, _address: &IpAddr) -> Result<u64> {
+        read_only()
+    }
+
+    fn remove_wireguard_keepalive(&mut self, _source: &str, _target: &str) -> Result<u64> {
+        read_only()
+    }
+
+    fn add_ip_pool(&mut self, _pool: &IpPool) -> Result<()> {
+        read_only()
+    }
+
+    fn remove_ip_pool(&mut self, _name: &str) -> Result<u64> {
+        read_only()
+    }
+
+    fn reserve_ip_range(&mut self, _cidr: &Cidr, _reason: Option<&str>) -> Result<()> {
+        read_only()
+    }
+
+    fn release_ip_range(&mut self, _cidr: &Cidr) -> Result<u64> {
+        read_only()
+    }
+
+    fn lock_wireguard_addresses(&mut self) -> Result<()> {
+        read_only()
+    }
+
+    fn commit(self: Box<Self>) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn inventory() -> inventory::Inventory {
+        inventory::Inventory {
+            version: inventory::FORMAT_VERSION,
+            owners: vec!["ivan".to_string()],
+            networks: vec!["internet".to_string(), "lan".to_string()],
+            providers: vec![inventory::Provider { id: 1, name: "Hetzner".to_string(), email: "support@hetzner.com".to_string() }],
+            network_links: vec![inventory::NetworkLink { network: "NONE".to_string(), other_network: "internet".to_string(), priority: 100 }],
+            ip_pools: vec![],
+            ip_reservations: vec![],
+            machines: vec![
+                inventory::Machine {
+                    hostname: "web1".to_string(),
+                    added_time: Utc::now(),
+                    owner: "ivan".to_string(),
+                    state: MachineState::Active,
+                    provider_id: Some(1),
+                    provider_reference: None,
+                    ssh: Some(inventory::SshServer { port: 22, user: "root".to_string() }),
+                    wireguard: Some(inventory::WireguardInterface {
+                        ipv4_address: "10.10.0.1".parse().unwrap(),
+                        ipv6_address: "fd00::1".parse().unwrap(),
+                        port: 904,
+                        pubkey: "AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=".to_string(),
+                        privkey: Some("BBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBB=".to_string()),
+                    }),
+                },
+                inventory::Machine {
+                    hostname: "web2".to_string(),
+                    added_time: Utc::now(),
+                    owner: "ivan".to_string(),
+                    state: MachineState::Active,
+                    provider_id: None,
+                    provider_reference: None,
+                    ssh: None,
+                    wireguard: None,
+                },
+            ],
+            machine_addresses: vec![
+                inventory::MachineAddress {
+                    hostname: "web1".to_string(),
+                    network: "internet".to_string(),
+                    address: "203.0.113.5".parse().unwrap(),
+                    ssh_port: Some(22),
+                    wireguard_port: Some(904),
+                },
+                inventory::MachineAddress {
+                    hostname: "web1".to_string(),
+                    network: "lan".to_string(),
+                    address: "192.168.1.5".parse().unwrap(),
+                    ssh_port: Some(22),
+                    wireguard_port: None,
+                },
+            ],
+            wireguard_keepalives: vec![inventory::WireguardKeepalive {
+                source_machine: "web2".to_string(),
+                target_machine: "web1".to_string(),
+                interval_sec: 25,
+            }],
+        }
+    }
+
+    #[test]
+    fn test_machines() {
+        let quarantined = vec![("10.10.0.9".parse().unwrap(), Utc::now())].into_iter().collect::<HashMap<IpAddr, DateTime<Utc>>>();
+        let json = serde_json::to_string(&Snapshot::new(inventory(), 14, quarantined.clone())).unwrap();
+        let snapshot: Snapshot = serde_json::from_str(&json).unwrap();
+        let mut store = &snapshot;
+
+        let machines = store.get_machines_with_addresses().unwrap();
+        let web1 = &machines["web1"];
+        assert_eq!(web1.networks, vec!["internet", "lan"]);
+        assert_eq!(web1.addresses.len(), 2);
+        assert_eq!(web1.provider_name.as_deref(), Some("Hetzner"));
+        assert_eq!(web1.wireguard_port, Some(904));
+        assert_eq!(web1.ssh_user.as_deref(), Some("root"));
+        let web2 = &machines["web2"];
+        assert_eq!(web2.networks, vec!["NONE"]);
+        assert_eq!(web2.wireguard_pubkey, None);
+        assert_eq!(web2.provider_name, None);
+
+        assert_eq!(store.get_network_links_priority_map().unwrap()[&("NONE".to_string(), "internet".to_string())], 100);
+        assert_eq!(store.get_wireguard_keepalive_map().unwrap()[&("web2".to_string(), "web1".to_string())], 25);
+        assert_eq!(store.get_quarantined_wireguard_addresses(0).unwrap(), quarantined);
+    }
+
+    #[test]
+    fn test_read_only() {
+        let snapshot = Snapshot::new(inventory(), 14, HashMap::new());
+        let mut store: Box<dyn Store> = Box::new(&snapshot);
+        assert!(store.remove_machine("web1").is_err());
+        assert!(store.release_ip_range(&"10.10.0.1".parse().unwrap()).is_err());
+        store.commit().unwrap();
+    }
+}
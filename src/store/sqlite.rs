@@ -0,0 +1,896 @@
+//! `Store` for a SQLite transaction, on the schema in schema/sqlite.sql.
+//!
+//! SQLite has no periods extension, so history is kept by triggers instead:
+//! every table in `VERSIONED_TABLES` has a `row_start` column and a `_history`
+//! table with the same columns plus `row_end`.  Updating or deleting a row
+//! copies the old row into the history table, like system versioning does in
+//! Postgres.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::Path;
+use anyhow::{anyhow, bail, ensure, Result};
+use chrono::{DateTime, Duration, Utc};
+use rusqlite::{params, Connection, Row, ToSql, Transaction, TransactionBehavior, NO_PARAMS};
+use infrabase::model::{Machine, MachineAddress, MachineState, MachinesMap, NetworkLinksPriorityMap, WireguardKeepaliveIntervalMap};
+
+use crate::apply::{Change, Record};
+use crate::inventory;
+use crate::ipam::{get_ipv4addr, get_ipv6addr, Cidr, IpPool};
+use super::{RemovedMachine, Store};
+
+/// The schema at version 1
+const SCHEMA: &str = include_str!("../../schema/sqlite.sql");
+
+/// A change to the schema after version 1, like a migration in Postgres
+struct Upgrade {
+    version: i32,
+    sql: &'static str,
+    /// Tables created by `sql` that have history
+    versioned_tables: &'static [&'static str],
+}
+
+/// All upgrades, in the order they are applied
+const UPGRADES: &[Upgrade] = &[
+    Upgrade {
+        version: 2,
+        sql: include_str!("../../schema/sqlite_migrations/0002_machine_states.sql"),
+        versioned_tables: &["machine_states"],
+    },
+];
+
+/// The schema version this build understands, stored in `PRAGMA user_version`
+pub(crate) const SCHEMA_VERSION: i32 = 2;
+
+/// Tables in schema/sqlite.sql with history, the same ones that are versioned
+/// in Postgres
+const VERSIONED_TABLES: &[&str] = &[
+    "network_links",
+    "providers",
+    "machines",
+    "wireguard_interfaces",
+    "ssh_servers",
+    "wireguard_keepalives",
+    "ip_pools",
+    "ip_reservations",
+    "machine_addresses",
+];
+
+/// The current time in the format of the time columns
+const NOW: &str = "strftime('%Y-%m-%dT%H:%M:%fZ', 'now')";
+
+/// Open the SQLite database at `path`.  Unless `create`, the file must already exist.
+pub(crate) fn open(path: &Path, create: bool) -> Result<Connection> {
+    ensure!(create || path.exists(), "SQLite database {:?} does not exist; run `i db init` to create it", path);
+    let connection = Connection::open(path)?;
+    connection.execute_batch("PRAGMA foreign_keys = ON")?;
+    Ok(connection)
+}
+
+fn get_schema_version(connection: &Connection) -> Result<i32> {
+    Ok(connection.query_row("PRAGMA user_version", NO_PARAMS, |row| row.get(0))?)
+}
+
+/// Fail unless the schema is at exactly the version this build understands
+pub(crate) fn check_schema_version(connection: &Connection) -> Result<()> {
+    match get_schema_version(connection)? {
+        0 => bail!("The SQLite database has no infrabase schema; run `i db init` to create it"),
+        SCHEMA_VERSION => Ok(()),
+        version if version < SCHEMA_VERSION => bail!("The SQLite database schema is at version {}, but this infrabase needs version {}; run `i db migrate`", version, SCHEMA_VERSION),
+        version => bail!("The SQLite database schema is at version {}, which is newer than this infrabase understands (version {}); upgrade infrabase", version, SCHEMA_VERSION),
+    }
+}
+
+/// Get the SQL that creates the history table and triggers for `table`
+fn history_sql(table: &str, columns: &[String]) -> String {
+    let column_list = columns.join(", ");
+    let old_values = columns.iter().map(|column| format!("OLD.{column}")).collect::<Vec<_>>().join(", ");
+    let history_columns = columns.iter()
+        .map(|column| if column == "row_start" { "row_start text NOT NULL".to_string() } else { column.clone() })
+        .collect::<Vec<_>>()
+        .join(", ");
+    format!("
+        CREATE TABLE {table}_history ({history_columns}, row_end text NOT NULL);
+        CREATE INDEX {table}_history_row_end ON {table}_history (row_end);
+        CREATE TRIGGER {table}_history_update AFTER UPDATE ON {table} BEGIN
+            INSERT INTO {table}_history ({column_list}, row_end) VALUES ({old_values}, {NOW});
+            UPDATE {table} SET row_start = {NOW} WHERE rowid = NEW.rowid;
+        END;
+        CREATE TRIGGER {table}_history_delete AFTER DELETE ON {table} BEGIN
+            INSERT INTO {table}_history ({column_list}, row_end) VALUES ({old_values}, {NOW});
+        END;
+    ")
+}
+
+/// Create the history table and triggers for an existing `table`
+fn add_history(transaction: &Transaction, table: &str) -> Result<()> {
+    let columns = query(transaction, &format!("PRAGMA table_info({table})"), NO_PARAMS, |row| Ok(row.get::<_, String>(1)?))?;
+    transaction.execute_batch(&history_sql(table, &columns))?;
+    Ok(())
+}
+
+/// Apply the upgrades after `version`, returning their versions
+fn upgrade(transaction: &Transaction, version: i32) -> Result<Vec<i32>> {
+    let mut applied = vec![];
+    for upgrade in UPGRADES.iter().filter(|upgrade| upgrade.version > version) {
+        transaction.execute_batch(upgrade.sql)?;
+        for table in upgrade.versioned_tables {
+            add_history(transaction, table)?;
+        }
+        transaction.execute_batch(&format!("PRAGMA user_version = {}", upgrade.version))?;
+        applied.push(upgrade.version);
+    }
+    Ok(applied)
+}
+
+/// Create the tables, history tables, and triggers in an empty database
+pub(crate) fn init(connection: &mut Connection) -> Result<()> {
+    let transaction = connection.transaction()?;
+    let tables: i64 = transaction.query_row("SELECT count(*) FROM sqlite_master WHERE type = 'table'", NO_PARAMS, |row| row.get(0))?;
+    ensure!(get_schema_version(&transaction)? == 0 && tables == 0, "The SQLite database is not empty");
+    transaction.execute_batch(SCHEMA)?;
+    for table in VERSIONED_TABLES {
+        add_history(&transaction, table)?;
+    }
+    upgrade(&transaction, 1)?;
+    transaction.commit()?;
+    Ok(())
+}
+
+/// Bring the schema of an existing database up to `SCHEMA_VERSION`, returning
+/// the versions that were applied
+pub(crate) fn migrate(connection: &mut Connection) -> Result<Vec<i32>> {
+    let transaction = connection.transaction_with_behavior(TransactionBehavior::Immediate)?;
+    let version = get_schema_version(&transaction)?;
+    ensure!(version != 0, "The SQLite database has no infrabase schema; run `i db init` to create it");
+    ensure!(version <= SCHEMA_VERSION,
+        "The SQLite database schema is at version {}, which is newer than this infrabase understands (version {})", version, SCHEMA_VERSION);
+    let applied = upgrade(&transaction, version)?;
+    transaction.commit()?;
+    Ok(applied)
+}
+
+/// Run `sql` and convert each row with `f`
+fn query<T, P>(connection: &Connection, sql: &str, params: P, mut f: impl FnMut(&Row) -> Result<T>) -> Result<Vec<T>>
+where
+    P: IntoIterator,
+    P::Item: ToSql,
+{
+    let mut statement = connection.prepare(sql)?;
+    let mut rows = statement.query(params)?;
+    let mut results = vec![];
+    while let Some(row) = rows.next()? {
+        results.push(f(row)?);
+    }
+    Ok(results)
+}
+
+fn get_address(row: &Row, index: usize) -> Result<IpAddr> {
+    Ok(row.get::<_, String>(index)?.parse()?)
+}
+
+fn get_optional_address(row: &Row, index: usize) -> Result<Option<IpAddr>> {
+    row.get::<_, Option<String>>(index)?.map(|s| Ok(s.parse()?)).transpose()
+}
+
+fn get_time(row: &Row, index: usize) -> Result<DateTime<Utc>> {
+    Ok(row.get::<_, String>(index)?.parse()?)
+}
+
+/// Format a time like `NOW`, so that it compares as text with the stored times
+fn format_time(time: &DateTime<Utc>) -> String {
+    time.format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string()
+}
+
+impl Store for Transaction<'_> {
+    fn get_owners(&mut self) -> Result<Vec<String>> {
+        query(self, "SELECT owner FROM owners ORDER BY owner", NO_PARAMS, |row| Ok(row.get(0)?))
+    }
+
+    fn get_networks(&mut self) -> Result<Vec<String>> {
+        query(self, "SELECT name FROM networks ORDER BY name", NO_PARAMS, |row| Ok(row.get(0)?))
+    }
+
+    fn get_providers(&mut self) -> Result<Vec<inventory::Provider>> {
+        query(self, "SELECT id, name, email FROM providers ORDER BY id", NO_PARAMS, |row| {
+            Ok(inventory::Provider { id: row.get(0)?, name: row.get(1)?, email: row.get(2)? })
+        })
+    }
+
+    fn get_network_links_priority_map(&mut self) -> Result<NetworkLinksPriorityMap> {
+        let links = query(self, "SELECT name, other_network, priority FROM network_links", NO_PARAMS, |row| {
+            Ok(((row.get(0)?, row.get(1)?), row.get(2)?))
+        })?;
+        Ok(links.into_iter().collect::<HashMap<_, _>>())
+    }
+
+    fn get_wireguard_keepalive_map(&mut self) -> Result<WireguardKeepaliveIntervalMap> {
+        let keepalives = query(self, "SELECT source_machine, target_machine, interval_sec FROM wireguard_keepalives", NO_PARAMS, |row| {
+            Ok(((row.get(0)?, row.get(1)?), row.get(2)?))
+        })?;
+        Ok(keepalives.into_iter().collect::<HashMap<_, _>>())
+    }
+
+    fn get_machines_with_addresses(&mut self) -> Result<MachinesMap> {
+        let mut machines = HashMap::new();
+        let rows = query(self,
+            "SELECT machines.hostname, wireguard_ipv4_address, wireguard_ipv6_address, wireguard_port, wireguard_privkey, wireguard_pubkey,
+                    ssh_port, ssh_user, added_time, owner, provider_id, provider_reference,
+                    providers.name, providers.email, coalesce(machine_states.state, 'active')
+             FROM machines
+             LEFT JOIN wireguard_interfaces ON machines.hostname    = wireguard_interfaces.hostname
+             LEFT JOIN ssh_servers          ON machines.hostname    = ssh_servers.hostname
+             LEFT JOIN machine_states       ON machines.hostname    = machine_states.hostname
+             LEFT JOIN providers            ON machines.provider_id = providers.id", NO_PARAMS, |row| {
+            Ok(Machine {
+                hostname: row.get(0)?,
+                state: row.get::<_, String>(14)?.parse()?,
+                wireguard_ipv4_address: get_optional_address(row, 1)?.map(get_ipv4addr),
+                wireguard_ipv6_address: get_optional_address(row, 2)?.map(get_ipv6addr),
+                wireguard_port: row.get(3)?,
+                wireguard_privkey: row.get(4)?,
+                wireguard_pubkey: row.get(5)?,
+                ssh_port: row.get(6)?,
+                ssh_user: row.get(7)?,
+                added_time: get_time(row, 8)?,
+                owner: row.get(9)?,
+                provider_id: row.get(10)?,
+                provider_name: row.get(12)?,
+                provider_email: row.get(13)?,
+                provider_reference: row.get(11)?,
+                networks: vec![],
+                addresses: vec![],
+            })
+        })?;
+        for machine in rows {
+            machines.insert(machine.hostname.clone(), machine);
+        }
+        let addresses = query(self, "SELECT hostname, network, address, ssh_port, wireguard_port FROM machine_addresses", NO_PARAMS, |row| {
+            Ok(MachineAddress {
+                hostname: row.get(0)?,
+                network: row.get(1)?,
+                address: get_address(row, 2)?,
+                ssh_port: row.get(3)?,
+                wireguard_port: row.get(4)?,
+            })
+        })?;
+        for address in addresses {
+            let machine = machines
+                .get_mut(&address.hostname)
+                .expect("Database gave us an address for a machine that doesn't exist");
+            machine.networks.push(address.network.clone());
+            machine.addresses.push(address);
+        }
+        // Like machines_view in Postgres
+        for machine in machines.values_mut() {
+            if machine.networks.is_empty() {
+                machine.networks.push("NONE".to_string());
+            }
+        }
+        Ok(machines)
+    }
+
+    fn get_ip_pools(&mut self) -> Result<Vec<IpPool>> {
+        query(self, "SELECT name, ipv4_cidr, ipv6_cidr, reserved, policy, ipv6_mode FROM ip_pools ORDER BY name", NO_PARAMS, |row| {
+            let reserved: String = row.get(3)?;
+            Ok(IpPool {
+                name: row.get(0)?,
+                ipv4_cidr: row.get::<_, String>(1)?.parse()?,
+                ipv6_cidr: row.get::<_, String>(2)?.parse()?,
+                reserved: reserved.split_whitespace().map(|cidr| cidr.parse()).collect::<Result<Vec<Cidr>>>()?,
+                policy: row.get::<_, String>(4)?.parse()?,
+                ipv6_mode: row.get::<_, String>(5)?.parse()?,
+            })
+        })
+    }
+
+    fn get_ip_reservations(&mut self) -> Result<Vec<inventory::IpReservation>> {
+        let mut reservations = query(self, "SELECT cidr, reason, added_time FROM ip_reservations", NO_PARAMS, |row| {
+            Ok(inventory::IpReservation {
+                cidr: row.get::<_, String>(0)?.parse()?,
+                reason: row.get(1)?,
+                added_time: get_time(row, 2)?,
+            })
+        })?;
+        // The order of cidr in Postgres, which text does not have
+        reservations.sort_unstable_by_key(|reservation| (reservation.cidr.first(), reservation.cidr.prefix_len()));
+        Ok(reservations)
+    }
+
+    fn get_quarantined_wireguard_addresses(&mut self, quarantine_days: i32) -> Result<HashMap<IpAddr, DateTime<Utc>>> {
+        let since = "strftime('%Y-%m-%dT%H:%M:%fZ', 'now', '-' || ?1 || ' days')";
+        let freed = query(self, &format!(
+            "SELECT address, max(row_end) FROM (
+                 SELECT wireguard_ipv4_address AS address, row_end FROM wireguard_interfaces_history
+                 UNION ALL
+                 SELECT wireguard_ipv6_address, row_end FROM wireguard_interfaces_history
+             )
+             GROUP BY address
+             HAVING max(row_end) > {since}"
+        ), params![quarantine_days], |row| Ok((get_address(row, 0)?, get_time(row, 1)?)))?;
+        Ok(freed.into_iter().map(|(address, freed_time)| (address, freed_time + Duration::days(i64::from(quarantine_days)))).collect())
+    }
+
+    fn execute_change(&mut self, change: &Change) -> Result<()> {
+        match change {
+            Change::Add(Record::Owner(owner)) => {
+                self.execute("INSERT INTO owners (owner) VALUES (?1)", params![owner])?;
+            }
+            Change::Remove(Record::Owner(owner)) => {
+                self.execute("DELETE FROM owners WHERE owner = ?1", params![owner])?;
+            }
+            Change::Add(Record::Network(name)) => {
+                self.execute("INSERT INTO networks (name) VALUES (?1)", params![name])?;
+            }
+            Change::Remove(Record::Network(name)) => {
+                self.execute("DELETE FROM networks WHERE name = ?1", params![name])?;
+            }
+            Change::Update(_, Record::Owner(_)) | Change::Update(_, Record::Network(_)) => {
+                unreachable!("owners and networks have nothing to update")
+            }
+            Change::Add(Record::Provider(provider)) => {
+                self.execute(
+                    "INSERT INTO providers (id, name, email) VALUES (?1, ?2, ?3)",
+                    params![provider.id, provider.name, provider.email]
+                )?;
+            }
+            Change::Update(_, Record::Provider(provider)) => {
+                self.execute(
+                    "UPDATE providers SET name = ?2, email = ?3 WHERE id = ?1",
+                    params![provider.id, provider.name, provider.email]
+                )?;
+            }
+            Change::Remove(Record::Provider(provider)) => {
+                self.execute("DELETE FROM providers WHERE id = ?1", params![provider.id])?;
+            }
+            Change::Add(Record::NetworkLink(link)) => {
+                self.execute(
+                    "INSERT INTO network_links (name, other_network, priority) VALUES (?1, ?2, ?3)",
+                    params![link.network, link.other_network, link.priority]
+                )?;
+            }
+            Change::Update(_, Record::NetworkLink(link)) => {
+                self.execute(
+                    "UPDATE network_links SET priority = ?3 WHERE name = ?1 AND other_network = ?2",
+                    params![link.network, link.other_network, link.priority]
+                )?;
+            }
+            Change::Remove(Record::NetworkLink(link)) => {
+                self.execute(
+                    "DELETE FROM network_links WHERE name = ?1 AND other_network = ?2",
+                    params![link.network, link.other_network]
+                )?;
+            }
+            Change::Add(Record::Machine(machine)) => insert_machine(self, machine)?,
+            Change::Update(Record::Machine(current), Record::Machine(target)) => update_machine(self, current, target)?,
+            Change::Remove(Record::Machine(machine)) => self.remove_machine(&machine.hostname)?,
+            Change::Add(Record::MachineAddress(address)) => {
+                self.execute(
+                    "INSERT INTO machine_addresses (hostname, network, address, ssh_port, wireguard_port)
+                     VALUES (?1, ?2, ?3, ?4, ?5)",
+                    params![address.hostname, address.network, address.address.to_string(), address.ssh_port, address.wireguard_port]
+                )?;
+            }
+            Change::Update(_, Record::MachineAddress(address)) => {
+                self.execute(
+                    "UPDATE machine_addresses SET ssh_port = ?4, wireguard_port = ?5
+                     WHERE hostname = ?1 AND network = ?2 AND address = ?3",
+                    params![address.hostname, address.network, address.address.to_string(), address.ssh_port, address.wireguard_port]
+                )?;
+            }
+            Change::Remove(Record::MachineAddress(address)) => {
+                self.remove_address(&address.hostname, &address.network, &address.address)?;
+            }
+            Change::Add(Record::WireguardKeepalive(keepalive)) => {
+                self.execute(
+                    "INSERT INTO wireguard_keepalives (source_machine, target_machine, interval_sec) VALUES (?1, ?2, ?3)",
+                    params![keepalive.source_machine, keepalive.target_machine, keepalive.interval_sec]
+                )?;
+            }
+            Change::Update(_, Record::WireguardKeepalive(keepalive)) => {
+                self.execute(
+                    "UPDATE wireguard_keepalives SET interval_sec = ?3 WHERE source_machine = ?1 AND target_machine = ?2",
+                    params![keepalive.source_machine, keepalive.target_machine, keepalive.interval_sec]
+                )?;
+            }
+            Change::Remove(Record::WireguardKeepalive(keepalive)) => {
+                self.remove_wireguard_keepalive(&keepalive.source_machine, &keepalive.target_machine)?;
+            }
+            Change::Update(current, target) => unreachable!("Update from {:?} to a different kind of record {:?}", current, target),
+        }
+        Ok(())
+    }
+
+    fn remove_machine(&mut self, hostname: &str) -> Result<()> {
+        let start: String = self.query_row(&format!("SELECT {NOW}"), NO_PARAMS, |row| row.get(0))?;
+        // Like the remove_machine procedure in Postgres
+        self.execute("DELETE FROM wireguard_interfaces WHERE hostname = ?1", params![hostname])?;
+        self.execute("DELETE FROM ssh_servers          WHERE hostname = ?1", params![hostname])?;
+        self.execute("DELETE FROM machine_addresses    WHERE hostname = ?1", params![hostname])?;
+        self.execute("DELETE FROM wireguard_keepalives WHERE source_machine = ?1 OR target_machine = ?1", params![hostname])?;
+        self.execute("DELETE FROM machine_states       WHERE hostname = ?1", params![hostname])?;
+        self.execute("DELETE FROM machines             WHERE hostname = ?1", params![hostname])?;
+        // Each DELETE above ended its rows at a slightly different time.  Give
+        // them all the row_end of the machine, like Postgres does for every row
+        // removed in one transaction, so that get_removed_machine finds them.
+        let removed_time: Option<String> = self.query_row(
+            "SELECT max(row_end) FROM machines_history WHERE hostname = ?1", params![hostname], |row| row.get(0)
+        )?;
+        let removed_time = match removed_time {
+            Some(removed_time) if removed_time >= start => removed_time,
+            _ => return Ok(()),
+        };
+        for (table, condition) in &[
+            ("wireguard_interfaces", "hostname = ?1"),
+            ("ssh_servers", "hostname = ?1"),
+            ("machine_addresses", "hostname = ?1"),
+            ("wireguard_keepalives", "(source_machine = ?1 OR target_machine = ?1)"),
+            ("machine_states", "hostname = ?1"),
+        ] {
+            self.execute(
+                &format!("UPDATE {table}_history SET row_end = ?3 WHERE {condition} AND row_end >= ?2"),
+                params![hostname, start, removed_time],
+            )?;
+        }
+        Ok(())
+    }
+
+    fn get_removed_machine(&mut self, hostname: &str, as_of: Option<DateTime<Utc>>) -> Result<Option<RemovedMachine>> {
+        let as_of = match as_of {
+            Some(as_of) => as_of,
+            // Every row removed by remove_machine has the same row_end
+            None => match self.query_row("SELECT max(row_end) FROM machines_history WHERE hostname = ?1", params![hostname], |row| row.get::<_, Option<String>>(0))? {
+                Some(removed_time) => removed_time.parse::<DateTime<Utc>>()? - Duration::milliseconds(1),
+                None => return Ok(None),
+            }
+        };
+        let as_of_text = format_time(&as_of);
+        let mut machines = query(self,
+            "SELECT machines.hostname, added_time, owner, provider_id, provider_reference,
+                    ssh_port, ssh_user,
+                    wireguard_ipv4_address, wireguard_ipv6_address, wireguard_port, wireguard_privkey, wireguard_pubkey,
+                    coalesce(machine_states.state, 'active')
+             FROM machines_history machines
+             LEFT JOIN ssh_servers_history ssh_servers
+                    ON ssh_servers.hostname = machines.hostname AND ssh_servers.row_start <= ?2 AND ssh_servers.row_end > ?2
+             LEFT JOIN wireguard_interfaces_history wireguard_interfaces
+                    ON wireguard_interfaces.hostname = machines.hostname AND wireguard_interfaces.row_start <= ?2 AND wireguard_interfaces.row_end > ?2
+             LEFT JOIN machine_states_history machine_states
+                    ON machine_states.hostname = machines.hostname AND machine_states.row_start <= ?2 AND machine_states.row_end > ?2
+             WHERE machines.hostname = ?1 AND machines.row_start <= ?2 AND machines.row_end > ?2", params![hostname, as_of_text], |row| {
+            let ssh = match (row.get(5)?, row.get(6)?) {
+                (Some(port), Some(user)) => Some(inventory::SshServer { port, user }),
+                _ => None,
+            };
+            let wireguard = match (get_optional_address(row, 7)?, get_optional_address(row, 8)?, row.get(9)?, row.get(11)?) {
+                (Some(ipv4_address), Some(ipv6_address), Some(port), Some(pubkey)) => Some(inventory::WireguardInterface {
+                    ipv4_address: get_ipv4addr(ipv4_address),
+                    ipv6_address: get_ipv6addr(ipv6_address),
+                    port,
+                    pubkey,
+                    privkey: row.get(10)?,
+                }),
+                _ => None,
+            };
+            Ok(inventory::Machine {
+                hostname: row.get(0)?,
+                added_time: get_time(row, 1)?,
+                owner: row.get(2)?,
+                state: row.get::<_, String>(12)?.parse()?,
+                provider_id: row.get(3)?,
+                provider_reference: row.get(4)?,
+                ssh,
+                wireguard,
+            })
+        })?;
+        let machine = match machines.pop() {
+            Some(machine) => machine,
+            None => return Ok(None),
+        };
+        let addresses = query(self,
+            "SELECT hostname, network, address, ssh_port, wireguard_port
+             FROM machine_addresses_history
+             WHERE hostname = ?1 AND row_start <= ?2 AND row_end > ?2
+             ORDER BY network, address", params![hostname, as_of_text], |row| {
+            Ok(inventory::MachineAddress {
+                hostname: row.get(0)?,
+                network: row.get(1)?,
+                address: get_address(row, 2)?,
+                ssh_port: row.get(3)?,
+                wireguard_port: row.get(4)?,
+            })
+        })?;
+        let keepalives = query(self,
+            "SELECT source_machine, target_machine, interval_sec
+             FROM wireguard_keepalives_history
+             WHERE (source_machine = ?1 OR target_machine = ?1) AND row_start <= ?2 AND row_end > ?2
+             ORDER BY source_machine, target_machine", params![hostname, as_of_text], |row| {
+            Ok(inventory::WireguardKeepalive {
+                source_machine: row.get(0)?,
+                target_machine: row.get(1)?,
+                interval_sec: row.get(2)?,
+            })
+        })?;
+        Ok(Some(RemovedMachine { as_of, machine, addresses, keepalives }))
+    }
+
+    fn set_machine_state(&mut self, hostname: &str, state: MachineState) -> Result<()> {
+        set_machine_state(self, hostname, state)
+    }
+
+    fn remove_address(&mut self, hostname: &str, network: &str, address: &IpAddr) -> Result<u64> {
+        let num_deleted = self.execute(
+            "DELETE FROM machine_addresses WHERE hostname = ?1 AND network = ?2 AND address = ?3",
+            params![hostname, network, address.to_string()],
+        )?;
+        Ok(num_deleted as u64)
+    }
+
+    fn remove_wireguard_keepalive(&mut self, source: &str, target: &str) -> Result<u64> {
+        let num_deleted = self.execute(
+            "DELETE FROM wireguard_keepalives WHERE source_machine = ?1 AND target_machine = ?2",
+            params![source, target],
+        )?;
+        Ok(num_deleted as u64)
+    }
+
+    fn add_ip_pool(&mut self, pool: &IpPool) -> Result<()> {
+        self.execute(
+            "INSERT INTO ip_pools (name, ipv4_cidr, ipv6_cidr, reserved, policy, ipv6_mode) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                pool.name,
+                pool.ipv4_cidr.to_string(),
+                pool.ipv6_cidr.to_string(),
+                pool.reserved.iter().map(ToString::to_string).collect::<Vec<_>>().join(" "),
+                pool.policy.to_string(),
+                pool.ipv6_mode.to_string(),
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn remove_ip_pool(&mut self, name: &str) -> Result<u64> {
+        Ok(self.execute("DELETE FROM ip_pools WHERE name = ?1", params![name])? as u64)
+    }
+
+    fn reserve_ip_range(&mut self, cidr: &Cidr, reason: Option<&str>) -> Result<()> {
+        self.execute("INSERT INTO ip_reservations (cidr, reason) VALUES (?1, ?2)", params![cidr.to_string(), reason])?;
+        Ok(())
+    }
+
+    fn release_ip_range(&mut self, cidr: &Cidr) -> Result<u64> {
+        Ok(self.execute("DELETE FROM ip_reservations WHERE cidr = ?1", params![cidr.to_string()])? as u64)
+    }
+
+    fn lock_wireguard_addresses(&mut self) -> Result<()> {
+        // Transactions that can write begin with BEGIN IMMEDIATE (see
+        // `Database::store`), so this one already holds the only write lock.
+        Ok(())
+    }
+
+    fn commit(self: Box<Self>) -> Result<()> {
+        Ok((*self).commit()?)
+    }
+}
+
+/// Insert a machine with its SSH server and WireGuard interface.  `added_time` is
+/// kept, so that a machine rebuilt by `undelete` shows when it was first added.
+fn insert_machine(transaction: &Transaction, machine: &inventory::Machine) -> Result<()> {
+    transaction.execute(
+        "INSERT INTO machines (hostname, added_time, owner, provider_id, provider_reference) VALUES (?1, ?2, ?3, ?4, ?5)",
+        params![machine.hostname, format_time(&machine.added_time), machine.owner, machine.provider_id, machine.provider_reference]
+    )?;
+    if let Some(ssh) = &machine.ssh {
+        set_ssh_server(transaction, &machine.hostname, ssh)?;
+    }
+    if let Some(wireguard) = &machine.wireguard {
+        set_wireguard_interface(transaction, &machine.hostname, wireguard)?;
+    }
+    if machine.state != MachineState::Active {
+        set_machine_state(transaction, &machine.hostname, machine.state)?;
+    }
+    Ok(())
+}
+
+/// Update the parts of a machine that differ between `current` and `target`
+fn update_machine(transaction: &Transaction, current: &inventory::Machine, target: &inventory::Machine) -> Result<()> {
+    let hostname = &target.hostname;
+    if (&current.owner, current.provider_id, &current.provider_reference) != (&target.owner, target.provider_id, &target.provider_reference) {
+        transaction.execute(
+            "UPDATE machines SET owner = ?2, provider_id = ?3, provider_reference = ?4 WHERE hostname = ?1",
+            params![hostname, target.owner, target.provider_id, target.provider_reference]
+        )?;
+    }
+    if current.ssh != target.ssh {
+        match &target.ssh {
+            Some(ssh) => set_ssh_server(transaction, hostname, ssh)?,
+            None => { transaction.execute("DELETE FROM ssh_servers WHERE hostname = ?1", params![hostname])?; },
+        }
+    }
+    if current.wireguard != target.wireguard {
+        match &target.wireguard {
+            Some(wireguard) => set_wireguard_interface(transaction, hostname, wireguard)?,
+            None => { transaction.execute("DELETE FROM wireguard_interfaces WHERE hostname = ?1", params![hostname])?; },
+        }
+    }
+    if current.state != target.state {
+        set_machine_state(transaction, hostname, target.state)?;
+    }
+    Ok(())
+}
+
+/// Insert or replace the state of a machine
+fn set_machine_state(transaction: &Transaction, hostname: &str, state: MachineState) -> Result<()> {
+    transaction.execute(
+        "INSERT INTO machine_states (hostname, state) VALUES (?1, ?2)
+         ON CONFLICT (hostname) DO UPDATE SET state = excluded.state",
+        params![hostname, state.as_str()]
+    )?;
+    Ok(())
+}
+
+/// Insert or replace the SSH server of a machine
+fn set_ssh_server(transaction: &Transaction, hostname: &str, ssh: &inventory::SshServer) -> Result<()> {
+    transaction.execute(
+        "INSERT INTO ssh_servers (hostname, ssh_port, ssh_user) VALUES (?1, ?2, ?3)
+         ON CONFLICT (hostname) DO UPDATE SET ssh_port = excluded.ssh_port, ssh_user = excluded.ssh_user",
+        params![hostname, ssh.port, ssh.user]
+    )?;
+    Ok(())
+}
+
+/// Insert or replace the WireGuard interface of a machine
+fn set_wireguard_interface(transaction: &Transaction, hostname: &str, wireguard: &inventory::WireguardInterface) -> Result<()> {
+    let privkey = wireguard.privkey.as_ref()
+        .ok_or_else(|| anyhow!("No WireGuard privkey for machine {:?}", hostname))?;
+    transaction.execute(
+        "INSERT INTO wireguard_interfaces (hostname, wireguard_ipv4_address, wireguard_ipv6_address, wireguard_port, wireguard_privkey, wireguard_pubkey)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+         ON CONFLICT (hostname) DO UPDATE SET
+                wireguard_ipv4_address = excluded.wireguard_ipv4_address,
+                wireguard_ipv6_address = excluded.wireguard_ipv6_address,
+                wireguard_port = excluded.wireguard_port,
+                wireguard_privkey = excluded.wireguard_privkey,
+                wireguard_pubkey = excluded.wireguard_pubkey",
+        params![
+            hostname,
+            wireguard.ipv4_address.to_string(),
+            wireguard.ipv6_address.to_string(),
+            wireguard.port,
+            privkey,
+            wireguard.pubkey,
+        ]
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ipam::{AllocationPolicy, Ipv6Mode};
+    use crate::test_fixtures::{machine, PRIVKEY};
+
+    fn database() -> Connection {
+        let mut connection = Connection::open_in_memory().unwrap();
+        connection.execute_batch("PRAGMA foreign_keys = ON").unwrap();
+        init(&mut connection).unwrap();
+        connection
+    }
+
+    fn add(store: &mut dyn Store, record: Record) {
+        store.execute_change(&Change::Add(record)).unwrap();
+    }
+
+    #[test]
+    fn test_init() {
+        let mut connection = database();
+        check_schema_version(&connection).unwrap();
+        // Refuses to initialize twice
+        assert!(init(&mut connection).is_err());
+        let empty = Connection::open_in_memory().unwrap();
+        assert!(check_schema_version(&empty).is_err());
+    }
+
+    #[test]
+    fn test_migrate() {
+        let mut connection = Connection::open_in_memory().unwrap();
+        connection.execute_batch(SCHEMA).unwrap();
+        connection.execute_batch("PRAGMA user_version = 1").unwrap();
+        assert!(check_schema_version(&connection).is_err());
+        assert_eq!(migrate(&mut connection).unwrap(), (2..=SCHEMA_VERSION).collect::<Vec<_>>());
+        check_schema_version(&connection).unwrap();
+        let history: i64 = connection.query_row(
+            "SELECT count(*) FROM sqlite_master WHERE name = 'machine_states_history'", NO_PARAMS, |row| row.get(0)
+        ).unwrap();
+        assert_eq!(history, 1);
+        assert!(migrate(&mut connection).unwrap().is_empty());
+        assert_eq!(UPGRADES.last().unwrap().version, SCHEMA_VERSION);
+    }
+
+    #[test]
+    fn test_machine_states() {
+        let mut connection = database();
+        let mut store: Box<dyn Store> = Box::new(connection.transaction().unwrap());
+        add(&mut *store, Record::Owner("ivan".to_string()));
+        add(&mut *store, Record::Machine(inventory::Machine { state: MachineState::Planned, ..machine("web1", "10.10.0.1", "fd00::1") }));
+        assert_eq!(store.get_machines_with_addresses().unwrap()["web1"].state, MachineState::Planned);
+        store.set_machine_state("web1", MachineState::Retired).unwrap();
+        assert_eq!(store.get_machines_with_addresses().unwrap()["web1"].state, MachineState::Retired);
+        store.remove_machine("web1").unwrap();
+        assert!(store.get_machines_with_addresses().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_machines() {
+        let mut connection = database();
+        let mut store: Box<dyn Store> = Box::new(connection.transaction().unwrap());
+        add(&mut *store, Record::Owner("ivan".to_string()));
+        add(&mut *store, Record::Network("internet".to_string()));
+        add(&mut *store, Record::Machine(machine("web1", "10.10.0.1", "fd00::1")));
+        add(&mut *store, Record::MachineAddress(inventory::MachineAddress {
+            hostname: "web1".to_string(),
+            network: "internet".to_string(),
+            address: "203.0.113.5".parse().unwrap(),
+            ssh_port: Some(22),
+            wireguard_port: Some(904),
+        }));
+        store.commit().unwrap();
+
+        let mut store: Box<dyn Store> = Box::new(connection.transaction().unwrap());
+        let machines = store.get_machines_with_addresses().unwrap();
+        let web1 = &machines["web1"];
+        assert_eq!(web1.wireguard_ipv4_address, Some("10.10.0.1".parse().unwrap()));
+        assert_eq!(web1.wireguard_ipv6_address, Some("fd00::1".parse().unwrap()));
+        assert_eq!(web1.ssh_user.as_deref(), Some("root"));
+        assert_eq!(web1.networks, vec!["internet"]);
+        assert_eq!(web1.addresses[0].address, "203.0.113.5".parse::<IpAddr>().unwrap());
+        assert_eq!(store.remove_address("web1", "internet", &"203.0.113.5".parse().unwrap()).unwrap(), 1);
+        assert_eq!(store.remove_address("web1", "internet", &"203.0.113.5".parse().unwrap()).unwrap(), 0);
+        assert_eq!(store.get_machines_with_addresses().unwrap()["web1"].networks, vec!["NONE"]);
+    }
+
+    /// Removed WireGuard addresses stay in the history, so that they are quarantined
+    #[test]
+    fn test_history() {
+        let mut connection = database();
+        let mut store: Box<dyn Store> = Box::new(connection.transaction().unwrap());
+        add(&mut *store, Record::Owner("ivan".to_string()));
+        add(&mut *store, Record::Machine(machine("web1", "10.10.0.1", "fd00::1")));
+        assert!(store.get_quarantined_wireguard_addresses(14).unwrap().is_empty());
+        store.remove_machine("web1").unwrap();
+        assert!(store.get_machines_with_addresses().unwrap().is_empty());
+        let quarantined = store.get_quarantined_wireguard_addresses(14).unwrap();
+        assert!(quarantined.contains_key(&"10.10.0.1".parse().unwrap()));
+        assert!(quarantined.contains_key(&"fd00::1".parse().unwrap()));
+        let until = quarantined[&"10.10.0.1".parse().unwrap()];
+        assert!(until > Utc::now() + Duration::days(13) && until <= Utc::now() + Duration::days(14));
+        assert!(store.get_quarantined_wireguard_addresses(0).unwrap().is_empty());
+
+        // The next machine does not get the freed addresses
+        let pool = IpPool {
+            name: "mesh".to_string(),
+            ipv4_cidr: "10.10.0.0/29".parse().unwrap(),
+            ipv6_cidr: "fd00::/125".parse().unwrap(),
+            reserved: vec![],
+            policy: AllocationPolicy::LowestFree,
+            ipv6_mode: Ipv6Mode::Independent,
+        };
+        let (ipv4, ipv6) = pool.allocate(None, None, &quarantined.keys().copied().collect()).unwrap();
+        assert_eq!((ipv4.to_string(), ipv6.to_string()), ("10.10.0.2".to_string(), "fd00::2".to_string()));
+        store.commit().unwrap();
+
+        let history: i64 = connection.query_row("SELECT count(*) FROM machines_history", NO_PARAMS, |row| row.get(0)).unwrap();
+        assert_eq!(history, 1);
+    }
+
+    /// A removed machine is found in the history with everything removed with it
+    #[test]
+    fn test_removed_machine() {
+        let mut connection = database();
+        let mut store: Box<dyn Store> = Box::new(connection.transaction().unwrap());
+        add(&mut *store, Record::Owner("ivan".to_string()));
+        add(&mut *store, Record::Network("internet".to_string()));
+        let added_time = "2020-06-01T00:00:00Z".parse().unwrap();
+        add(&mut *store, Record::Machine(inventory::Machine { state: MachineState::Planned, added_time, ..machine("web1", "10.10.0.1", "fd00::1") }));
+        add(&mut *store, Record::Machine(inventory::Machine { wireguard: None, ..machine("web2", "10.10.0.2", "fd00::2") }));
+        add(&mut *store, Record::MachineAddress(inventory::MachineAddress {
+            hostname: "web1".to_string(),
+            network: "internet".to_string(),
+            address: "203.0.113.5".parse().unwrap(),
+            ssh_port: Some(22),
+            wireguard_port: Some(904),
+        }));
+        add(&mut *store, Record::WireguardKeepalive(inventory::WireguardKeepalive {
+            source_machine: "web2".to_string(),
+            target_machine: "web1".to_string(),
+            interval_sec: 25,
+        }));
+        store.commit().unwrap();
+        // Rows added and removed within the same millisecond were never in the inventory
+        std::thread::sleep(std::time::Duration::from_millis(5));
+
+        let mut store: Box<dyn Store> = Box::new(connection.transaction().unwrap());
+        assert!(store.get_removed_machine("web3", None).unwrap().is_none());
+        store.remove_machine("web1").unwrap();
+        let removed = store.get_removed_machine("web1", None).unwrap().unwrap();
+        assert_eq!(removed.machine.state, MachineState::Planned);
+        assert_eq!(removed.machine.ssh, Some(inventory::SshServer { port: 22, user: "root".to_string() }));
+        assert_eq!(removed.machine.wireguard.as_ref().unwrap().privkey.as_deref(), Some(PRIVKEY));
+        assert_eq!(removed.addresses.len(), 1);
+        assert_eq!(removed.keepalives.len(), 1);
+        assert!(store.get_removed_machine("web1", Some(removed.as_of + Duration::days(1))).unwrap().is_none());
+        // Adding the machine back, like `undelete` does, keeps when it was first added
+        assert_eq!(removed.machine.added_time, added_time);
+        add(&mut *store, Record::Machine(removed.machine));
+        let machines = store.get_machines_with_addresses().unwrap();
+        assert_eq!(machines["web1"].added_time, added_time);
+    }
+
+    /// `undelete` refuses to put a machine back on an address reserved since it was removed
+    #[test]
+    fn test_undelete_reserved_address() {
+        let mut connection = database();
+        let mut store: Box<dyn Store> = Box::new(connection.transaction().unwrap());
+        add(&mut *store, Record::Owner("ivan".to_string()));
+        add(&mut *store, Record::Machine(machine("web1", "10.10.0.1", "fd00::1")));
+        store.commit().unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(5));
+        let mut store: Box<dyn Store> = Box::new(connection.transaction().unwrap());
+        store.remove_machine("web1").unwrap();
+        store.commit().unwrap();
+
+        let config = crate::config::Config::default();
+        let mut store: Box<dyn Store> = Box::new(connection.transaction().unwrap());
+        store.reserve_ip_range(&"10.10.0.0/30".parse().unwrap(), None).unwrap();
+        let error = crate::undelete_machine(store, &config, "web1", None).unwrap_err();
+        assert!(error.to_string().contains("WireGuard address 10.10.0.1 is reserved by 10.10.0.0/30"), "{}", error);
+        // Its own quarantined addresses do not stop it
+        let store: Box<dyn Store> = Box::new(connection.transaction().unwrap());
+        crate::undelete_machine(store, &config, "web1", None).unwrap();
+        let mut store: Box<dyn Store> = Box::new(connection.transaction().unwrap());
+        assert!(store.get_machines_with_addresses().unwrap().contains_key("web1"));
+    }
+
+    /// Updates keep the old row in the history and restart row_start
+    #[test]
+    fn test_history_on_update() {
+        let mut connection = database();
+        let transaction = connection.transaction().unwrap();
+        transaction.execute_batch("
+            INSERT INTO networks (name) VALUES ('internet');
+            INSERT INTO network_links (name, other_network, priority, row_start) VALUES ('internet', 'internet', 0, '2020-01-01T00:00:00.000Z');
+            UPDATE network_links SET priority = 1;
+        ").unwrap();
+        let (row_start, row_end): (String, String) = transaction.query_row(
+            "SELECT row_start, row_end FROM network_links_history WHERE priority = 0", NO_PARAMS, |row| Ok((row.get(0)?, row.get(1)?))
+        ).unwrap();
+        assert_eq!(row_start, "2020-01-01T00:00:00.000Z");
+        let current: String = transaction.query_row("SELECT row_start FROM network_links", NO_PARAMS, |row| row.get(0)).unwrap();
+        assert_eq!(current, row_end);
+    }
+
+    #[test]
+    fn test_pools_and_reservations() {
+        let mut connection = database();
+        let mut store: Box<dyn Store> = Box::new(connection.transaction().unwrap());
+        store.add_ip_pool(&IpPool {
+            name: "mesh".to_string(),
+            ipv4_cidr: "10.10.0.0/16".parse().unwrap(),
+            ipv6_cidr: "fd00::/64".parse().unwrap(),
+            reserved: vec!["10.10.0.0/24".parse().unwrap(), "fd00::/120".parse().unwrap()],
+            policy: AllocationPolicy::AfterHighest,
+            ipv6_mode: Ipv6Mode::EmbedIpv4,
+        }).unwrap();
+        let pools = store.get_ip_pools().unwrap();
+        assert_eq!(pools[0].reserved.len(), 2);
+        assert_eq!(pools[0].policy, AllocationPolicy::AfterHighest);
+
+        store.reserve_ip_range(&"10.10.9.0/24".parse().unwrap(), Some("routers")).unwrap();
+        store.reserve_ip_range(&"10.10.10.0/24".parse().unwrap(), None).unwrap();
+        let reservations = store.get_ip_reservations().unwrap();
+        assert_eq!(reservations.iter().map(|r| r.cidr.to_string()).collect::<Vec<_>>(), vec!["10.10.9.0/24", "10.10.10.0/24"]);
+        assert_eq!(store.release_ip_range(&"10.10.9.0/24".parse().unwrap()).unwrap(), 1);
+        assert_eq!(store.remove_ip_pool("mesh").unwrap(), 1);
+        assert_eq!(store.remove_ip_pool("mesh").unwrap(), 0);
+    }
+}
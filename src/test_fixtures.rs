@@ -0,0 +1,61 @@
+//! Machines for the tests of the `i` binary, so that every test builds them
+//! the same way.  The library's own tests have theirs in generate.rs.
+
+use infrabase::model::{self, MachineState};
+
+use crate::inventory;
+
+pub(crate) const PUBKEY: &str = "AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=";
+pub(crate) const PRIVKEY: &str = "BBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBB=";
+
+/// An active machine owned by ivan, with SSH on port 22 and WireGuard on port 904
+pub(crate) fn machine(hostname: &str, ipv4: &str, ipv6: &str) -> inventory::Machine {
+    inventory::Machine {
+        hostname: hostname.to_string(),
+        added_time: "2020-06-01T00:00:00Z".parse().unwrap(),
+        owner: "ivan".to_string(),
+        state: MachineState::Active,
+        provider_id: None,
+        provider_reference: None,
+        ssh: Some(inventory::SshServer { port: 22, user: "root".to_string() }),
+        wireguard: Some(inventory::WireguardInterface {
+            ipv4_address: ipv4.parse().unwrap(),
+            ipv6_address: ipv6.parse().unwrap(),
+            port: 904,
+            pubkey: PUBKEY.to_string(),
+            privkey: Some(PRIVKEY.to_string()),
+        }),
+    }
+}
+
+/// `machine` as a store reads it, on the networks of `addresses`.  Its keys
+/// are "{hostname}-privkey" and "{hostname}-pubkey".
+pub(crate) fn model_machine(hostname: &str, ipv4: &str, ipv6: &str, addresses: Vec<model::MachineAddress>) -> model::Machine {
+    let networks = if addresses.is_empty() {
+        vec!["NONE".to_string()]
+    } else {
+        addresses.iter().map(|a| a.network.clone()).collect()
+    };
+    let machine = machine(hostname, ipv4, ipv6);
+    let wireguard = machine.wireguard.unwrap();
+    let ssh = machine.ssh.unwrap();
+    model::Machine {
+        hostname: machine.hostname,
+        state: machine.state,
+        wireguard_ipv4_address: Some(wireguard.ipv4_address),
+        wireguard_ipv6_address: Some(wireguard.ipv6_address),
+        wireguard_port: Some(wireguard.port),
+        wireguard_privkey: Some(format!("{hostname}-privkey")),
+        wireguard_pubkey: Some(format!("{hostname}-pubkey")),
+        ssh_port: Some(ssh.port),
+        ssh_user: Some(ssh.user),
+        added_time: machine.added_time,
+        owner: machine.owner,
+        provider_id: None,
+        provider_name: None,
+        provider_email: None,
+        provider_reference: None,
+        networks,
+        addresses,
+    }
+}
@@ -0,0 +1,208 @@
+//! TLS for the Postgres connection.
+//!
+//! `sslmode` in DATABASE_URL works like it does in libpq:
+//!
+//! - `disable`: never use TLS
+//! - `prefer` (the default): use TLS if the server supports it, without verifying its certificate
+//! - `require`: always use TLS, verifying the certificate only if `database.ca_file` is set
+//! - `verify-ca`: always use TLS, and verify that the certificate is signed by a trusted CA
+//! - `verify-full`: like `verify-ca`, and also verify that the certificate is for the host
+//!
+//! Trusted CAs are the system's, plus `database.ca_file` if set.  A client
+//! certificate is sent if `database.client_cert_file` and `database.client_key_file`
+//! are set.
+
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+use anyhow::{anyhow, bail, Context, Result};
+use native_tls::{Certificate, Identity, TlsConnector};
+use postgres::Client;
+use postgres::config::SslMode as PostgresSslMode;
+use postgres_native_tls::MakeTlsConnector;
+
+use crate::config::DatabaseConfig;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum SslMode {
+    Disable,
+    Prefer,
+    Require,
+    VerifyCa,
+    VerifyFull,
+}
+
+impl FromStr for SslMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<SslMode> {
+        Ok(match s {
+            "disable" => SslMode::Disable,
+            "prefer" => SslMode::Prefer,
+            "require" => SslMode::Require,
+            "verify-ca" => SslMode::VerifyCa,
+            "verify-full" => SslMode::VerifyFull,
+            // libpq's "allow" tries without TLS first, which the postgres crate cannot do
+            _ => bail!("Unsupported sslmode {:?}: use disable, prefer, require, verify-ca, or verify-full", s),
+        })
+    }
+}
+
+impl fmt::Display for SslMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            SslMode::Disable => "disable",
+            SslMode::Prefer => "prefer",
+            SslMode::Require => "require",
+            SslMode::VerifyCa => "verify-ca",
+            SslMode::VerifyFull => "verify-full",
+        })
+    }
+}
+
+/// Remove `sslmode` from a postgresql:// URL or a key=value connection string,
+/// because the postgres crate does not understand verify-ca and verify-full.
+/// Returns the rest of the connection string and the sslmode, `prefer` if unset.
+pub(crate) fn take_sslmode(url: &str) -> Result<(String, SslMode)> {
+    let mut mode = None;
+    let mut take = |param: &str| -> Result<bool> {
+        match param.strip_prefix("sslmode=") {
+            Some(value) => {
+                mode = Some(value.parse()?);
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    };
+    let rest = if url.starts_with("postgres://") || url.starts_with("postgresql://") {
+        match url.split_once('?') {
+            Some((base, query)) => {
+                let mut params = vec![];
+                for param in query.split('&') {
+                    if !take(param)? {
+                        params.push(param);
+                    }
+                }
+                if params.is_empty() { base.to_string() } else { format!("{}?{}", base, params.join("&")) }
+            }
+            None => url.to_string(),
+        }
+    } else {
+        let mut params = vec![];
+        for param in url.split_whitespace() {
+            if !take(param)? {
+                params.push(param);
+            }
+        }
+        params.join(" ")
+    };
+    Ok((rest, mode.unwrap_or(SslMode::Prefer)))
+}
+
+fn read_file(path: &Path) -> Result<Vec<u8>> {
+    fs::read(path).with_context(|| format!("Could not read {:?}", path))
+}
+
+/// Build the TLS connector for `mode` with the CA and client certificate from `config`
+fn tls_connector(config: &DatabaseConfig, mode: SslMode) -> Result<TlsConnector> {
+    let mut builder = TlsConnector::builder();
+    if let Some(path) = &config.ca_file {
+        let ca = Certificate::from_pem(&read_file(path)?)
+            .with_context(|| format!("Could not parse {:?} as a PEM certificate", path))?;
+        builder.add_root_certificate(ca);
+    }
+    if let (Some(cert_path), Some(key_path)) = (&config.client_cert_file, &config.client_key_file) {
+        let identity = Identity::from_pkcs8(&read_file(cert_path)?, &read_file(key_path)?)
+            .with_context(|| format!("Could not load client certificate {:?} with key {:?}", cert_path, key_path))?;
+        builder.identity(identity);
+    }
+    match mode {
+        SslMode::Disable | SslMode::Prefer => {
+            builder.danger_accept_invalid_certs(true);
+        }
+        // libpq verifies like verify-ca when there is a CA file
+        SslMode::Require if config.ca_file.is_none() => {
+            builder.danger_accept_invalid_certs(true);
+        }
+        SslMode::Require | SslMode::VerifyCa => {
+            builder.danger_accept_invalid_hostnames(true);
+        }
+        SslMode::VerifyFull => {}
+    }
+    Ok(builder.build()?)
+}
+
+/// Connect to the database in `config`, using TLS as its sslmode says
+pub(crate) fn connect(config: &DatabaseConfig) -> Result<Client> {
+    let url = config.url.as_ref().ok_or_else(|| anyhow!("database.url is not set"))?;
+    let (url, mode) = take_sslmode(url)?;
+    let mut postgres_config = url.parse::<postgres::Config>()?;
+    postgres_config.ssl_mode(match mode {
+        SslMode::Disable => PostgresSslMode::Disable,
+        SslMode::Prefer => PostgresSslMode::Prefer,
+        SslMode::Require | SslMode::VerifyCa | SslMode::VerifyFull => PostgresSslMode::Require,
+    });
+    let connector = MakeTlsConnector::new(tls_connector(config, mode)?);
+    postgres_config.connect(connector)
+        .with_context(|| format!("Could not connect to the database with sslmode={mode}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn test_take_sslmode() {
+        let take = |url| take_sslmode(url).unwrap();
+        assert_eq!(take("postgresql://ivan@db/infra"), ("postgresql://ivan@db/infra".to_string(), SslMode::Prefer));
+        assert_eq!(take("postgresql://ivan@db/infra?sslmode=verify-full"), ("postgresql://ivan@db/infra".to_string(), SslMode::VerifyFull));
+        assert_eq!(
+            take("postgres://ivan@db/infra?connect_timeout=5&sslmode=require&application_name=i"),
+            ("postgres://ivan@db/infra?connect_timeout=5&application_name=i".to_string(), SslMode::Require)
+        );
+        assert_eq!(take("host=db user=ivan sslmode=verify-ca"), ("host=db user=ivan".to_string(), SslMode::VerifyCa));
+        assert_eq!(take("host=db sslmode=disable user=ivan"), ("host=db user=ivan".to_string(), SslMode::Disable));
+        assert!(take_sslmode("postgresql://db/infra?sslmode=allow").is_err());
+        assert!(take_sslmode("host=db sslmode=verify_full").is_err());
+    }
+
+    #[test]
+    fn test_sslmode_roundtrip() {
+        for mode in &[SslMode::Disable, SslMode::Prefer, SslMode::Require, SslMode::VerifyCa, SslMode::VerifyFull] {
+            assert_eq!(mode.to_string().parse::<SslMode>().unwrap(), *mode);
+        }
+    }
+
+    /// Needs a Postgres that only accepts TLS, with a self-signed certificate for
+    /// localhost.  scripts/test-tls starts a throwaway one and runs this test.
+    #[test]
+    #[ignore]
+    fn test_connect_with_self_signed_certificate() {
+        let port = env::var("INFRABASE_TEST_TLS_PORT").expect("INFRABASE_TEST_TLS_PORT is not set");
+        let ca_file = env::var("INFRABASE_TEST_TLS_CA_FILE").expect("INFRABASE_TEST_TLS_CA_FILE is not set");
+        let config = |host: &str, sslmode: &str, ca_file: Option<&str>| DatabaseConfig {
+            url: Some(format!("postgresql://postgres@{host}:{port}/postgres?sslmode={sslmode}")),
+            ca_file: ca_file.map(Into::into),
+            ..Default::default()
+        };
+        let ssl_in_use = |config: &DatabaseConfig| -> bool {
+            let mut client = connect(config).unwrap();
+            client.query_one("SELECT ssl FROM pg_stat_ssl WHERE pid = pg_backend_pid()", &[]).unwrap().get(0)
+        };
+
+        // Encrypted but unverified, like libpq
+        assert!(ssl_in_use(&config("localhost", "prefer", None)));
+        assert!(ssl_in_use(&config("localhost", "require", None)));
+        // Verified against the CA file
+        assert!(ssl_in_use(&config("localhost", "verify-full", Some(&ca_file))));
+        assert!(ssl_in_use(&config("127.0.0.1", "verify-ca", Some(&ca_file))));
+        assert!(connect(&config("localhost", "verify-full", None)).is_err());
+        assert!(connect(&config("localhost", "require", Some(&ca_file))).is_ok());
+        // The certificate is for localhost, not 127.0.0.1
+        assert!(connect(&config("127.0.0.1", "verify-full", Some(&ca_file))).is_err());
+        // The server only accepts TLS
+        assert!(connect(&config("localhost", "disable", None)).is_err());
+    }
+}
@@ -32,14 +32,19 @@ fn chomp_newline(vec: &mut Vec<u8>) {
 
 pub(crate) fn generate_keypair() -> Result<Keypair> {
     let mut privkey = run("wg", &["genkey"], None)?.to_vec();
-    let mut pubkey = run("wg", &["pubkey"], Some(&privkey))?.to_vec();
-
     chomp_newline(&mut privkey);
-    chomp_newline(&mut pubkey);
+    let pubkey = get_pubkey(&privkey)?;
 
     Ok(Keypair { privkey, pubkey })
 }
 
+/// Get the public key for a private key
+pub(crate) fn get_pubkey(privkey: &[u8]) -> Result<Vec<u8>> {
+    let mut pubkey = run("wg", &["pubkey"], Some(privkey))?.to_vec();
+    chomp_newline(&mut pubkey);
+    Ok(pubkey)
+}
+
 #[cfg(test)]
 mod tests {
     use super::chomp_newline;